@@ -0,0 +1,125 @@
+//! Native Client Example
+//!
+//! Connects to a running sidecar with [`NativeTransport`], negotiates RGBA at
+//! a given resolution and saves the first N frames the server broadcasts as
+//! PNG files, printing fps and ping latency from `stats()` as it goes.
+//!
+//! The sidecar only sends frames that its embedding application broadcasts
+//! with `SidecarServer::broadcast_frame`, so run this against a server that
+//! does.
+//!
+//! ```bash
+//! cargo run --example client -- ws://127.0.0.1:9876 --size 640x480 --frames 10 --out frames
+//! ```
+
+use qemuweb_sidecar::native::NativeTransport;
+use qemuweb_sidecar::{
+    ConnectionState, EmulatorToSidecarMessage, Frame, FrameFormat, SidecarConfig, Transport, DEFAULT_PORT,
+};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+struct Args {
+    url: String,
+    width: u32,
+    height: u32,
+    frames: usize,
+    out_dir: PathBuf,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        url: format!("ws://127.0.0.1:{}", DEFAULT_PORT),
+        width: 640,
+        height: 480,
+        frames: 10,
+        out_dir: PathBuf::from("frames"),
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--size" => {
+                let size = value()?;
+                let (w, h) = size.split_once('x').ok_or(format!("Invalid size: {}", size))?;
+                args.width = w.parse().map_err(|_| format!("Invalid width: {}", w))?;
+                args.height = h.parse().map_err(|_| format!("Invalid height: {}", h))?;
+            }
+            "--frames" => {
+                let frames = value()?;
+                args.frames = frames.parse().map_err(|_| format!("Invalid frame count: {}", frames))?;
+            }
+            "--out" => args.out_dir = PathBuf::from(value()?),
+            _ => args.url = arg,
+        }
+    }
+    Ok(args)
+}
+
+fn save_png(path: &Path, frame: &Frame) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, frame.metadata.width, frame.metadata.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&frame.data)?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = parse_args()?;
+    std::fs::create_dir_all(&args.out_dir)?;
+
+    println!("Connecting to {}", args.url);
+    let mut transport = NativeTransport::new(args.url.clone(), SidecarConfig::default());
+    transport.connect().await?;
+    transport.set_format(FrameFormat::Rgba, args.width, args.height).await?;
+
+    let mut poll_interval = tokio::time::interval(Duration::from_millis(10));
+    let mut ping_interval = tokio::time::interval(Duration::from_secs(1));
+    let mut saved = 0;
+
+    while saved < args.frames {
+        tokio::select! {
+            _ = poll_interval.tick() => {}
+            _ = ping_interval.tick() => {
+                transport.ping().await?;
+                let stats = transport.stats();
+                println!(
+                    "fps {:.1}, latency {:.1}ms, {} frames, {} bytes",
+                    stats.current_fps, stats.avg_latency, stats.frames_received, stats.bytes_transferred
+                );
+            }
+        }
+
+        for msg in transport.poll_all() {
+            if let EmulatorToSidecarMessage::FormatAck { format, success } = msg {
+                println!("Format {:?} accepted: {}", format, success);
+            }
+        }
+
+        while let Some(frame) = transport.poll_frame() {
+            if saved == args.frames {
+                break;
+            }
+            let path = args.out_dir.join(format!("frame-{:05}.png", frame.metadata.sequence));
+            save_png(&path, &frame)?;
+            println!("Saved {}", path.display());
+            saved += 1;
+        }
+
+        if transport.state() != ConnectionState::Connected {
+            eprintln!("Connection closed");
+            break;
+        }
+    }
+
+    let stats = transport.stats();
+    println!(
+        "Done: {} frames, {:.1} fps, {:.1}ms latency",
+        stats.frames_received, stats.current_fps, stats.avg_latency
+    );
+    transport.disconnect().await?;
+    Ok(())
+}
@@ -20,7 +20,7 @@ fi
 wasm-pack build \
     --target web \
     --out-dir dist/wasm \
-    --features wasm \
+    --features wasm,webp \
     --no-default-features \
     -- --profile release-wasm
 
@@ -0,0 +1,490 @@
+//! Frame Chunking
+//!
+//! Splits large frame payloads across several binary messages and
+//! reassembles them on receive.
+//!
+//! A chunked frame is sent as the usual `frame` metadata message followed by
+//! one `frameChunk` header + binary message pair per chunk. Chunks may arrive
+//! in any order; frames that are still incomplete after a timeout are
+//! discarded. Each chunk header carries a CRC-32 of the whole payload, which
+//! is checked once the frame is complete.
+//!
+//! Chunk counts and sizes come from the sender, so neither is trusted: a
+//! frame may not have more chunks than bytes, chunks are stored as they
+//! arrive rather than preallocated, and a frame whose chunks add up to more
+//! than its metadata allows is dropped.
+
+use crate::frame::{Frame, FrameError};
+use crate::protocol::{FrameChunk, FrameMetadata};
+use std::collections::{BTreeMap, HashMap};
+
+/// Default maximum payload size of a single chunk (1 MiB)
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Default time to wait for the remaining chunks of a frame, in ms
+pub const DEFAULT_REASSEMBLY_TIMEOUT_MS: f64 = 1000.0;
+
+/// Default number of incomplete frames held at once
+pub const DEFAULT_MAX_PENDING_FRAMES: usize = 8;
+
+/// Default size limit for chunked frames in formats without a fixed size,
+/// that of an 8192x8192 RGBA frame (256 MiB)
+pub const DEFAULT_MAX_FRAME_LEN: usize = 8192 * 8192 * 4;
+
+/// Split a frame payload into chunks of at most `max_chunk_size` bytes
+///
+/// Always yields at least one chunk, so an empty payload still produces a
+/// single (empty) chunk.
+pub fn split_payload(sequence: u64, data: &[u8], max_chunk_size: usize) -> Vec<(FrameChunk, &[u8])> {
+    let max_chunk_size = max_chunk_size.max(1);
+    let chunk_count = data.len().div_ceil(max_chunk_size).max(1) as u32;
+    let crc = Some(crc32fast::hash(data));
+
+    if data.is_empty() {
+        return vec![(
+            FrameChunk {
+                sequence,
+                chunk_index: 0,
+                chunk_count,
+                crc,
+            },
+            data,
+        )];
+    }
+
+    data.chunks(max_chunk_size)
+        .enumerate()
+        .map(|(index, payload)| {
+            (
+                FrameChunk {
+                    sequence,
+                    chunk_index: index as u32,
+                    chunk_count,
+                    crc,
+                },
+                payload,
+            )
+        })
+        .collect()
+}
+
+/// A frame whose chunks are still arriving
+struct PartialFrame {
+    metadata: FrameMetadata,
+    /// Chunks received so far, by index
+    chunks: BTreeMap<u32, Vec<u8>>,
+    chunk_count: u32,
+    /// Total length of `chunks`
+    len: usize,
+    /// Most bytes the payload may add up to
+    max_len: usize,
+    started_at: f64,
+    /// Expected CRC-32 of the payload, from the first chunk that had one
+    crc: Option<u32>,
+}
+
+impl PartialFrame {
+    fn missing(&self) -> u64 {
+        (self.chunk_count as usize - self.chunks.len()) as u64
+    }
+}
+
+/// Reassembles chunked frames
+///
+/// At most `max_pending` incomplete frames are held at once. Starting
+/// another evicts the oldest, so memory stays bounded even if chunks never
+/// arrive. A larger depth rides out more interleaving and loss between
+/// frames, at the cost of memory and of holding stale partial frames longer;
+/// a smaller one fails fast and shows up as overflows in the stats.
+pub struct FrameReassembler {
+    pending: HashMap<u64, PartialFrame>,
+    timeout_ms: f64,
+    max_pending: usize,
+    max_frame_len: usize,
+    dropped: u64,
+    overflowed: u64,
+    chunks_lost: u64,
+    corrupted: u64,
+}
+
+impl FrameReassembler {
+    /// Create a reassembler that discards incomplete frames after `timeout_ms`
+    pub fn new(timeout_ms: f64) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout_ms,
+            max_pending: DEFAULT_MAX_PENDING_FRAMES,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            dropped: 0,
+            overflowed: 0,
+            chunks_lost: 0,
+            corrupted: 0,
+        }
+    }
+
+    /// Set how many incomplete frames may be held at once (at least 1)
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// Set the size limit for frames in formats without a fixed size, e.g.
+    /// `Compressed`
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Start collecting chunks for a frame
+    ///
+    /// Calling this again for a sequence that is already pending is a no-op.
+    /// Fails if the frame has more chunks than it can have bytes. If
+    /// `max_pending` frames are already incomplete, the oldest is dropped
+    /// and counted as an overflow.
+    pub fn begin(&mut self, metadata: FrameMetadata, chunk_count: u32, now: f64) -> Result<(), FrameError> {
+        if self.pending.contains_key(&metadata.sequence) {
+            return Ok(());
+        }
+        let max_len = Frame::buffer_size(metadata.format, metadata.width, metadata.height)?
+            .unwrap_or(self.max_frame_len);
+        let chunk_count = chunk_count.max(1);
+        if chunk_count as usize > max_len.max(1) {
+            return Err(FrameError::InvalidChunk(format!(
+                "{} chunks for a frame of at most {} bytes",
+                chunk_count, max_len
+            )));
+        }
+
+        if self.pending.len() >= self.max_pending {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by(|a, b| a.1.started_at.total_cmp(&b.1.started_at))
+                .map(|(&sequence, _)| sequence);
+            if let Some(sequence) = oldest {
+                self.discard(sequence);
+                self.overflowed += 1;
+            }
+        }
+
+        self.pending.insert(
+            metadata.sequence,
+            PartialFrame {
+                metadata,
+                chunks: BTreeMap::new(),
+                chunk_count,
+                len: 0,
+                max_len,
+                started_at: now,
+                crc: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Check whether chunks for a sequence are being collected
+    pub fn is_pending(&self, sequence: u64) -> bool {
+        self.pending.contains_key(&sequence)
+    }
+
+    /// Add a chunk, returning the frame once all of its chunks have arrived
+    pub fn insert(&mut self, chunk: FrameChunk, data: Vec<u8>, now: f64) -> Result<Option<Frame>, FrameError> {
+        self.expire(now);
+
+        let partial = self.pending.get_mut(&chunk.sequence).ok_or_else(|| {
+            FrameError::InvalidChunk(format!("no frame pending for sequence {}", chunk.sequence))
+        })?;
+
+        if chunk.chunk_count != partial.chunk_count {
+            let expected = partial.chunk_count;
+            self.discard(chunk.sequence);
+            return Err(FrameError::InvalidChunk(format!(
+                "chunk count changed from {} to {}",
+                expected, chunk.chunk_count
+            )));
+        }
+        if chunk.chunk_index >= partial.chunk_count {
+            return Err(FrameError::InvalidChunk(format!(
+                "chunk index {} out of range for {} chunks",
+                chunk.chunk_index, chunk.chunk_count
+            )));
+        }
+
+        // Duplicates replace the earlier copy without counting twice
+        partial.len += data.len();
+        if let Some(previous) = partial.chunks.insert(chunk.chunk_index, data) {
+            partial.len -= previous.len();
+        }
+        partial.crc = partial.crc.or(chunk.crc);
+
+        if partial.len > partial.max_len {
+            let max_len = partial.max_len;
+            self.discard(chunk.sequence);
+            return Err(FrameError::InvalidChunk(format!(
+                "chunks add up to more than {} bytes",
+                max_len
+            )));
+        }
+        if partial.chunks.len() < partial.chunk_count as usize {
+            return Ok(None);
+        }
+
+        let partial = self.pending.remove(&chunk.sequence).unwrap();
+        let mut data = Vec::with_capacity(partial.len);
+        for chunk in partial.chunks.into_values() {
+            data.extend_from_slice(&chunk);
+        }
+        if let Some(expected) = partial.crc {
+            let actual = crc32fast::hash(&data);
+            if actual != expected {
+                self.dropped += 1;
+                self.corrupted += 1;
+                return Err(FrameError::ChecksumMismatch { expected, actual });
+            }
+        }
+        Frame::new(partial.metadata, data).map(Some)
+    }
+
+    /// Discard frames that have been incomplete for longer than the timeout
+    ///
+    /// Returns the number of frames dropped by this call.
+    pub fn expire(&mut self, now: f64) -> usize {
+        let timeout_ms = self.timeout_ms;
+        let before = self.pending.len();
+        let mut chunks_lost = 0;
+        self.pending.retain(|_, partial| {
+            let keep = now - partial.started_at <= timeout_ms;
+            if !keep {
+                chunks_lost += partial.missing();
+            }
+            keep
+        });
+        self.chunks_lost += chunks_lost;
+        let expired = before - self.pending.len();
+        self.dropped += expired as u64;
+        expired
+    }
+
+    /// Drop an incomplete frame, counting it as dropped
+    pub fn discard(&mut self, sequence: u64) {
+        if let Some(partial) = self.pending.remove(&sequence) {
+            self.dropped += 1;
+            self.chunks_lost += partial.missing();
+        }
+    }
+
+    /// Number of frames still waiting for chunks
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of incomplete frames discarded so far, including overflows
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Number of incomplete frames evicted because `max_pending` was reached
+    pub fn overflow_count(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// Number of chunks that never arrived for frames given up on
+    pub fn chunks_lost(&self) -> u64 {
+        self.chunks_lost
+    }
+
+    /// Number of completed frames dropped for failing their CRC check,
+    /// also counted as dropped
+    pub fn corrupted_count(&self) -> u64 {
+        self.corrupted
+    }
+}
+
+impl Default for FrameReassembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_REASSEMBLY_TIMEOUT_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FrameFormat;
+
+    fn test_metadata(sequence: u64) -> FrameMetadata {
+        FrameMetadata {
+            sequence,
+            timestamp: 0.0,
+            width: 4,
+            height: 4,
+            format: FrameFormat::Rgba,
+            keyframe: true,
+            generation: None,
+            checksum: None,
+            stream_id: 0,
+        }
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..64u8).collect()
+    }
+
+    #[test]
+    fn test_split_payload() {
+        let data = test_data();
+        let chunks = split_payload(7, &data, 30);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|(c, _)| c.sequence == 7 && c.chunk_count == 3));
+        assert_eq!(chunks[2].1.len(), 4);
+
+        let empty = split_payload(1, &[], 30);
+        assert_eq!(empty.len(), 1);
+        assert_eq!(empty[0].0.chunk_count, 1);
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let data = test_data();
+        let mut reassembler = FrameReassembler::default();
+        let mut chunks = split_payload(1, &data, 16);
+        reassembler.begin(test_metadata(1), chunks.len() as u32, 0.0).unwrap();
+        chunks.reverse();
+
+        let mut result = None;
+        for (chunk, payload) in chunks {
+            result = reassembler.insert(chunk, payload.to_vec(), 1.0).unwrap();
+        }
+
+        let frame = result.expect("frame should be complete");
+        assert_eq!(frame.data, data);
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_missing_chunk_times_out() {
+        let data = test_data();
+        let mut reassembler = FrameReassembler::new(100.0);
+        let chunks = split_payload(1, &data, 16);
+        reassembler.begin(test_metadata(1), chunks.len() as u32, 0.0).unwrap();
+
+        for (chunk, payload) in chunks.iter().skip(1) {
+            let result = reassembler.insert(*chunk, payload.to_vec(), 10.0).unwrap();
+            assert!(result.is_none());
+        }
+
+        assert_eq!(reassembler.expire(50.0), 0);
+        assert_eq!(reassembler.expire(200.0), 1);
+        assert_eq!(reassembler.dropped_count(), 1);
+        assert_eq!(reassembler.chunks_lost(), 1);
+
+        // Late chunk for the discarded frame is rejected
+        let (chunk, payload) = chunks[0];
+        assert!(reassembler.insert(chunk, payload.to_vec(), 210.0).is_err());
+    }
+
+    #[test]
+    fn test_pending_frames_are_bounded() {
+        let mut reassembler = FrameReassembler::default().with_max_pending(2);
+        for sequence in 1..=4 {
+            reassembler.begin(test_metadata(sequence), 4, sequence as f64).unwrap();
+        }
+
+        assert_eq!(reassembler.pending_count(), 2);
+        assert!(!reassembler.is_pending(1) && !reassembler.is_pending(2));
+        assert!(reassembler.is_pending(3) && reassembler.is_pending(4));
+        assert_eq!(reassembler.overflow_count(), 2);
+        assert_eq!(reassembler.dropped_count(), 2);
+
+        // Re-beginning a pending frame doesn't evict anything
+        reassembler.begin(test_metadata(4), 4, 10.0).unwrap();
+        assert_eq!(reassembler.overflow_count(), 2);
+    }
+
+    #[test]
+    fn test_corrupted_chunk_fails_crc() {
+        let data = test_data();
+        let mut reassembler = FrameReassembler::default();
+        let chunks = split_payload(1, &data, 16);
+        assert!(chunks.iter().all(|(c, _)| c.crc == Some(crc32fast::hash(&data))));
+        reassembler.begin(test_metadata(1), chunks.len() as u32, 0.0).unwrap();
+
+        let mut result = Ok(None);
+        for (index, (chunk, payload)) in chunks.into_iter().enumerate() {
+            let mut payload = payload.to_vec();
+            if index == 2 {
+                payload[3] ^= 0x40;
+            }
+            result = reassembler.insert(chunk, payload, 1.0);
+        }
+        assert!(matches!(result, Err(FrameError::ChecksumMismatch { .. })));
+        assert_eq!(reassembler.corrupted_count(), 1);
+        assert_eq!(reassembler.dropped_count(), 1);
+        assert_eq!(reassembler.pending_count(), 0);
+
+        // Without a CRC nothing is checked
+        reassembler.begin(test_metadata(2), 1, 2.0).unwrap();
+        let chunk = FrameChunk {
+            sequence: 2,
+            chunk_index: 0,
+            chunk_count: 1,
+            crc: None,
+        };
+        assert!(reassembler.insert(chunk, data, 2.0).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_chunk_count_mismatch() {
+        let mut reassembler = FrameReassembler::default();
+        reassembler.begin(test_metadata(1), 4, 0.0).unwrap();
+
+        let chunk = FrameChunk {
+            sequence: 1,
+            chunk_index: 0,
+            chunk_count: 2,
+            crc: None,
+        };
+        let result = reassembler.insert(chunk, vec![0u8; 32], 0.0);
+        assert!(matches!(result, Err(FrameError::InvalidChunk(_))));
+        assert_eq!(reassembler.dropped_count(), 1);
+    }
+
+    #[test]
+    fn test_chunk_limits() {
+        let mut reassembler = FrameReassembler::default();
+
+        // More chunks than the 64-byte frame has bytes
+        let result = reassembler.begin(test_metadata(1), u32::MAX, 0.0);
+        assert!(matches!(result, Err(FrameError::InvalidChunk(_))));
+        assert!(!reassembler.is_pending(1));
+
+        // Chunks that add up to more than the frame are dropped early
+        reassembler.begin(test_metadata(2), 4, 0.0).unwrap();
+        let chunk = |chunk_index| FrameChunk {
+            sequence: 2,
+            chunk_index,
+            chunk_count: 4,
+            crc: None,
+        };
+        assert!(reassembler.insert(chunk(0), vec![0u8; 60], 0.0).unwrap().is_none());
+        let result = reassembler.insert(chunk(1), vec![0u8; 8], 0.0);
+        assert!(matches!(result, Err(FrameError::InvalidChunk(_))));
+        assert!(!reassembler.is_pending(2));
+        assert_eq!(reassembler.dropped_count(), 1);
+
+        // Unsized formats are held to the reassembler's limit
+        let mut reassembler = FrameReassembler::default().with_max_frame_len(16);
+        let metadata = FrameMetadata {
+            format: FrameFormat::Compressed,
+            ..test_metadata(3)
+        };
+        assert!(reassembler.begin(metadata.clone(), 17, 0.0).is_err());
+        reassembler.begin(metadata, 2, 0.0).unwrap();
+        let chunk = FrameChunk {
+            sequence: 3,
+            chunk_index: 0,
+            chunk_count: 2,
+            crc: None,
+        };
+        assert!(reassembler.insert(chunk, vec![0u8; 17], 0.0).is_err());
+    }
+}
@@ -0,0 +1,485 @@
+//! Frame Compression
+//!
+//! Encodes RGBA frames into `FrameFormat::Compressed` payloads and back.
+//!
+//! Every compressed payload starts with a one-byte codec id so the decoder
+//! knows which algorithm produced it. Bare WebP data (a `RIFF....WEBP`
+//! container without the codec byte) is also recognised, so payloads
+//! produced by other WebP encoders can be decoded directly.
+//!
+//! Besides WebP, the general-purpose `Zstd`, `Lz4` and `Deflate` codecs
+//! compress the raw RGBA bytes. Each sits behind the feature of the same
+//! name; decoding is capped at the frame's RGBA size, so a corrupt payload
+//! can't claim more memory than the frame it describes.
+
+use crate::frame::{Frame, FrameError};
+use crate::protocol::FrameFormat;
+use serde::{Deserialize, Serialize};
+
+/// Codec id for WebP payloads
+const CODEC_WEBP: u8 = 1;
+/// Codec id for Zstandard payloads
+const CODEC_ZSTD: u8 = 2;
+/// Codec id for LZ4 block payloads
+const CODEC_LZ4: u8 = 3;
+/// Codec id for raw DEFLATE payloads
+const CODEC_DEFLATE: u8 = 4;
+
+/// Whether any codec is compiled in, so `Compressed` frames can be made and
+/// read at all
+pub const HAS_CODEC: bool = cfg!(any(feature = "webp", feature = "zstd", feature = "lz4", feature = "deflate"));
+
+/// Compression level for DEFLATE, favouring speed over ratio
+#[cfg(feature = "deflate")]
+const DEFLATE_LEVEL: u8 = 3;
+
+/// Most an LZ4 block can expand, in output bytes per input byte
+///
+/// A run's length is spread over extension bytes of up to 255 each, so no
+/// block decodes to more than this many times its size.
+#[cfg(feature = "lz4")]
+const LZ4_MAX_RATIO: usize = 255;
+
+/// Compression codec for `FrameFormat::Compressed` frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionCodec {
+    /// WebP, either lossless or lossy at the given quality (0-100)
+    ///
+    /// Lossy encoding requires the `webp-lossy` feature. Decoding of both
+    /// variants only needs `webp`.
+    WebP { lossless: bool, quality: u8 },
+    /// Zstandard at its fastest level (`zstd` feature)
+    Zstd,
+    /// LZ4 block compression (`lz4` feature)
+    Lz4,
+    /// Raw DEFLATE (`deflate` feature)
+    Deflate,
+}
+
+impl Default for CompressionCodec {
+    /// Lossless WebP, or without the `webp` feature the first of `Zstd`,
+    /// `Lz4` and `Deflate` that is compiled in
+    fn default() -> Self {
+        if cfg!(feature = "webp") || !HAS_CODEC {
+            Self::WebP {
+                lossless: true,
+                quality: 100,
+            }
+        } else if cfg!(feature = "zstd") {
+            Self::Zstd
+        } else if cfg!(feature = "lz4") {
+            Self::Lz4
+        } else {
+            Self::Deflate
+        }
+    }
+}
+
+impl CompressionCodec {
+    /// Codec id written at the start of the compressed payload
+    pub fn id(&self) -> u8 {
+        match self {
+            CompressionCodec::WebP { .. } => CODEC_WEBP,
+            CompressionCodec::Zstd => CODEC_ZSTD,
+            CompressionCodec::Lz4 => CODEC_LZ4,
+            CompressionCodec::Deflate => CODEC_DEFLATE,
+        }
+    }
+}
+
+/// Codec detected from a compressed payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedCodec {
+    /// WebP with the sidecar codec byte
+    WebP,
+    /// WebP container without the codec byte
+    BareWebP,
+    /// Zstandard frame after the codec byte
+    Zstd,
+    /// LZ4 block after the codec byte
+    Lz4,
+    /// Raw DEFLATE stream after the codec byte
+    Deflate,
+}
+
+/// Identify the codec of a compressed payload from its header
+pub fn detect_codec(data: &[u8]) -> Result<DetectedCodec, FrameError> {
+    if is_webp_container(data) {
+        return Ok(DetectedCodec::BareWebP);
+    }
+
+    match data.first() {
+        Some(&CODEC_WEBP) => Ok(DetectedCodec::WebP),
+        Some(&CODEC_ZSTD) => Ok(DetectedCodec::Zstd),
+        Some(&CODEC_LZ4) => Ok(DetectedCodec::Lz4),
+        Some(&CODEC_DEFLATE) => Ok(DetectedCodec::Deflate),
+        Some(id) => Err(FrameError::CompressionError(format!("Unknown codec id {}", id))),
+        None => Err(FrameError::CompressionError("Empty compressed payload".to_string())),
+    }
+}
+
+/// The WebP container inside a compressed payload, if it holds WebP
+///
+/// Browsers decode WebP natively, so this can be handed straight to
+/// `createImageBitmap` instead of decoding in WASM.
+pub fn webp_payload(data: &[u8]) -> Option<&[u8]> {
+    match detect_codec(data).ok()? {
+        DetectedCodec::WebP => Some(&data[1..]),
+        DetectedCodec::BareWebP => Some(data),
+        DetectedCodec::Zstd | DetectedCodec::Lz4 | DetectedCodec::Deflate => None,
+    }
+}
+
+fn is_webp_container(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP"
+}
+
+/// Compress an RGBA frame with the given codec
+pub fn compress(frame: &Frame, codec: CompressionCodec) -> Result<Frame, FrameError> {
+    if frame.metadata.format != FrameFormat::Rgba {
+        return Err(FrameError::UnsupportedConversion {
+            from: frame.metadata.format,
+            to: FrameFormat::Compressed,
+        });
+    }
+
+    let mut data = vec![codec.id()];
+    match codec {
+        CompressionCodec::WebP { lossless, quality } => {
+            encode_webp(frame, lossless, quality, &mut data)?;
+        }
+        CompressionCodec::Zstd => encode_zstd(&frame.data, &mut data)?,
+        CompressionCodec::Lz4 => encode_lz4(&frame.data, &mut data)?,
+        CompressionCodec::Deflate => encode_deflate(&frame.data, &mut data)?,
+    }
+
+    let mut metadata = frame.metadata.clone();
+    metadata.format = FrameFormat::Compressed;
+    metadata.checksum = None;
+    Frame::new(metadata, data)
+}
+
+/// Decompress a compressed frame back to RGBA
+pub fn decompress(frame: &Frame) -> Result<Frame, FrameError> {
+    if frame.metadata.format != FrameFormat::Compressed {
+        return Err(FrameError::UnsupportedConversion {
+            from: frame.metadata.format,
+            to: FrameFormat::Rgba,
+        });
+    }
+
+    let (width, height) = (frame.metadata.width, frame.metadata.height);
+    let rgba_len = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(4))
+        .ok_or(FrameError::InvalidDimensions { width, height })?;
+    let data = match detect_codec(&frame.data)? {
+        DetectedCodec::WebP | DetectedCodec::BareWebP => {
+            let webp = webp_payload(&frame.data).ok_or_else(|| {
+                FrameError::CompressionError("Unsupported compressed payload".to_string())
+            })?;
+            decode_webp(webp, width, height)?
+        }
+        DetectedCodec::Zstd => decode_zstd(&frame.data[1..], rgba_len)?,
+        DetectedCodec::Lz4 => decode_lz4(&frame.data[1..], rgba_len)?,
+        DetectedCodec::Deflate => decode_deflate(&frame.data[1..], rgba_len)?,
+    };
+
+    let mut metadata = frame.metadata.clone();
+    metadata.format = FrameFormat::Rgba;
+    metadata.checksum = None;
+    Frame::new(metadata, data)
+}
+
+#[cfg(feature = "webp")]
+fn encode_webp(frame: &Frame, lossless: bool, quality: u8, out: &mut Vec<u8>) -> Result<(), FrameError> {
+    if !lossless {
+        return encode_webp_lossy(frame, quality, out);
+    }
+
+    let (width, height) = (frame.metadata.width, frame.metadata.height);
+    image_webp::WebPEncoder::new(&mut *out)
+        .encode(&frame.data, width, height, image_webp::ColorType::Rgba8)
+        .map_err(|e| FrameError::CompressionError(e.to_string()))
+}
+
+#[cfg(all(feature = "webp", feature = "webp-lossy"))]
+fn encode_webp_lossy(frame: &Frame, quality: u8, out: &mut Vec<u8>) -> Result<(), FrameError> {
+    let (width, height) = (frame.metadata.width, frame.metadata.height);
+    let encoded = libwebp::Encoder::from_rgba(&frame.data, width, height).encode(quality.min(100) as f32);
+    out.extend_from_slice(&encoded);
+    Ok(())
+}
+
+#[cfg(all(feature = "webp", not(feature = "webp-lossy")))]
+fn encode_webp_lossy(_frame: &Frame, _quality: u8, _out: &mut Vec<u8>) -> Result<(), FrameError> {
+    Err(FrameError::CompressionError(
+        "Lossy WebP encoding requires the `webp-lossy` feature".to_string(),
+    ))
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_frame: &Frame, _lossless: bool, _quality: u8, _out: &mut Vec<u8>) -> Result<(), FrameError> {
+    Err(FrameError::CompressionError(
+        "WebP support requires the `webp` feature".to_string(),
+    ))
+}
+
+/// Decode a WebP image that should be `width` x `height` to RGBA
+///
+/// The image's own dimensions are checked against the frame's before
+/// anything is allocated for it.
+#[cfg(feature = "webp")]
+fn decode_webp(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, FrameError> {
+    let map_err = |e: image_webp::DecodingError| FrameError::CompressionError(e.to_string());
+
+    let mut decoder = image_webp::WebPDecoder::new(std::io::Cursor::new(data)).map_err(map_err)?;
+    let (decoded_width, decoded_height) = decoder.dimensions();
+    if (decoded_width, decoded_height) != (width, height) {
+        return Err(FrameError::InvalidDimensions {
+            width: decoded_width,
+            height: decoded_height,
+        });
+    }
+    let size = decoder
+        .output_buffer_size()
+        .ok_or_else(|| FrameError::CompressionError("WebP image too large".to_string()))?;
+    let mut buffer = vec![0u8; size];
+    decoder.read_image(&mut buffer).map_err(map_err)?;
+
+    if decoder.has_alpha() {
+        return Ok(buffer);
+    }
+
+    // Opaque images decode as RGB
+    let mut rgba = Vec::with_capacity(buffer.len() / 3 * 4);
+    for pixel in buffer.chunks_exact(3) {
+        rgba.extend_from_slice(pixel);
+        rgba.push(255);
+    }
+    Ok(rgba)
+}
+
+#[cfg(not(feature = "webp"))]
+fn decode_webp(_data: &[u8], _width: u32, _height: u32) -> Result<Vec<u8>, FrameError> {
+    Err(FrameError::CompressionError(
+        "WebP support requires the `webp` feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn encode_zstd(data: &[u8], out: &mut Vec<u8>) -> Result<(), FrameError> {
+    ruzstd::encoding::compress(data, &mut *out, ruzstd::encoding::CompressionLevel::Fastest);
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+fn decode_zstd(data: &[u8], max_len: usize) -> Result<Vec<u8>, FrameError> {
+    use std::io::Read;
+
+    let decoder = ruzstd::decoding::StreamingDecoder::new(data)
+        .map_err(|e| FrameError::CompressionError(e.to_string()))?;
+    // Grow as the payload decodes rather than trusting the claimed size
+    let mut out = Vec::with_capacity(max_len.min(data.len().saturating_mul(4)));
+    // One byte over the limit is enough to tell the payload is too big
+    decoder
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| FrameError::CompressionError(e.to_string()))?;
+    Ok(out)
+}
+
+#[cfg(feature = "lz4")]
+fn encode_lz4(data: &[u8], out: &mut Vec<u8>) -> Result<(), FrameError> {
+    out.extend_from_slice(&lz4_flex::block::compress(data));
+    Ok(())
+}
+
+#[cfg(feature = "lz4")]
+fn decode_lz4(data: &[u8], max_len: usize) -> Result<Vec<u8>, FrameError> {
+    // The output is allocated up front, so cap it at what `data` can produce
+    let max_len = max_len.min(data.len().saturating_mul(LZ4_MAX_RATIO));
+    lz4_flex::block::decompress(data, max_len).map_err(|e| FrameError::CompressionError(e.to_string()))
+}
+
+#[cfg(feature = "deflate")]
+fn encode_deflate(data: &[u8], out: &mut Vec<u8>) -> Result<(), FrameError> {
+    out.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(data, DEFLATE_LEVEL));
+    Ok(())
+}
+
+#[cfg(feature = "deflate")]
+fn decode_deflate(data: &[u8], max_len: usize) -> Result<Vec<u8>, FrameError> {
+    miniz_oxide::inflate::decompress_to_vec_with_limit(data, max_len)
+        .map_err(|e| FrameError::CompressionError(e.to_string()))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn encode_zstd(_data: &[u8], _out: &mut Vec<u8>) -> Result<(), FrameError> {
+    Err(codec_disabled("Zstd", "zstd"))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decode_zstd(_data: &[u8], _max_len: usize) -> Result<Vec<u8>, FrameError> {
+    Err(codec_disabled("Zstd", "zstd"))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn encode_lz4(_data: &[u8], _out: &mut Vec<u8>) -> Result<(), FrameError> {
+    Err(codec_disabled("LZ4", "lz4"))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decode_lz4(_data: &[u8], _max_len: usize) -> Result<Vec<u8>, FrameError> {
+    Err(codec_disabled("LZ4", "lz4"))
+}
+
+#[cfg(not(feature = "deflate"))]
+fn encode_deflate(_data: &[u8], _out: &mut Vec<u8>) -> Result<(), FrameError> {
+    Err(codec_disabled("DEFLATE", "deflate"))
+}
+
+#[cfg(not(feature = "deflate"))]
+fn decode_deflate(_data: &[u8], _max_len: usize) -> Result<Vec<u8>, FrameError> {
+    Err(codec_disabled("DEFLATE", "deflate"))
+}
+
+#[cfg(not(all(feature = "zstd", feature = "lz4", feature = "deflate")))]
+fn codec_disabled(name: &str, feature: &str) -> FrameError {
+    FrameError::CompressionError(format!("{} support requires the `{}` feature", name, feature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "webp", feature = "zstd", feature = "lz4", feature = "deflate"))]
+    fn test_frame(width: u32, height: u32) -> Frame {
+        use crate::protocol::FrameMetadata;
+
+        let metadata = FrameMetadata {
+            sequence: 0,
+            timestamp: 0.0,
+            width,
+            height,
+            format: FrameFormat::Rgba,
+            keyframe: true,
+            generation: None,
+            checksum: None,
+            stream_id: 0,
+        };
+        let data: Vec<u8> = (0..width * height * 4).map(|i| (i * 37 % 251) as u8).collect();
+        Frame::new(metadata, data).unwrap()
+    }
+
+    #[test]
+    fn test_detect_codec() {
+        assert_eq!(detect_codec(&[CODEC_WEBP, 0, 0]).unwrap(), DetectedCodec::WebP);
+        assert_eq!(
+            detect_codec(b"RIFF\0\0\0\0WEBPVP8L").unwrap(),
+            DetectedCodec::BareWebP
+        );
+        assert_eq!(detect_codec(&[CODEC_ZSTD]).unwrap(), DetectedCodec::Zstd);
+        assert_eq!(detect_codec(&[CODEC_LZ4]).unwrap(), DetectedCodec::Lz4);
+        assert_eq!(detect_codec(&[CODEC_DEFLATE]).unwrap(), DetectedCodec::Deflate);
+        assert!(matches!(detect_codec(&[0xEE]), Err(FrameError::CompressionError(_))));
+        assert!(detect_codec(&[]).is_err());
+    }
+
+    #[cfg(any(feature = "webp", feature = "zstd", feature = "lz4", feature = "deflate"))]
+    #[test]
+    fn test_default_codec_is_compiled_in() {
+        let frame = test_frame(5, 3);
+        let compressed = compress(&frame, CompressionCodec::default()).unwrap();
+        assert_eq!(decompress(&compressed).unwrap().data, frame.data);
+    }
+
+    #[cfg(all(feature = "zstd", feature = "lz4", feature = "deflate"))]
+    #[test]
+    fn test_byte_codecs_roundtrip() {
+        let frame = test_frame(9, 6);
+        for codec in [CompressionCodec::Zstd, CompressionCodec::Lz4, CompressionCodec::Deflate] {
+            let compressed = compress(&frame, codec).unwrap();
+            assert_eq!(compressed.metadata.format, FrameFormat::Compressed);
+            assert_eq!(compressed.data[0], codec.id());
+            assert!(webp_payload(&compressed.data).is_none());
+
+            let restored = decompress(&compressed).unwrap();
+            assert_eq!(restored.metadata.format, FrameFormat::Rgba);
+            assert_eq!(restored.data, frame.data, "{:?}", codec);
+
+            // A payload decoding to more than the frame holds is rejected
+            let mut small = compressed.clone();
+            small.metadata.height -= 1;
+            assert!(decompress(&small).is_err(), "{:?}", codec);
+        }
+
+        let mut unknown = compress(&frame, CompressionCodec::Lz4).unwrap();
+        let mut data = unknown.data.to_vec();
+        data[0] = 0xEE;
+        unknown.data = data.into();
+        assert!(matches!(decompress(&unknown), Err(FrameError::CompressionError(_))));
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn test_webp_lossless_roundtrip() {
+        let frame = test_frame(7, 5);
+        let compressed = compress(&frame, CompressionCodec::default()).unwrap();
+        assert_eq!(compressed.metadata.format, FrameFormat::Compressed);
+        assert_eq!(compressed.data[0], CODEC_WEBP);
+        assert!(webp_payload(&compressed.data).is_some());
+
+        let restored = decompress(&compressed).unwrap();
+        assert_eq!(restored.metadata.format, FrameFormat::Rgba);
+        assert_eq!(restored.data, frame.data);
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn test_bare_webp_is_decoded() {
+        let frame = test_frame(4, 4);
+        let compressed = compress(&frame, CompressionCodec::default()).unwrap();
+        let mut bare = compressed.clone();
+        bare.data = bare.data.slice(1..);
+
+        assert_eq!(decompress(&bare).unwrap().data, frame.data);
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn test_webp_dimension_mismatch() {
+        let mut compressed = compress(&test_frame(4, 4), CompressionCodec::default()).unwrap();
+        compressed.metadata.width = 8;
+        compressed.metadata.height = 2;
+
+        assert!(matches!(
+            decompress(&compressed),
+            Err(FrameError::InvalidDimensions { width: 4, height: 4 })
+        ));
+    }
+
+    #[cfg(all(feature = "webp", not(feature = "webp-lossy")))]
+    #[test]
+    fn test_lossy_requires_feature() {
+        let frame = test_frame(4, 4);
+        let codec = CompressionCodec::WebP {
+            lossless: false,
+            quality: 80,
+        };
+        assert!(matches!(compress(&frame, codec), Err(FrameError::CompressionError(_))));
+    }
+
+    #[cfg(feature = "webp-lossy")]
+    #[test]
+    fn test_webp_lossy_roundtrip() {
+        let frame = test_frame(16, 16);
+        let codec = CompressionCodec::WebP {
+            lossless: false,
+            quality: 90,
+        };
+        let restored = decompress(&compress(&frame, codec).unwrap()).unwrap();
+        assert_eq!(restored.data.len(), frame.data.len());
+    }
+}
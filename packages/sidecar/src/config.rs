@@ -0,0 +1,212 @@
+//! Config File
+//!
+//! TOML configuration for the native server, passed with `--config`. Every
+//! key is optional and absent keys keep their built-in default. Sending the
+//! process SIGHUP re-reads the file and applies `max_clients`,
+//! `idle_timeout_ms`, `heartbeat_interval_ms`, `allowed_upstreams`,
+//! `max_frames_per_sec`, `frame_burst` and `log_level` without dropping
+//! connections.
+//!
+//! ```toml
+//! bind_addr = "127.0.0.1:9876"
+//! max_clients = 10
+//! max_accepts_per_sec = 50
+//! max_frames_per_sec = 60
+//! frame_burst = 120
+//! idle_timeout_ms = 30000
+//! heartbeat_interval_ms = 10000
+//! log_level = "debug"
+//! tls_cert = "cert.pem"
+//! tls_key = "key.pem"
+//! metrics_addr = "0.0.0.0:9877"
+//! allowed_origins = ["https://app.example"]
+//! allowed_upstreams = ["wss://relay.example:9000"]
+//! ```
+
+use crate::server::{BindAddr, ServerConfig, TlsConfig};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::level_filters::LevelFilter;
+
+/// Config file errors
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config: {0}")]
+    Parse(String),
+}
+
+/// Contents of a config file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Socket address or `host:port`
+    pub bind_addr: Option<BindAddr>,
+    pub max_clients: Option<usize>,
+    /// New connections accepted per second
+    pub max_accepts_per_sec: Option<u32>,
+    /// Frames each client may send per second
+    pub max_frames_per_sec: Option<u32>,
+    /// Frames a client may send at once; defaults to `max_frames_per_sec`
+    pub frame_burst: Option<u32>,
+    pub frame_buffer_size: Option<usize>,
+    pub reassembly_timeout_ms: Option<u64>,
+    /// Idle timeout in ms; `0` disables it
+    pub idle_timeout_ms: Option<u64>,
+    /// Ping interval for quiet clients in ms; `0` means half the idle timeout
+    pub heartbeat_interval_ms: Option<u64>,
+    /// One of `off`, `error`, `warn`, `info`, `debug`, `trace`
+    pub log_level: Option<String>,
+    /// PEM certificate chain for serving `wss://`; needs `tls_key` too
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for serving `wss://`; needs `tls_cert` too
+    pub tls_key: Option<PathBuf>,
+    /// Token clients must send in an `auth` message before anything else
+    pub auth_token: Option<String>,
+    /// Address to serve Prometheus metrics on; needs the `metrics` feature
+    pub metrics_addr: Option<SocketAddr>,
+    /// Browser origins allowed to connect; absent or empty allows all
+    pub allowed_origins: Option<Vec<String>>,
+    /// Upstream servers remote mode may relay to; absent or empty disables it
+    pub allowed_upstreams: Option<Vec<String>>,
+}
+
+impl FileConfig {
+    /// Read and parse a config file
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&text)
+    }
+
+    /// Parse config file contents
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.log_level()?;
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err(ConfigError::Parse(
+                "tls_cert and tls_key must be set together".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Overlay the keys present in the file onto `config`
+    pub fn apply(&self, config: &mut ServerConfig) {
+        if let Some(bind_addr) = &self.bind_addr {
+            config.bind_addr = bind_addr.clone();
+        }
+        if let Some(max_clients) = self.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(rate) = self.max_accepts_per_sec {
+            config.max_accepts_per_sec = Some(rate);
+        }
+        if let Some(rate) = self.max_frames_per_sec {
+            config.max_frames_per_sec = Some(rate);
+        }
+        if let Some(burst) = self.frame_burst {
+            config.frame_burst = Some(burst);
+        }
+        if let Some(frame_buffer_size) = self.frame_buffer_size {
+            config.frame_buffer_size = frame_buffer_size;
+        }
+        if let Some(ms) = self.reassembly_timeout_ms {
+            config.reassembly_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.idle_timeout_ms {
+            config.idle_timeout = (ms > 0).then(|| Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.heartbeat_interval_ms {
+            config.heartbeat_interval = (ms > 0).then(|| Duration::from_millis(ms));
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            config.tls = Some(TlsConfig::new(cert, key));
+        }
+        if let Some(token) = &self.auth_token {
+            config.auth_token = Some(token.clone());
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics_addr = Some(metrics_addr);
+        }
+        if let Some(origins) = &self.allowed_origins {
+            config.allowed_origins = Some(origins.clone());
+        }
+        if let Some(upstreams) = &self.allowed_upstreams {
+            config.allowed_upstreams = upstreams.clone();
+        }
+    }
+
+    /// The configured log level, if any
+    pub fn log_level(&self) -> Result<Option<LevelFilter>, ConfigError> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                level
+                    .parse()
+                    .map_err(|_| ConfigError::Parse(format!("Unknown log level: {}", level)))
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_apply() {
+        let file = FileConfig::parse(
+            r#"
+            max_clients = 3
+            max_frames_per_sec = 30
+            frame_burst = 5
+            idle_timeout_ms = 0
+            heartbeat_interval_ms = 250
+            log_level = "debug"
+            tls_cert = "cert.pem"
+            tls_key = "key.pem"
+            metrics_addr = "0.0.0.0:9877"
+            allowed_origins = ["https://app.example"]
+            allowed_upstreams = ["wss://relay.example:9000"]
+            "#,
+        )
+        .unwrap();
+
+        let mut config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(5)),
+            ..ServerConfig::default()
+        };
+        file.apply(&mut config);
+
+        assert_eq!(config.max_clients, 3);
+        assert_eq!(config.max_frames_per_sec, Some(30));
+        assert_eq!(config.frame_burst, Some(5));
+        assert_eq!(config.max_accepts_per_sec, None);
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.heartbeat_interval, Some(Duration::from_millis(250)));
+        assert_eq!(config.bind_addr, ServerConfig::default().bind_addr);
+        assert_eq!(config.tls, Some(TlsConfig::new("cert.pem", "key.pem")));
+        assert_eq!(config.metrics_addr, Some("0.0.0.0:9877".parse().unwrap()));
+        assert_eq!(config.allowed_origins, Some(vec!["https://app.example".to_string()]));
+        assert_eq!(config.allowed_upstreams, vec!["wss://relay.example:9000".to_string()]);
+        assert_eq!(file.log_level().unwrap(), Some(LevelFilter::DEBUG));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(FileConfig::parse("max_clientz = 3"), Err(ConfigError::Parse(_))));
+        assert!(matches!(FileConfig::parse("log_level = \"loud\""), Err(ConfigError::Parse(_))));
+        assert!(matches!(FileConfig::parse("tls_cert = \"cert.pem\""), Err(ConfigError::Parse(_))));
+    }
+}
@@ -2,7 +2,11 @@
 //!
 //! Handles frame data storage and format conversion.
 
-use crate::protocol::{FrameFormat, FrameMetadata};
+use crate::compression::{self, CompressionCodec};
+use crate::protocol::{FrameFormat, FrameMetadata, INDEXED8_PALETTE_SIZE};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use thiserror::Error;
 
 /// Frame-related errors
@@ -19,34 +23,206 @@ pub enum FrameError {
 
     #[error("Compression error: {0}")]
     CompressionError(String),
+
+    #[error("Invalid frame chunk: {0}")]
+    InvalidChunk(String),
+
+    #[error("Frame checksum mismatch: expected {expected:08x}, got {actual:08x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    #[error("Frame has more than 256 colors, use quantize_indexed8 to reduce them")]
+    TooManyColors,
+
+    #[error("Cannot composite {overlay:?} onto {base:?}, both must be RGBA")]
+    UnsupportedComposite { base: FrameFormat, overlay: FrameFormat },
+
+    #[error("Cannot crop {0:?} frames, only RGBA and RGB565")]
+    UnsupportedCrop(FrameFormat),
+
+    #[error("Crop {width}x{height} at ({x}, {y}) is outside the frame")]
+    CropOutOfBounds { x: u32, y: u32, width: u32, height: u32 },
+
+    #[error("Cannot apply regions to {0:?} frames, only RGBA and RGB565")]
+    UnsupportedRegion(FrameFormat),
+
+    #[error("{0:?} frames have no fixed size")]
+    UnsizedFormat(FrameFormat),
+
+    #[error("Invalid binary frame: {0}")]
+    InvalidBinaryFrame(String),
+}
+
+/// Monotonic time in ms, for `Frame::convert_timed`
+#[cfg(not(target_arch = "wasm32"))]
+fn clock_ms() -> f64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+}
+
+/// Monotonic time in ms, for `Frame::convert_timed`
+///
+/// `Instant` isn't available in the browser, so this is `performance.now()`,
+/// falling back to the wall clock where there is no `window` (workers).
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+fn clock_ms() -> f64 {
+    web_sys::window()
+        .and_then(|window| window.performance())
+        .map(|performance| performance.now())
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+/// How `Frame::composite` combines overlay pixels with the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendMode {
+    /// Alpha-blend the overlay over the base (non-premultiplied "over")
+    #[default]
+    SourceOver,
+    /// Copy overlay pixels, alpha included, replacing the base
+    Replace,
 }
 
+/// Which frames a full [`FrameBuffer`] discards to make room for a new one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Drop the oldest frame, keeping the most recent `capacity` frames
+    #[default]
+    Oldest,
+    /// Drop everything buffered, so a reader that fell behind jumps
+    /// straight to the newest frame
+    Newest,
+    /// Drop the oldest delta frame, and only drop a keyframe when nothing
+    /// but keyframes is buffered
+    KeepKeyframe,
+}
+
+/// 4x4 Bayer matrix for ordered dithering
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
 /// Frame data container
+///
+/// The pixel data is reference counted, so cloning a frame (e.g. to send it
+/// to several clients) shares one buffer instead of copying it.
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub metadata: FrameMetadata,
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 impl Frame {
     /// Create a new frame with the given metadata and data
-    pub fn new(metadata: FrameMetadata, data: Vec<u8>) -> Result<Self, FrameError> {
-        let expected_size = Self::expected_size(&metadata);
-        if let Some(expected) = expected_size {
-            if data.len() != expected {
-                return Err(FrameError::SizeMismatch {
-                    expected,
-                    actual: data.len(),
-                });
+    ///
+    /// Frames must have non-zero dimensions, so an empty frame is always an
+    /// error rather than a silently blank render.
+    pub fn new(metadata: FrameMetadata, data: impl Into<Bytes>) -> Result<Self, FrameError> {
+        check_dimensions(metadata.width, metadata.height)?;
+        if is_yuv(metadata.format) {
+            check_even_dimensions(metadata.width, metadata.height)?;
+        }
+        let frame = Self {
+            metadata,
+            data: data.into(),
+        };
+        frame.check_size()?;
+        Ok(frame)
+    }
+
+    /// Check that the data length matches the metadata
+    ///
+    /// `data` is public and may have been modified since construction, so
+    /// conversions re-check this before reading pixels.
+    fn check_size(&self) -> Result<(), FrameError> {
+        match Self::expected_size(&self.metadata)? {
+            Some(expected) if self.data.len() != expected => Err(FrameError::SizeMismatch {
+                expected,
+                actual: self.data.len(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `f` on a mutable copy of the data
+    ///
+    /// The copy is free unless another frame shares the buffer. Any
+    /// checksum is cleared, since it no longer describes the data.
+    fn modify_data<T>(&mut self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        let mut data = Vec::from(std::mem::take(&mut self.data));
+        let result = f(&mut data);
+        self.data = data.into();
+        self.metadata.checksum = None;
+        result
+    }
+
+    /// CRC-32 of the frame data
+    pub fn compute_checksum(&self) -> u32 {
+        crc32fast::hash(&self.data)
+    }
+
+    /// Set `metadata.checksum` to the CRC-32 of the data
+    pub fn with_checksum(mut self) -> Self {
+        self.metadata.checksum = Some(self.compute_checksum());
+        self
+    }
+
+    /// Check the data against `metadata.checksum`, if there is one
+    pub fn verify_checksum(&self) -> Result<(), FrameError> {
+        match self.metadata.checksum {
+            Some(expected) => {
+                let actual = self.compute_checksum();
+                if actual != expected {
+                    return Err(FrameError::ChecksumMismatch { expected, actual });
+                }
+                Ok(())
             }
+            None => Ok(()),
         }
-        Ok(Self { metadata, data })
     }
 
     /// Calculate expected buffer size for metadata
-    fn expected_size(metadata: &FrameMetadata) -> Option<usize> {
-        let pixels = metadata.width as usize * metadata.height as usize;
-        metadata.format.bytes_per_pixel().map(|bpp| pixels * bpp)
+    fn expected_size(metadata: &FrameMetadata) -> Result<Option<usize>, FrameError> {
+        Self::buffer_size(metadata.format, metadata.width, metadata.height)
+    }
+
+    /// Buffer size in bytes of a frame with the given format and dimensions
+    ///
+    /// Returns `None` for formats without a fixed size, and
+    /// `InvalidDimensions` when the size doesn't fit in a `usize`.
+    pub fn buffer_size(format: FrameFormat, width: u32, height: u32) -> Result<Option<usize>, FrameError> {
+        let too_large = || FrameError::InvalidDimensions { width, height };
+        let pixels = (width as usize).checked_mul(height as usize).ok_or_else(too_large)?;
+        if is_yuv(format) {
+            // Full-size Y plane, then quarter-size U and V samples, either
+            // as two planes or interleaved
+            let chroma = (width / 2) as usize * (height / 2) as usize;
+            return pixels.checked_add(2 * chroma).map(Some).ok_or_else(too_large);
+        }
+        let Some(bpp) = format.bytes_per_pixel() else {
+            return Ok(None);
+        };
+        pixels
+            .checked_mul(bpp)
+            .and_then(|size| size.checked_add(format.header_size()))
+            .map(Some)
+            .ok_or_else(too_large)
+    }
+
+    /// Formats that `convert` can produce and accept
+    ///
+    /// `Compressed` is only listed when a codec is compiled in.
+    pub fn supported_formats() -> Vec<FrameFormat> {
+        let mut formats = vec![
+            FrameFormat::Rgba,
+            FrameFormat::Rgb565,
+            FrameFormat::Yuv420,
+            FrameFormat::Indexed8,
+            FrameFormat::Nv12,
+        ];
+        if compression::HAS_CODEC {
+            formats.push(FrameFormat::Compressed);
+        }
+        formats
     }
 
     /// Get the raw data as a slice
@@ -57,48 +233,349 @@ impl Frame {
     /// Convert frame to a different format
     pub fn convert(&self, target_format: FrameFormat) -> Result<Frame, FrameError> {
         if self.metadata.format == target_format {
+            self.check_size()?;
+            return Ok(self.clone());
+        }
+        self.convert_into(target_format, Vec::new())
+    }
+
+    /// Convert frame to a different format, compressing with `codec`
+    ///
+    /// Same as `convert`, except frames converted to `Compressed` use
+    /// `codec` instead of [`CompressionCodec::default`].
+    pub fn convert_with_codec(&self, target_format: FrameFormat, codec: CompressionCodec) -> Result<Frame, FrameError> {
+        if self.metadata.format == target_format {
+            self.check_size()?;
             return Ok(self.clone());
         }
+        self.convert_with(target_format, codec, Vec::new())
+    }
+
+    /// Convert frame to a different format, also returning how long it took
+    ///
+    /// For profiling conversion throughput on a host; `convert` does the
+    /// same work without touching the clock.
+    #[cfg(any(not(target_arch = "wasm32"), feature = "wasm"))]
+    pub fn convert_timed(&self, target_format: FrameFormat) -> Result<(Frame, std::time::Duration), FrameError> {
+        let start = clock_ms();
+        let frame = self.convert(target_format)?;
+        let elapsed = std::time::Duration::from_secs_f64((clock_ms() - start).max(0.0) / 1000.0);
+        Ok((frame, elapsed))
+    }
+
+    /// Compress an RGBA frame into a `Compressed` frame
+    pub fn compress(&self, codec: CompressionCodec) -> Result<Frame, FrameError> {
+        compression::compress(self, codec)
+    }
+
+    /// Decompress a `Compressed` frame back to RGBA
+    pub fn decompress(&self) -> Result<Frame, FrameError> {
+        compression::decompress(self)
+    }
+
+    /// Draw `overlay` onto this frame with its top-left corner at (`x`, `y`)
+    ///
+    /// Both frames must be RGBA. The overlay is clipped to this frame's
+    /// bounds, so negative or out-of-range offsets draw only the visible
+    /// part (or nothing).
+    pub fn composite(&mut self, overlay: &Frame, x: i32, y: i32, blend: BlendMode) -> Result<(), FrameError> {
+        if self.metadata.format != FrameFormat::Rgba || overlay.metadata.format != FrameFormat::Rgba {
+            return Err(FrameError::UnsupportedComposite {
+                base: self.metadata.format,
+                overlay: overlay.metadata.format,
+            });
+        }
+        self.check_size()?;
+        overlay.check_size()?;
+
+        let (base_w, base_h) = (self.metadata.width as i64, self.metadata.height as i64);
+        let (over_w, over_h) = (overlay.metadata.width as i64, overlay.metadata.height as i64);
+        let (x, y) = (x as i64, y as i64);
+
+        let (left, right) = (x.max(0), (x + over_w).min(base_w));
+        let (top, bottom) = (y.max(0), (y + over_h).min(base_h));
+        if left >= right || top >= bottom {
+            return Ok(());
+        }
+
+        let row_len = ((right - left) * 4) as usize;
+        self.modify_data(|data| {
+            for row in top..bottom {
+                let dst_start = ((row * base_w + left) * 4) as usize;
+                let src_start = (((row - y) * over_w + (left - x)) * 4) as usize;
+                let dst = &mut data[dst_start..dst_start + row_len];
+                let src = &overlay.data[src_start..src_start + row_len];
+
+                match blend {
+                    BlendMode::Replace => dst.copy_from_slice(src),
+                    BlendMode::SourceOver => {
+                        for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+                            blend_over(d, s);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Copy the `width` x `height` region at (`x`, `y`) into a new frame
+    ///
+    /// Works on RGBA and RGB565 frames. The region must lie entirely inside
+    /// this frame; the result keeps the sequence, timestamp and other
+    /// metadata but takes the region's size.
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Result<Frame, FrameError> {
+        let bpp = match self.metadata.format {
+            FrameFormat::Rgba | FrameFormat::Rgb565 => self.metadata.format.bytes_per_pixel().unwrap(),
+            format => return Err(FrameError::UnsupportedCrop(format)),
+        };
+        self.check_size()?;
+
+        let fits = |start: u32, len: u32, limit: u32| len > 0 && start.checked_add(len).is_some_and(|end| end <= limit);
+        if !fits(x, width, self.metadata.width) || !fits(y, height, self.metadata.height) {
+            return Err(FrameError::CropOutOfBounds { x, y, width, height });
+        }
 
-        let new_data = match (self.metadata.format, target_format) {
+        let stride = self.metadata.width as usize * bpp;
+        let row_len = width as usize * bpp;
+        let mut data = Vec::with_capacity(row_len * height as usize);
+        for row in y as usize..(y + height) as usize {
+            let start = row * stride + x as usize * bpp;
+            data.extend_from_slice(&self.data[start..start + row_len]);
+        }
+
+        let metadata = FrameMetadata {
+            width,
+            height,
+            checksum: None,
+            ..self.metadata.clone()
+        };
+        Frame::new(metadata, data)
+    }
+
+    /// Overwrite the `width` x `height` region at (`x`, `y`) with `region_data`
+    ///
+    /// `region_data` holds the region's rows in this frame's format, which
+    /// must be RGBA or RGB565. A region that doesn't lie entirely inside the
+    /// frame is rejected with `InvalidDimensions`.
+    pub fn apply_region(&mut self, region_data: &[u8], x: u32, y: u32, width: u32, height: u32) -> Result<(), FrameError> {
+        let bpp = match self.metadata.format {
+            FrameFormat::Rgba | FrameFormat::Rgb565 => self.metadata.format.bytes_per_pixel().unwrap(),
+            format => return Err(FrameError::UnsupportedRegion(format)),
+        };
+        self.check_size()?;
+
+        let fits = |start: u32, len: u32, limit: u32| len > 0 && start.checked_add(len).is_some_and(|end| end <= limit);
+        if !fits(x, width, self.metadata.width) || !fits(y, height, self.metadata.height) {
+            return Err(FrameError::InvalidDimensions { width, height });
+        }
+        let row_len = width as usize * bpp;
+        let expected = row_len * height as usize;
+        if region_data.len() != expected {
+            return Err(FrameError::SizeMismatch {
+                expected,
+                actual: region_data.len(),
+            });
+        }
+
+        let stride = self.metadata.width as usize * bpp;
+        self.modify_data(|data| {
+            for (row, src) in region_data.chunks_exact(row_len).enumerate() {
+                let start = (y as usize + row) * stride + x as usize * bpp;
+                data[start..start + row_len].copy_from_slice(src);
+            }
+        });
+        Ok(())
+    }
+
+    /// Encode this frame as a delta against `previous`
+    ///
+    /// The delta is the byte-wise XOR of the two frames, so unchanged pixels
+    /// become zeros and a mostly static screen compresses to very little.
+    /// Both frames must have the same format and dimensions; `Compressed`
+    /// frames can't be diffed. The result keeps this frame's metadata with
+    /// `keyframe` cleared.
+    pub fn delta_from(&self, previous: &Frame) -> Result<Frame, FrameError> {
+        let data = self.xor_with(previous)?;
+        let metadata = FrameMetadata {
+            keyframe: false,
+            checksum: None,
+            ..self.metadata.clone()
+        };
+        Frame::new(metadata, data)
+    }
+
+    /// Reconstruct the full frame from this delta and the frame it was
+    /// taken against, see [`Frame::delta_from`]
+    pub fn apply_delta(&self, base: &Frame) -> Result<Frame, FrameError> {
+        let data = self.xor_with(base)?;
+        let metadata = FrameMetadata {
+            checksum: None,
+            ..self.metadata.clone()
+        };
+        Frame::new(metadata, data)
+    }
+
+    fn xor_with(&self, other: &Frame) -> Result<Vec<u8>, FrameError> {
+        let (from, to) = (other.metadata.format, self.metadata.format);
+        if from != to || to == FrameFormat::Compressed {
+            return Err(FrameError::UnsupportedConversion { from, to });
+        }
+        self.check_size()?;
+        other.check_size()?;
+        if (self.metadata.width, self.metadata.height) != (other.metadata.width, other.metadata.height) {
+            return Err(FrameError::SizeMismatch {
+                expected: other.data.len(),
+                actual: self.data.len(),
+            });
+        }
+
+        Ok(self.data.iter().zip(&other.data).map(|(a, b)| a ^ b).collect())
+    }
+
+    /// Reduce a frame to `Indexed8`, dithering if it has too many colors
+    ///
+    /// Frames with at most 256 distinct colors are converted exactly, as
+    /// with `convert(FrameFormat::Indexed8)`. Anything more colorful is
+    /// mapped onto a fixed opaque 3-3-2 palette with ordered dithering, which
+    /// is lossy: colors shift by up to one palette step and alpha is lost.
+    /// Formats other than RGBA are converted to RGBA first.
+    pub fn quantize_indexed8(&self) -> Result<Frame, FrameError> {
+        match self.convert(FrameFormat::Indexed8) {
+            Err(FrameError::TooManyColors) => {}
+            result => return result,
+        }
+
+        let rgba;
+        let source = if self.metadata.format == FrameFormat::Rgba {
+            self
+        } else {
+            rgba = self.convert(FrameFormat::Rgba)?;
+            &rgba
+        };
+
+        let mut output = Vec::with_capacity(INDEXED8_PALETTE_SIZE + source.data.len() / 4);
+        for index in 0..=255u16 {
+            let (r, g, b) = (index >> 5, (index >> 2) & 0x07, index & 0x03);
+            output.extend_from_slice(&[(r * 255 / 7) as u8, (g * 255 / 7) as u8, (b * 255 / 3) as u8, 255]);
+        }
+
+        let width = source.metadata.width as usize;
+        for (i, pixel) in source.data.chunks_exact(4).enumerate() {
+            let threshold = BAYER_4X4[(i / width) % 4][(i % width) % 4] as f32;
+            // Offset in (-0.5, 0.5) of one palette step
+            let offset = (threshold + 0.5) / 16.0 - 0.5;
+            let level = |value: u8, max: u8| {
+                let step = 255.0 / max as f32;
+                (value as f32 / step + offset).round().clamp(0.0, max as f32) as u8
+            };
+            output.push((level(pixel[0], 7) << 5) | (level(pixel[1], 7) << 2) | level(pixel[2], 3));
+        }
+
+        let mut metadata = self.metadata.clone();
+        metadata.format = FrameFormat::Indexed8;
+        metadata.checksum = None;
+        Frame::new(metadata, output)
+    }
+
+    /// Convert frame to a different format, writing into `buffer`
+    ///
+    /// The buffer's existing contents are discarded but its allocation is
+    /// reused, which pairs with buffers handed out by a [`FramePool`].
+    pub fn convert_into(&self, target_format: FrameFormat, buffer: Vec<u8>) -> Result<Frame, FrameError> {
+        self.convert_with(target_format, CompressionCodec::default(), buffer)
+    }
+
+    fn convert_with(
+        &self,
+        target_format: FrameFormat,
+        codec: CompressionCodec,
+        mut buffer: Vec<u8>,
+    ) -> Result<Frame, FrameError> {
+        self.check_size()?;
+        buffer.clear();
+
+        match (self.metadata.format, target_format) {
+            (from, to) if from == to => {
+                buffer.extend_from_slice(&self.data);
+            }
             (FrameFormat::Rgba, FrameFormat::Rgb565) => {
-                self.rgba_to_rgb565()
+                self.rgba_to_rgb565(&mut buffer)
             }
             (FrameFormat::Rgb565, FrameFormat::Rgba) => {
-                self.rgb565_to_rgba()
+                self.rgb565_to_rgba(&mut buffer)
+            }
+            (FrameFormat::Rgba, FrameFormat::Yuv420) => {
+                self.rgba_to_yuv420(&mut buffer)?
+            }
+            (FrameFormat::Yuv420, FrameFormat::Rgba) => {
+                self.yuv420_to_rgba(&mut buffer)?
+            }
+            (FrameFormat::Rgba, FrameFormat::Nv12) => {
+                self.rgba_to_nv12(&mut buffer)?
+            }
+            (FrameFormat::Nv12, FrameFormat::Rgba) => {
+                self.nv12_to_rgba(&mut buffer)?
+            }
+            (FrameFormat::Rgba, FrameFormat::Indexed8) => {
+                self.rgba_to_indexed8(&mut buffer)?
+            }
+            (FrameFormat::Indexed8, FrameFormat::Rgba) => {
+                self.indexed8_to_rgba(&mut buffer)
+            }
+            (FrameFormat::Rgba, FrameFormat::Compressed) => {
+                return self.compress(codec);
+            }
+            (FrameFormat::Compressed, FrameFormat::Rgba) => {
+                return self.decompress();
+            }
+            (from, to) if has_direct_conversion(from, FrameFormat::Rgba)
+                && has_direct_conversion(FrameFormat::Rgba, to) =>
+            {
+                return self.convert_via_rgba(to, codec, buffer);
             }
             (from, to) => {
                 return Err(FrameError::UnsupportedConversion { from, to });
             }
-        };
+        }
 
         let mut new_metadata = self.metadata.clone();
         new_metadata.format = target_format;
+        new_metadata.checksum = None;
+
+        Frame::new(new_metadata, buffer)
+    }
 
-        Frame::new(new_metadata, new_data)
+    /// Convert to `target_format` in two hops, decoding to RGBA first
+    ///
+    /// For pairs without a direct conversion, e.g. RGB565 to YUV420. The
+    /// intermediate frame is dropped; `buffer` receives the final result.
+    fn convert_via_rgba(
+        &self,
+        target_format: FrameFormat,
+        codec: CompressionCodec,
+        buffer: Vec<u8>,
+    ) -> Result<Frame, FrameError> {
+        let rgba = self.convert_into(FrameFormat::Rgba, Vec::new())?;
+        rgba.convert_with(target_format, codec, buffer)
     }
 
     /// Convert RGBA to RGB565
-    fn rgba_to_rgb565(&self) -> Vec<u8> {
-        let pixel_count = self.data.len() / 4;
-        let mut output = Vec::with_capacity(pixel_count * 2);
+    fn rgba_to_rgb565(&self, output: &mut Vec<u8>) {
+        output.reserve(self.data.len() / 2);
 
-        for chunk in self.data.chunks_exact(4) {
-            let r = chunk[0] as u16;
-            let g = chunk[1] as u16;
-            let b = chunk[2] as u16;
-            // RGB565: 5 bits R, 6 bits G, 5 bits B
-            let rgb565: u16 = ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3);
-            output.extend_from_slice(&rgb565.to_le_bytes());
-        }
+        #[cfg(feature = "simd")]
+        let rest = rgba_to_rgb565_simd(&self.data, output);
+        #[cfg(not(feature = "simd"))]
+        let rest = &self.data[..];
 
-        output
+        rgba_to_rgb565_scalar(rest, output);
     }
 
     /// Convert RGB565 to RGBA
-    fn rgb565_to_rgba(&self) -> Vec<u8> {
+    fn rgb565_to_rgba(&self, output: &mut Vec<u8>) {
         let pixel_count = self.data.len() / 2;
-        let mut output = Vec::with_capacity(pixel_count * 4);
+        output.reserve(pixel_count * 4);
 
         for chunk in self.data.chunks_exact(2) {
             let rgb565 = u16::from_le_bytes([chunk[0], chunk[1]]);
@@ -111,8 +588,434 @@ impl Frame {
             output.push((b << 3) | (b >> 2));
             output.push(255); // Alpha
         }
+    }
+
+    /// Convert RGBA to planar YUV 4:2:0 (I420) with BT.601 coefficients
+    ///
+    /// Luma is studio range (16-235). Each chroma sample is taken from the
+    /// average color of its 2x2 block, and alpha is discarded.
+    fn rgba_to_yuv420(&self, output: &mut Vec<u8>) -> Result<(), FrameError> {
+        self.rgba_to_yuv(output, false)
+    }
+
+    /// Convert RGBA to semi-planar YUV 4:2:0 (NV12), as `rgba_to_yuv420`
+    fn rgba_to_nv12(&self, output: &mut Vec<u8>) -> Result<(), FrameError> {
+        self.rgba_to_yuv(output, true)
+    }
+
+    /// Convert RGBA to YUV 4:2:0 with U/V `interleaved` or in two planes
+    fn rgba_to_yuv(&self, output: &mut Vec<u8>, interleaved: bool) -> Result<(), FrameError> {
+        let (width, height) = (self.metadata.width, self.metadata.height);
+        check_even_dimensions(width, height)?;
+        let (width, height) = (width as usize, height as usize);
+        output.reserve(width * height * 3 / 2);
+
+        for pixel in self.data.chunks_exact(4) {
+            let (r, g, b) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+            output.push((((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8);
+        }
+
+        let mut v_plane = Vec::with_capacity(width * height / 4);
+        for row in (0..height).step_by(2) {
+            for col in (0..width).step_by(2) {
+                let (mut r, mut g, mut b) = (0, 0, 0);
+                for offset in [row * width + col, row * width + col + 1, (row + 1) * width + col, (row + 1) * width + col + 1] {
+                    let pixel = &self.data[offset * 4..offset * 4 + 3];
+                    r += pixel[0] as i32;
+                    g += pixel[1] as i32;
+                    b += pixel[2] as i32;
+                }
+                let (r, g, b) = ((r + 2) / 4, (g + 2) / 4, (b + 2) / 4);
+                output.push((((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8);
+                let v = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+                if interleaved {
+                    output.push(v);
+                } else {
+                    v_plane.push(v);
+                }
+            }
+        }
+        output.extend_from_slice(&v_plane);
+        Ok(())
+    }
+
+    /// Convert planar YUV 4:2:0 (I420) to RGBA with BT.601 coefficients
+    fn yuv420_to_rgba(&self, output: &mut Vec<u8>) -> Result<(), FrameError> {
+        self.yuv_to_rgba(output, false)
+    }
+
+    /// Convert semi-planar YUV 4:2:0 (NV12) to RGBA, as `yuv420_to_rgba`
+    fn nv12_to_rgba(&self, output: &mut Vec<u8>) -> Result<(), FrameError> {
+        self.yuv_to_rgba(output, true)
+    }
+
+    /// Convert YUV 4:2:0 with U/V `interleaved` or in two planes to RGBA
+    fn yuv_to_rgba(&self, output: &mut Vec<u8>, interleaved: bool) -> Result<(), FrameError> {
+        let (width, height) = (self.metadata.width, self.metadata.height);
+        check_even_dimensions(width, height)?;
+        let (width, height) = (width as usize, height as usize);
+        output.reserve(width * height * 4);
+
+        let (y_plane, chroma) = self.data.split_at(width * height);
+        let chroma_len = chroma.len() / 2;
+        let clamp = |value: i32| (value >> 8).clamp(0, 255) as u8;
+
+        for row in 0..height {
+            for col in 0..width {
+                let c = y_plane[row * width + col] as i32 - 16;
+                let chroma_index = (row / 2) * (width / 2) + col / 2;
+                let (u, v) = if interleaved {
+                    (chroma[chroma_index * 2], chroma[chroma_index * 2 + 1])
+                } else {
+                    (chroma[chroma_index], chroma[chroma_len + chroma_index])
+                };
+                let d = u as i32 - 128;
+                let e = v as i32 - 128;
+                output.push(clamp(298 * c + 409 * e + 128));
+                output.push(clamp(298 * c - 100 * d - 208 * e + 128));
+                output.push(clamp(298 * c + 516 * d + 128));
+                output.push(255);
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert RGBA to Indexed8, failing if there are more than 256 colors
+    fn rgba_to_indexed8(&self, output: &mut Vec<u8>) -> Result<(), FrameError> {
+        output.resize(INDEXED8_PALETTE_SIZE, 0);
+        output.reserve(self.data.len() / 4);
+
+        let mut palette: HashMap<[u8; 4], u8> = HashMap::new();
+        for chunk in self.data.chunks_exact(4) {
+            let color = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            let index = match palette.get(&color) {
+                Some(&index) => index,
+                None => {
+                    let index = u8::try_from(palette.len()).map_err(|_| FrameError::TooManyColors)?;
+                    palette.insert(color, index);
+                    let offset = index as usize * 4;
+                    output[offset..offset + 4].copy_from_slice(&color);
+                    index
+                }
+            };
+            output.push(index);
+        }
+        Ok(())
+    }
+
+    /// Convert Indexed8 to RGBA by palette lookup
+    fn indexed8_to_rgba(&self, output: &mut Vec<u8>) {
+        let (palette, indices) = self.data.split_at(INDEXED8_PALETTE_SIZE);
+        output.reserve(indices.len() * 4);
+
+        for &index in indices {
+            let offset = index as usize * 4;
+            output.extend_from_slice(&palette[offset..offset + 4]);
+        }
+    }
+}
+
+/// Frame metadata as bincode-encoded in a binary frame
+///
+/// Unlike [`FrameMetadata`]'s serde form it has no skipped fields, which a
+/// format without field names can't represent.
+#[derive(Serialize, Deserialize)]
+struct BinaryMetadata {
+    sequence: u64,
+    timestamp: f64,
+    width: u32,
+    height: u32,
+    format: u8,
+    keyframe: bool,
+    generation: Option<u32>,
+    checksum: Option<u32>,
+    stream_id: u32,
+}
+
+/// Encode a frame as a single self-contained binary message
+///
+/// A little-endian `u32` length, then that many bytes of bincode-encoded
+/// metadata, then the frame data. Unlike the fixed binary header this
+/// carries every metadata field. See [`decode_frame_binary`].
+pub fn encode_frame_binary(frame: &Frame) -> Vec<u8> {
+    let metadata = &frame.metadata;
+    let header = BinaryMetadata {
+        sequence: metadata.sequence,
+        timestamp: metadata.timestamp,
+        width: metadata.width,
+        height: metadata.height,
+        format: metadata.format.to_byte(),
+        keyframe: metadata.keyframe,
+        generation: metadata.generation,
+        checksum: metadata.checksum,
+        stream_id: metadata.stream_id,
+    };
+    // Plain numbers and options always serialize
+    let header = bincode::serialize(&header).unwrap_or_default();
+
+    let mut message = Vec::with_capacity(4 + header.len() + frame.data.len());
+    message.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    message.extend_from_slice(&header);
+    message.extend_from_slice(&frame.data);
+    message
+}
+
+/// Decode a message made by [`encode_frame_binary`]
+pub fn decode_frame_binary(bytes: &[u8]) -> Result<Frame, FrameError> {
+    let (metadata, offset) = decode_binary_metadata(bytes)?;
+    Frame::new(metadata, bytes[offset..].to_vec())
+}
+
+/// Metadata of a binary frame and the offset its data starts at
+pub(crate) fn decode_binary_metadata(bytes: &[u8]) -> Result<(FrameMetadata, usize), FrameError> {
+    let invalid = |reason: &str| FrameError::InvalidBinaryFrame(reason.to_string());
+    let len = bytes.get(..4).ok_or_else(|| invalid("missing header length"))?;
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    let header = bytes
+        .get(4..)
+        .and_then(|rest| rest.get(..len))
+        .ok_or_else(|| invalid("header runs past the end of the message"))?;
+    let header: BinaryMetadata =
+        bincode::deserialize(header).map_err(|e| FrameError::InvalidBinaryFrame(e.to_string()))?;
+    let format = FrameFormat::from_byte(header.format)
+        .ok_or_else(|| FrameError::InvalidBinaryFrame(format!("unknown format {}", header.format)))?;
+
+    let metadata = FrameMetadata {
+        sequence: header.sequence,
+        timestamp: header.timestamp,
+        width: header.width,
+        height: header.height,
+        format,
+        keyframe: header.keyframe,
+        generation: header.generation,
+        checksum: header.checksum,
+        stream_id: header.stream_id,
+    };
+    Ok((metadata, 4 + len))
+}
+
+/// Whether `convert_into` has a single-step conversion between two formats
+///
+/// Compressed frames count when any codec is compiled in, the same
+/// condition under which `supported_formats` lists them, so multi-hop
+/// conversions to and from `Compressed` report `UnsupportedConversion`
+/// without one.
+fn has_direct_conversion(from: FrameFormat, to: FrameFormat) -> bool {
+    use FrameFormat::*;
+    match (from, to) {
+        (Rgba, Rgb565) | (Rgb565, Rgba) => true,
+        (Rgba, Yuv420) | (Yuv420, Rgba) => true,
+        (Rgba, Nv12) | (Nv12, Rgba) => true,
+        (Rgba, Indexed8) | (Indexed8, Rgba) => true,
+        (Rgba, Compressed) | (Compressed, Rgba) => compression::HAS_CODEC,
+        (from, to) => from == to,
+    }
+}
+
+/// Pack RGBA pixels into little-endian RGB565, one pixel at a time
+fn rgba_to_rgb565_scalar(rgba: &[u8], output: &mut Vec<u8>) {
+    for chunk in rgba.chunks_exact(4) {
+        let r = chunk[0] as u16;
+        let g = chunk[1] as u16;
+        let b = chunk[2] as u16;
+        // RGB565: 5 bits R, 6 bits G, 5 bits B
+        let rgb565: u16 = ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3);
+        output.extend_from_slice(&rgb565.to_le_bytes());
+    }
+}
+
+/// Pack RGBA pixels into RGB565 eight at a time
+///
+/// Each pixel is loaded as a little-endian `u32` (`0xAABBGGRR`) and the
+/// channel bits are masked and shifted into place in one go. Returns the
+/// trailing pixels that don't fill a vector, for the scalar path.
+#[cfg(feature = "simd")]
+fn rgba_to_rgb565_simd<'a>(rgba: &'a [u8], output: &mut Vec<u8>) -> &'a [u8] {
+    use wide::u32x8;
+
+    let mut chunks = rgba.chunks_exact(32);
+    for chunk in &mut chunks {
+        let pixels = u32x8::from(std::array::from_fn::<u32, 8, _>(|i| {
+            u32::from_le_bytes([chunk[i * 4], chunk[i * 4 + 1], chunk[i * 4 + 2], chunk[i * 4 + 3]])
+        }));
+        let r = (pixels & u32x8::splat(0x0000_00F8)) << 8_u32;
+        let g = (pixels & u32x8::splat(0x0000_FC00)) >> 5_u32;
+        let b = (pixels & u32x8::splat(0x00F8_0000)) >> 19_u32;
+        for rgb565 in (r | g | b).to_array() {
+            output.extend_from_slice(&(rgb565 as u16).to_le_bytes());
+        }
+    }
+    chunks.remainder()
+}
+
+/// Blend one non-premultiplied RGBA pixel over another in place
+fn blend_over(dst: &mut [u8], src: &[u8]) {
+    let src_a = src[3] as u32;
+    let dst_weight = dst[3] as u32 * (255 - src_a);
+    // Output alpha scaled by 255
+    let alpha = src_a * 255 + dst_weight;
+    if alpha == 0 {
+        dst.fill(0);
+        return;
+    }
+
+    for channel in 0..3 {
+        let value = src[channel] as u32 * src_a * 255 + dst[channel] as u32 * dst_weight;
+        dst[channel] = ((value + alpha / 2) / alpha) as u8;
+    }
+    dst[3] = ((alpha + 127) / 255) as u8;
+}
+
+/// Whether frames of `format` are YUV 4:2:0 with subsampled chroma
+fn is_yuv(format: FrameFormat) -> bool {
+    matches!(format, FrameFormat::Yuv420 | FrameFormat::Nv12)
+}
+
+fn check_dimensions(width: u32, height: u32) -> Result<(), FrameError> {
+    if width == 0 || height == 0 {
+        return Err(FrameError::InvalidDimensions { width, height });
+    }
+    Ok(())
+}
+
+/// YUV 4:2:0 subsamples chroma over 2x2 blocks, so both sides must be even
+fn check_even_dimensions(width: u32, height: u32) -> Result<(), FrameError> {
+    if !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+        return Err(FrameError::InvalidDimensions { width, height });
+    }
+    Ok(())
+}
+
+/// Detects torn frames from their source generations
+///
+/// Each delta frame is expected to carry the same generation as the frame
+/// before it, or the next one. Anything else means the picture a receiver
+/// has built up mixes framebuffer generations, e.g. because the source
+/// changed mid-copy, and only a keyframe can repair it. Frames without a
+/// generation are ignored, so sources that don't set one pay nothing.
+#[derive(Debug, Default)]
+pub struct GenerationTracker {
+    last: Option<u32>,
+    torn: u64,
+}
+
+impl GenerationTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a received frame, returning `true` if it is torn
+    ///
+    /// After a tear, deltas go unchecked until the next keyframe.
+    pub fn observe(&mut self, generation: Option<u32>, keyframe: bool) -> bool {
+        let Some(generation) = generation else {
+            return false;
+        };
+        if keyframe {
+            self.last = Some(generation);
+            return false;
+        }
+
+        match self.last {
+            Some(last) if generation == last || generation == last.wrapping_add(1) => {
+                self.last = Some(generation);
+                false
+            }
+            Some(_) => {
+                self.last = None;
+                self.torn += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of torn frames seen so far
+    pub fn torn_count(&self) -> u64 {
+        self.torn
+    }
+}
+
+/// Pool of reusable frame buffers
+///
+/// Every buffer in a pool has the size of one frame of the pool's format and
+/// dimensions. Buffers of any other size are discarded on release, and the
+/// pool never holds more than `max_buffers` idle buffers.
+pub struct FramePool {
+    buffers: Vec<Vec<u8>>,
+    format: FrameFormat,
+    width: u32,
+    height: u32,
+    buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl FramePool {
+    /// Create a pool for frames of the given format and dimensions
+    pub fn new(format: FrameFormat, width: u32, height: u32, max_buffers: usize) -> Result<Self, FrameError> {
+        check_dimensions(width, height)?;
+        let buffer_size = Frame::buffer_size(format, width, height)?
+            .ok_or(FrameError::UnsizedFormat(format))?;
+        Ok(Self {
+            buffers: Vec::with_capacity(max_buffers),
+            format,
+            width,
+            height,
+            buffer_size,
+            max_buffers,
+        })
+    }
+
+    /// Size in bytes of the buffers in this pool
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Number of idle buffers waiting to be reused
+    pub fn available(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Take an empty buffer with room for one frame
+    pub fn acquire(&mut self) -> Vec<u8> {
+        match self.buffers.pop() {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer
+            }
+            None => Vec::with_capacity(self.buffer_size),
+        }
+    }
+
+    /// Return a filled buffer to the pool
+    ///
+    /// Buffers whose length doesn't match the pool's frame size, or that
+    /// arrive while the pool is full, are dropped.
+    pub fn release(&mut self, buffer: Vec<u8>) {
+        if buffer.len() == self.buffer_size && self.buffers.len() < self.max_buffers {
+            self.buffers.push(buffer);
+        }
+    }
+
+    /// Return a frame's buffer to the pool
+    ///
+    /// Buffers still shared with clones of the frame are dropped instead.
+    pub fn release_frame(&mut self, frame: Frame) {
+        if let Ok(data) = frame.data.try_into_mut() {
+            self.release(data.into());
+        }
+    }
 
-        output
+    /// Convert a frame into the pool's format using a pooled buffer
+    pub fn convert(&mut self, frame: &Frame) -> Result<Frame, FrameError> {
+        if frame.metadata.width != self.width || frame.metadata.height != self.height {
+            return Err(FrameError::InvalidDimensions {
+                width: frame.metadata.width,
+                height: frame.metadata.height,
+            });
+        }
+        let buffer = self.acquire();
+        frame.convert_into(self.format, buffer)
     }
 }
 
@@ -121,76 +1024,250 @@ pub struct FrameBuffer {
     frames: Vec<Option<Frame>>,
     write_index: usize,
     read_index: usize,
+    len: usize,
     capacity: usize,
+    /// Total payload size of the buffered frames
+    bytes: usize,
+    /// Frames overwritten before they were read
+    dropped: u64,
+    policy: DropPolicy,
 }
 
 impl FrameBuffer {
     /// Create a new frame buffer with the given capacity
     pub fn new(capacity: usize) -> Self {
+        Self::with_policy(capacity, DropPolicy::default())
+    }
+
+    /// Create a frame buffer that makes room according to `policy` when full
+    pub fn with_policy(capacity: usize, policy: DropPolicy) -> Self {
+        let capacity = capacity.max(1);
         let mut frames = Vec::with_capacity(capacity);
         frames.resize_with(capacity, || None);
         Self {
             frames,
             write_index: 0,
             read_index: 0,
+            len: 0,
             capacity,
+            bytes: 0,
+            dropped: 0,
+            policy,
         }
     }
 
+    /// How the buffer makes room when full
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
     /// Push a frame into the buffer
+    ///
+    /// Returns `false` if the buffer was full and frames were dropped to
+    /// make room, as chosen by its [`DropPolicy`].
     pub fn push(&mut self, frame: Frame) -> bool {
+        let had_room = self.len < self.capacity;
+        if !had_room {
+            self.dropped += self.make_room() as u64;
+        }
+
+        self.bytes += frame.data.len();
         self.frames[self.write_index] = Some(frame);
-        let prev_write = self.write_index;
         self.write_index = (self.write_index + 1) % self.capacity;
+        self.len += 1;
+        had_room
+    }
+
+    /// Drop frames from a full buffer as the policy says, returning how many
+    fn make_room(&mut self) -> usize {
+        match self.policy {
+            DropPolicy::Oldest => {
+                self.pop();
+                1
+            }
+            DropPolicy::Newest => {
+                let dropped = self.len;
+                self.clear();
+                dropped
+            }
+            DropPolicy::KeepKeyframe => {
+                let offset = self.iter().position(|frame| !frame.metadata.keyframe).unwrap_or(0);
+                self.remove(offset);
+                1
+            }
+        }
+    }
+
+    /// Remove the frame `offset` places after the oldest, closing the gap
+    fn remove(&mut self, offset: usize) {
+        let capacity = self.capacity;
+        let index = |offset: usize| (self.read_index + offset) % capacity;
 
-        // Check if we're overwriting unread frames
-        if self.write_index == self.read_index && self.frames[prev_write].is_some() {
-            // Advance read index - dropping a frame
-            self.read_index = (self.read_index + 1) % self.capacity;
-            return false; // Indicates a frame was dropped
+        if let Some(frame) = self.frames[index(offset)].take() {
+            self.bytes -= frame.data.len();
         }
-        true
+        for offset in offset..self.len - 1 {
+            let next = self.frames[index(offset + 1)].take();
+            self.frames[index(offset)] = next;
+        }
+        self.len -= 1;
+        self.write_index = index(self.len);
     }
 
     /// Pop the next frame from the buffer
     pub fn pop(&mut self) -> Option<Frame> {
-        if self.read_index == self.write_index {
+        if self.len == 0 {
             return None;
         }
         let frame = self.frames[self.read_index].take();
         self.read_index = (self.read_index + 1) % self.capacity;
+        self.len -= 1;
+        if let Some(frame) = &frame {
+            self.bytes -= frame.data.len();
+        }
         frame
     }
 
-    /// Check if the buffer is empty
-    pub fn is_empty(&self) -> bool {
-        self.read_index == self.write_index
+    /// The most recently pushed frame, without removing it
+    pub fn latest(&self) -> Option<&Frame> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = (self.write_index + self.capacity - 1) % self.capacity;
+        self.frames[index].as_ref()
     }
 
-    /// Get the number of frames in the buffer
-    pub fn len(&self) -> usize {
-        if self.write_index >= self.read_index {
-            self.write_index - self.read_index
-        } else {
-            self.capacity - self.read_index + self.write_index
-        }
+    /// Iterate over the buffered frames, oldest first, without removing them
+    pub fn iter(&self) -> impl Iterator<Item = &Frame> {
+        (0..self.len)
+            .map(move |offset| (self.read_index + offset) % self.capacity)
+            .filter_map(move |index| self.frames[index].as_ref())
     }
 
-    /// Clear all frames
-    pub fn clear(&mut self) {
-        for frame in &mut self.frames {
-            *frame = None;
+    /// Remove and yield all buffered frames, oldest first
+    ///
+    /// The buffer is empty with reset indices as soon as this returns.
+    pub fn drain(&mut self) -> impl Iterator<Item = Frame> {
+        let mut frames = Vec::with_capacity(self.len);
+        while let Some(frame) = self.pop() {
+            frames.push(frame);
         }
-        self.read_index = 0;
-        self.write_index = 0;
+        self.clear();
+        frames.into_iter()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Timestamp of the oldest buffered frame
+    pub fn oldest_timestamp(&self) -> Option<f64> {
+        if self.len == 0 {
+            return None;
+        }
+        self.frames[self.read_index]
+            .as_ref()
+            .map(|frame| frame.metadata.timestamp)
+    }
 
-    fn test_metadata() -> FrameMetadata {
+    /// Drop frames whose timestamp is more than `max_age_ms` before `now`
+    ///
+    /// Frames are removed oldest first, but the newest frame is always kept
+    /// so a reader still has something to show. Returns the number dropped.
+    pub fn drop_stale(&mut self, now: f64, max_age_ms: f64) -> usize {
+        let mut dropped = 0;
+        while self.len > 1 {
+            let oldest = self.frames[self.read_index].as_ref();
+            if !oldest.is_some_and(|frame| now - frame.metadata.timestamp > max_age_ms) {
+                break;
+            }
+            self.pop();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Check if the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the number of frames in the buffer
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Maximum number of frames the buffer holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change how many frames the buffer holds, keeping the queued ones
+    ///
+    /// When shrinking below the current length the oldest frames are
+    /// dropped (and counted in `dropped_count`) so the newest remain.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        let capacity = capacity.max(1);
+        let excess = self.len.saturating_sub(capacity);
+        for _ in 0..excess {
+            self.pop();
+        }
+        self.dropped += excess as u64;
+
+        let mut frames: Vec<Option<Frame>> = Vec::with_capacity(capacity);
+        while let Some(frame) = self.pop() {
+            frames.push(Some(frame));
+        }
+        let len = frames.len();
+        frames.resize_with(capacity, || None);
+
+        self.bytes = frames.iter().flatten().map(|frame| frame.data.len()).sum();
+        self.frames = frames;
+        self.capacity = capacity;
+        self.read_index = 0;
+        self.write_index = len % capacity;
+        self.len = len;
+    }
+
+    /// Check if the next push will overwrite the oldest frame
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity
+    }
+
+    /// How full the buffer is, from 0.0 (empty) to 1.0 (full)
+    pub fn fullness(&self) -> f32 {
+        self.len as f32 / self.capacity as f32
+    }
+
+    /// Total payload size of the buffered frames in bytes
+    pub fn byte_len(&self) -> usize {
+        self.bytes
+    }
+
+    /// Frames dropped by `push` before they were read
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Reset the drop counter, leaving the buffered frames alone
+    pub fn reset_stats(&mut self) {
+        self.dropped = 0;
+    }
+
+    /// Clear all frames
+    pub fn clear(&mut self) {
+        for frame in &mut self.frames {
+            *frame = None;
+        }
+        self.read_index = 0;
+        self.write_index = 0;
+        self.len = 0;
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn test_metadata() -> FrameMetadata {
         FrameMetadata {
             sequence: 0,
             timestamp: 0.0,
@@ -198,6 +1275,9 @@ mod tests {
             height: 2,
             format: FrameFormat::Rgba,
             keyframe: true,
+            generation: None,
+            checksum: None,
+            stream_id: 0,
         }
     }
 
@@ -217,6 +1297,60 @@ mod tests {
         assert!(matches!(result, Err(FrameError::SizeMismatch { .. })));
     }
 
+    #[test]
+    fn test_zero_dimensions_rejected() {
+        for (width, height) in [(0, 0), (1, 0), (0, 1)] {
+            let metadata = FrameMetadata {
+                width,
+                height,
+                ..test_metadata()
+            };
+            assert!(matches!(
+                Frame::new(metadata, Vec::new()),
+                Err(FrameError::InvalidDimensions { .. })
+            ));
+            assert!(FramePool::new(FrameFormat::Rgba, width, height, 1).is_err());
+        }
+
+        let metadata = FrameMetadata {
+            width: 0,
+            height: 0,
+            format: FrameFormat::Compressed,
+            ..test_metadata()
+        };
+        assert!(Frame::new(metadata, vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_oversized_dimensions_rejected() {
+        let metadata = FrameMetadata {
+            width: u32::MAX,
+            height: u32::MAX,
+            ..test_metadata()
+        };
+        assert!(matches!(
+            Frame::new(metadata, vec![0u8; 16]),
+            Err(FrameError::InvalidDimensions { .. })
+        ));
+        assert!(Frame::buffer_size(FrameFormat::Rgba, u32::MAX, u32::MAX).is_err());
+        assert!(FramePool::new(FrameFormat::Rgba, u32::MAX, u32::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn test_partial_pixel_length_rejected() {
+        // 2x2 RGBA needs 16 bytes; 15 is not a whole number of pixels
+        let result = Frame::new(test_metadata(), vec![0u8; 15]);
+        assert!(matches!(result, Err(FrameError::SizeMismatch { expected: 16, actual: 15 })));
+
+        // Conversions re-check data that was changed after construction
+        let mut frame = Frame::new(test_metadata(), vec![0u8; 16]).unwrap();
+        frame.data.truncate(14);
+        assert!(matches!(
+            frame.convert(FrameFormat::Rgb565),
+            Err(FrameError::SizeMismatch { expected: 16, actual: 14 })
+        ));
+    }
+
     #[test]
     fn test_rgba_to_rgb565() {
         let metadata = test_metadata();
@@ -228,6 +1362,940 @@ mod tests {
         assert_eq!(converted.data.len(), 8); // 2x2 RGB565 = 8 bytes
     }
 
+    /// Build a 1-pixel-high frame from a row of pixels
+    fn row_frame(format: FrameFormat, data: Vec<u8>) -> Frame {
+        let bpp = format.bytes_per_pixel().unwrap();
+        let metadata = FrameMetadata {
+            width: (data.len() / bpp) as u32,
+            height: 1,
+            format,
+            ..test_metadata()
+        };
+        Frame::new(metadata, data).unwrap()
+    }
+
+    /// Known RGBA pixels and the RGB565 word each truncates to
+    const RGB565_VECTORS: &[([u8; 4], u16)] = &[
+        ([255, 0, 0, 255], 0xF800),     // red
+        ([0, 255, 0, 255], 0x07E0),     // green
+        ([0, 0, 255, 255], 0x001F),     // blue
+        ([255, 255, 255, 255], 0xFFFF), // white
+        ([0, 0, 0, 255], 0x0000),       // black
+        ([128, 128, 128, 255], 0x8410), // mid gray
+        ([7, 3, 7, 255], 0x0000),       // below one step truncates to zero
+        ([255, 0, 0, 0], 0xF800),       // alpha is discarded
+    ];
+
+    /// RGB565 words and the RGBA pixel each expands to
+    ///
+    /// Expansion replicates the high bits into the low bits, so full-scale
+    /// channels map back to 255 and zero stays zero.
+    const RGBA_VECTORS: &[(u16, [u8; 4])] = &[
+        (0xF800, [255, 0, 0, 255]),
+        (0x07E0, [0, 255, 0, 255]),
+        (0x001F, [0, 0, 255, 255]),
+        (0xFFFF, [255, 255, 255, 255]),
+        (0x0000, [0, 0, 0, 255]),
+        (0x8410, [132, 130, 132, 255]),  // 16/32/16 -> 128|4, 128|2, 128|4
+        (0x0821, [8, 4, 8, 255]),        // lowest non-zero step per channel
+        (0x7BEF, [123, 125, 123, 255]),  // 15/31/15 -> 120|3, 124|1, 120|3
+    ];
+
+    #[test]
+    fn test_rgba_to_rgb565_vectors() {
+        let input = RGB565_VECTORS.iter().flat_map(|(rgba, _)| *rgba).collect();
+        let expected: Vec<u8> = RGB565_VECTORS
+            .iter()
+            .flat_map(|(_, word)| word.to_le_bytes())
+            .collect();
+
+        let converted = row_frame(FrameFormat::Rgba, input)
+            .convert(FrameFormat::Rgb565)
+            .unwrap();
+        assert_eq!(converted.data, expected);
+    }
+
+    #[test]
+    fn test_rgb565_to_rgba_vectors() {
+        let input = RGBA_VECTORS.iter().flat_map(|(word, _)| word.to_le_bytes()).collect();
+        let expected: Vec<u8> = RGBA_VECTORS.iter().flat_map(|(_, rgba)| *rgba).collect();
+
+        let converted = row_frame(FrameFormat::Rgb565, input)
+            .convert(FrameFormat::Rgba)
+            .unwrap();
+        assert_eq!(converted.data, expected);
+    }
+
+    #[test]
+    fn test_yuv420_known_colors() {
+        // Studio-range luma, neutral chroma for grays
+        for (pixel, y) in [([255, 255, 255, 255], 235), ([0, 0, 0, 255], 16)] {
+            let yuv = solid_frame(2, 2, pixel).convert(FrameFormat::Yuv420).unwrap();
+            assert_eq!(yuv.data, vec![y, y, y, y, 128, 128]);
+            assert_eq!(yuv.convert(FrameFormat::Rgba).unwrap().data, pixel.repeat(4));
+        }
+
+        let red = solid_frame(2, 2, [255, 0, 0, 255]).convert(FrameFormat::Yuv420).unwrap();
+        assert_eq!(red.data, vec![82, 82, 82, 82, 90, 240]);
+    }
+
+    #[test]
+    fn test_yuv420_roundtrip() {
+        let metadata = FrameMetadata {
+            width: 4,
+            height: 4,
+            ..test_metadata()
+        };
+        // Smooth gradient, so chroma subsampling loses little
+        let data: Vec<u8> = (0..16u8).flat_map(|i| [100 + i * 2, 80 + i, 150 - i, 255]).collect();
+        let frame = Frame::new(metadata, data).unwrap();
+
+        let yuv = frame.convert(FrameFormat::Yuv420).unwrap();
+        assert_eq!(yuv.data.len(), 16 + 2 * 4);
+        assert_eq!(Frame::buffer_size(FrameFormat::Yuv420, 4, 4).unwrap(), Some(24));
+
+        let restored = yuv.convert(FrameFormat::Rgba).unwrap();
+        for (a, b) in frame.data.iter().zip(&restored.data) {
+            assert!(a.abs_diff(*b) <= 8, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_nv12_roundtrip() {
+        let metadata = FrameMetadata {
+            width: 4,
+            height: 4,
+            ..test_metadata()
+        };
+        let data: Vec<u8> = (0..16u8).flat_map(|i| [100 + i * 2, 80 + i, 150 - i, 255]).collect();
+        let frame = Frame::new(metadata, data).unwrap();
+
+        // Same samples as I420, with U and V interleaved after the Y plane
+        let nv12 = frame.convert(FrameFormat::Nv12).unwrap();
+        let i420 = frame.convert(FrameFormat::Yuv420).unwrap();
+        assert_eq!(Frame::buffer_size(FrameFormat::Nv12, 4, 4).unwrap(), Some(24));
+        assert_eq!(nv12.data[..16], i420.data[..16]);
+        let (u_plane, v_plane) = i420.data[16..].split_at(4);
+        let interleaved: Vec<u8> = u_plane.iter().zip(v_plane).flat_map(|(&u, &v)| [u, v]).collect();
+        assert_eq!(nv12.data[16..], interleaved[..]);
+
+        let restored = nv12.convert(FrameFormat::Rgba).unwrap();
+        for (a, b) in frame.data.iter().zip(&restored.data) {
+            assert!(a.abs_diff(*b) <= 8, "{} vs {}", a, b);
+        }
+        assert_eq!(restored.data, i420.convert(FrameFormat::Rgba).unwrap().data);
+
+        let odd = FrameMetadata {
+            width: 3,
+            height: 2,
+            format: FrameFormat::Nv12,
+            ..test_metadata()
+        };
+        assert!(matches!(
+            Frame::new(odd, vec![0u8; 8]),
+            Err(FrameError::InvalidDimensions { width: 3, height: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_yuv420_odd_dimensions_rejected() {
+        let metadata = FrameMetadata {
+            width: 3,
+            height: 2,
+            format: FrameFormat::Yuv420,
+            ..test_metadata()
+        };
+        assert!(matches!(
+            Frame::new(metadata, vec![0u8; 8]),
+            Err(FrameError::InvalidDimensions { width: 3, height: 2 })
+        ));
+
+        assert!(matches!(
+            solid_frame(3, 3, [0, 0, 0, 255]).convert(FrameFormat::Yuv420),
+            Err(FrameError::InvalidDimensions { width: 3, height: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_multi_hop_conversions() {
+        let frame = solid_frame(2, 2, [255, 0, 0, 255]);
+        let rgb565 = frame.convert(FrameFormat::Rgb565).unwrap();
+
+        let yuv = rgb565.convert(FrameFormat::Yuv420).unwrap();
+        assert_eq!(yuv.metadata.format, FrameFormat::Yuv420);
+        assert_eq!(yuv.data, frame.convert(FrameFormat::Yuv420).unwrap().data);
+
+        let indexed = yuv.convert(FrameFormat::Indexed8).unwrap();
+        assert_eq!(indexed.metadata.format, FrameFormat::Indexed8);
+        let back = indexed.convert(FrameFormat::Rgb565).unwrap();
+        assert_eq!(back.metadata.format, FrameFormat::Rgb565);
+        assert_eq!(back.data.len(), 8);
+
+        let compressed = rgb565.convert(FrameFormat::Compressed);
+        if compression::HAS_CODEC {
+            let compressed = compressed.unwrap();
+            assert_eq!(compressed.metadata.format, FrameFormat::Compressed);
+            assert_eq!(compressed.convert(FrameFormat::Rgb565).unwrap().data, rgb565.data);
+        } else {
+            assert!(matches!(compressed, Err(FrameError::UnsupportedConversion { .. })));
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_multi_hop_to_byte_codec() {
+        // Reachable through RGBA with any codec compiled in, not just WebP
+        let rgb565 = solid_frame(2, 2, [255, 0, 0, 255]).convert(FrameFormat::Rgb565).unwrap();
+        let compressed = rgb565.convert_with_codec(FrameFormat::Compressed, CompressionCodec::Zstd).unwrap();
+        assert_eq!(compressed.data[0], CompressionCodec::Zstd.id());
+        assert_eq!(compressed.convert(FrameFormat::Rgb565).unwrap().data, rgb565.data);
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let previous = solid_frame(4, 4, [10, 20, 30, 255]);
+        let mut current = previous.clone();
+        current.metadata.sequence = 1;
+        current.apply_region(&[200, 100, 50, 255], 1, 1, 1, 1).unwrap();
+
+        let delta = current.delta_from(&previous).unwrap();
+        assert!(!delta.metadata.keyframe);
+        assert_eq!(delta.metadata.sequence, 1);
+        // Only the changed pixel is non-zero
+        assert_eq!(delta.data.iter().filter(|&&b| b != 0).count(), 3);
+
+        let restored = delta.apply_delta(&previous).unwrap();
+        assert_eq!(restored.data, current.data);
+        assert!(previous.delta_from(&previous).unwrap().data.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_delta_rejects_mismatched_frames() {
+        let frame = solid_frame(2, 2, [1, 2, 3, 255]);
+        assert!(matches!(
+            frame.delta_from(&solid_frame(4, 2, [1, 2, 3, 255])),
+            Err(FrameError::SizeMismatch { expected: 32, actual: 16 })
+        ));
+        assert!(matches!(
+            frame.delta_from(&frame.convert(FrameFormat::Rgb565).unwrap()),
+            Err(FrameError::UnsupportedConversion { from: FrameFormat::Rgb565, to: FrameFormat::Rgba })
+        ));
+    }
+
+    fn solid_frame(width: u32, height: u32, pixel: [u8; 4]) -> Frame {
+        let metadata = FrameMetadata {
+            width,
+            height,
+            ..test_metadata()
+        };
+        Frame::new(metadata, pixel.repeat((width * height) as usize)).unwrap()
+    }
+
+    /// Coordinates of the pixels in `frame` equal to `pixel`
+    fn pixels_matching(frame: &Frame, pixel: [u8; 4]) -> Vec<(u32, u32)> {
+        let width = frame.metadata.width;
+        frame
+            .data
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, p)| *p == pixel)
+            .map(|(i, _)| (i as u32 % width, i as u32 / width))
+            .collect()
+    }
+
+    #[test]
+    fn test_composite_clips_at_each_edge() {
+        const RED: [u8; 4] = [255, 0, 0, 255];
+        let overlay = solid_frame(2, 2, RED);
+
+        // (x, y) offset and the base pixels the visible part covers
+        let cases = [
+            ((-1, 1), vec![(0, 1), (0, 2)]), // left
+            ((1, -1), vec![(1, 0), (2, 0)]), // top
+            ((3, 1), vec![(3, 1), (3, 2)]),  // right
+            ((1, 3), vec![(1, 3), (2, 3)]),  // bottom
+            ((-1, -1), vec![(0, 0)]),        // top-left corner
+            ((1, 1), vec![(1, 1), (2, 1), (1, 2), (2, 2)]),
+            ((4, 0), vec![]),                // fully outside
+            ((-2, 0), vec![]),
+        ];
+        for ((x, y), expected) in cases {
+            let mut base = solid_frame(4, 4, [0, 0, 0, 255]);
+            base.composite(&overlay, x, y, BlendMode::SourceOver).unwrap();
+            assert_eq!(pixels_matching(&base, RED), expected, "offset ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn test_composite_alpha_blending() {
+        let overlay = solid_frame(1, 1, [255, 0, 0, 128]);
+
+        let mut base = solid_frame(1, 1, [0, 0, 255, 255]);
+        base.composite(&overlay, 0, 0, BlendMode::SourceOver).unwrap();
+        assert_eq!(base.data, vec![128, 0, 127, 255]);
+
+        // Over a transparent base the overlay shows through unchanged
+        let mut base = solid_frame(1, 1, [0, 0, 0, 0]);
+        base.composite(&overlay, 0, 0, BlendMode::SourceOver).unwrap();
+        assert_eq!(base.data, vec![255, 0, 0, 128]);
+
+        let mut base = solid_frame(1, 1, [0, 0, 255, 255]);
+        base.composite(&overlay, 0, 0, BlendMode::Replace).unwrap();
+        assert_eq!(base.data, vec![255, 0, 0, 128]);
+    }
+
+    /// RGBA frame whose pixels encode their own coordinates
+    fn coordinate_frame(width: u32, height: u32) -> Frame {
+        let metadata = FrameMetadata {
+            width,
+            height,
+            ..test_metadata()
+        };
+        let data: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 0, 255]))
+            .collect();
+        Frame::new(metadata, data).unwrap()
+    }
+
+    #[test]
+    fn test_crop_corner_and_center() {
+        let frame = coordinate_frame(6, 5);
+
+        // (x, y, width, height) regions: corners and the center
+        for (x, y, width, height) in [(0, 0, 2, 2), (4, 3, 2, 2), (0, 3, 3, 2), (2, 1, 2, 3), (0, 0, 6, 5)] {
+            let cropped = frame.crop(x, y, width, height).unwrap();
+            assert_eq!((cropped.metadata.width, cropped.metadata.height), (width, height));
+            assert_eq!(cropped.metadata.sequence, frame.metadata.sequence);
+            let expected: Vec<u8> = (y..y + height)
+                .flat_map(|row| (x..x + width).flat_map(move |col| [col as u8, row as u8, 0, 255]))
+                .collect();
+            assert_eq!(cropped.data, expected, "region {}x{} at ({}, {})", width, height, x, y);
+        }
+
+        // RGB565 crops the same region as RGBA then converting
+        let rgb565 = frame.convert(FrameFormat::Rgb565).unwrap();
+        let cropped = rgb565.crop(2, 1, 3, 2).unwrap();
+        assert_eq!(cropped.data, frame.crop(2, 1, 3, 2).unwrap().convert(FrameFormat::Rgb565).unwrap().data);
+    }
+
+    #[test]
+    fn test_crop_rejects_bad_regions() {
+        let frame = coordinate_frame(4, 4);
+        for (x, y, width, height) in [(3, 0, 2, 1), (0, 3, 1, 2), (4, 0, 1, 1), (0, 0, 0, 1), (u32::MAX, 0, 2, 1)] {
+            assert!(matches!(
+                frame.crop(x, y, width, height),
+                Err(FrameError::CropOutOfBounds { .. })
+            ));
+        }
+
+        let indexed = frame.quantize_indexed8().unwrap();
+        assert!(matches!(indexed.crop(0, 0, 2, 2), Err(FrameError::UnsupportedCrop(FrameFormat::Indexed8))));
+    }
+
+    #[test]
+    fn test_convert_timed_matches_convert() {
+        let frame = coordinate_frame(64, 32);
+        let (timed, elapsed) = frame.convert_timed(FrameFormat::Rgb565).unwrap();
+        assert_eq!(timed.data, frame.convert(FrameFormat::Rgb565).unwrap().data);
+        assert!(elapsed < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_apply_region() {
+        let mut frame = solid_frame(4, 3, [0, 0, 0, 255]);
+        let patch = coordinate_frame(2, 2);
+        frame.apply_region(&patch.data, 2, 1, 2, 2).unwrap();
+        assert_eq!(frame.crop(2, 1, 2, 2).unwrap().data, patch.data);
+        assert_eq!(frame.crop(0, 0, 2, 3).unwrap().data, solid_frame(2, 3, [0, 0, 0, 255]).data);
+
+        for (x, y, width, height) in [(3, 0, 2, 1), (0, 2, 1, 2), (0, 0, 0, 1), (u32::MAX, 0, 2, 1)] {
+            let data = vec![0u8; width as usize * height as usize * 4];
+            assert!(matches!(
+                frame.apply_region(&data, x, y, width, height),
+                Err(FrameError::InvalidDimensions { .. })
+            ));
+        }
+        assert!(matches!(
+            frame.apply_region(&[0u8; 4], 0, 0, 2, 1),
+            Err(FrameError::SizeMismatch { expected: 8, actual: 4 })
+        ));
+
+        let mut indexed = frame.quantize_indexed8().unwrap();
+        assert!(matches!(
+            indexed.apply_region(&[0u8; 1], 0, 0, 1, 1),
+            Err(FrameError::UnsupportedRegion(FrameFormat::Indexed8))
+        ));
+    }
+
+    #[test]
+    fn test_composite_rejects_other_formats() {
+        let mut base = solid_frame(2, 2, [0, 0, 0, 255]);
+        let overlay = base.convert(FrameFormat::Rgb565).unwrap();
+        assert!(matches!(
+            base.composite(&overlay, 0, 0, BlendMode::SourceOver),
+            Err(FrameError::UnsupportedComposite { .. })
+        ));
+    }
+
+    #[test]
+    fn test_indexed8_exact_roundtrip() {
+        let colors = [[0, 0, 0, 255], [170, 170, 170, 255], [0, 0, 170, 128]];
+        let input: Vec<u8> = (0..10).flat_map(|i| colors[i % 3]).collect();
+        let frame = row_frame(FrameFormat::Rgba, input);
+
+        let indexed = frame.convert(FrameFormat::Indexed8).unwrap();
+        assert_eq!(indexed.data.len(), INDEXED8_PALETTE_SIZE + 10);
+        assert_eq!(&indexed.data[..12], colors.concat().as_slice());
+        assert_eq!(&indexed.data[INDEXED8_PALETTE_SIZE..][..4], &[0, 1, 2, 0]);
+
+        let restored = indexed.convert(FrameFormat::Rgba).unwrap();
+        assert_eq!(restored.data, frame.data);
+        assert_eq!(frame.quantize_indexed8().unwrap().data, indexed.data);
+    }
+
+    #[test]
+    fn test_indexed8_too_many_colors() {
+        // 300 distinct greys/reds
+        let input: Vec<u8> = (0..300u32).flat_map(|i| [(i % 256) as u8, (i / 256) as u8, 0, 255]).collect();
+        let frame = row_frame(FrameFormat::Rgba, input);
+        assert!(matches!(frame.convert(FrameFormat::Indexed8), Err(FrameError::TooManyColors)));
+
+        // Quantizing is lossy but stays within a palette step of the input
+        let quantized = frame.quantize_indexed8().unwrap();
+        assert_eq!(quantized.metadata.format, FrameFormat::Indexed8);
+        let restored = quantized.convert(FrameFormat::Rgba).unwrap();
+        for (before, after) in frame.data.chunks_exact(4).zip(restored.data.chunks_exact(4)) {
+            assert!((before[0] as i32 - after[0] as i32).abs() <= 37);
+            assert!((before[1] as i32 - after[1] as i32).abs() <= 37);
+            assert_eq!(after[3], 255);
+        }
+
+        // Other formats go through RGBA
+        assert_eq!(
+            quantized.convert(FrameFormat::Rgb565).unwrap().data,
+            restored.convert(FrameFormat::Rgb565).unwrap().data
+        );
+    }
+
+    #[test]
+    fn test_quantize_colorful_rgb565() {
+        // 300 distinct RGB565 words
+        let input: Vec<u8> = (0..300u16).flat_map(|i| (i * 211).to_le_bytes()).collect();
+        let frame = row_frame(FrameFormat::Rgb565, input);
+        assert!(matches!(frame.convert(FrameFormat::Indexed8), Err(FrameError::TooManyColors)));
+
+        // Dithered from the RGBA pixels, not the raw RGB565 bytes
+        let quantized = frame.quantize_indexed8().unwrap();
+        assert_eq!(quantized.data.len(), INDEXED8_PALETTE_SIZE + 300);
+        let rgba = frame.convert(FrameFormat::Rgba).unwrap();
+        assert_eq!(quantized.data, rgba.quantize_indexed8().unwrap().data);
+    }
+
+    /// Random frames of the given format with dimensions up to `max_dim`
+    fn arb_frame(format: FrameFormat, max_dim: u32) -> impl Strategy<Value = Frame> {
+        (1..=max_dim, 1..=max_dim).prop_flat_map(move |(width, height)| {
+            let size = Frame::buffer_size(format, width, height).unwrap().unwrap();
+            proptest::collection::vec(any::<u8>(), size).prop_map(move |data| {
+                let metadata = FrameMetadata {
+                    width,
+                    height,
+                    format,
+                    ..test_metadata()
+                };
+                Frame::new(metadata, data).unwrap()
+            })
+        })
+    }
+
+    /// Random RGBA frames drawn from a palette of at most 256 colors
+    fn arb_few_color_frame(max_dim: u32) -> impl Strategy<Value = Frame> {
+        let colors = proptest::collection::vec(any::<[u8; 4]>(), 1..=INDEXED8_PALETTE_SIZE / 4);
+        (1..=max_dim, 1..=max_dim, colors).prop_flat_map(|(width, height, colors)| {
+            let pixels = (width * height) as usize;
+            proptest::collection::vec(any::<proptest::sample::Index>(), pixels).prop_map(move |indices| {
+                let data: Vec<u8> = indices.iter().flat_map(|i| colors[i.index(colors.len())]).collect();
+                let metadata = FrameMetadata {
+                    width,
+                    height,
+                    ..test_metadata()
+                };
+                Frame::new(metadata, data).unwrap()
+            })
+        })
+    }
+
+    /// Random RGBA frames of even size whose 2x2 blocks are each one color,
+    /// up to `max_blocks` blocks across and down
+    fn arb_block_frame(max_blocks: u32) -> impl Strategy<Value = Frame> {
+        (1..=max_blocks, 1..=max_blocks).prop_flat_map(|(columns, rows)| {
+            let blocks = (columns * rows) as usize;
+            proptest::collection::vec(any::<[u8; 4]>(), blocks).prop_map(move |colors| {
+                let (width, height) = (columns * 2, rows * 2);
+                let data: Vec<u8> = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (y / 2 * columns + x / 2) as usize))
+                    .flat_map(|block| colors[block])
+                    .collect();
+                let metadata = FrameMetadata {
+                    width,
+                    height,
+                    ..test_metadata()
+                };
+                Frame::new(metadata, data).unwrap()
+            })
+        })
+    }
+
+    /// Largest per-channel error of RGBA through YUV 4:2:0 and back, for
+    /// frames whose 2x2 blocks are one color each
+    ///
+    /// Chroma is shared by each 2x2 block, so blocks of mixed colors can be
+    /// off by far more. What is left is rounding in the 8-bit BT.601
+    /// coefficients: at most 2 for red and green and 3 for blue, found by
+    /// checking every RGB color. Alpha always comes back opaque.
+    fn check_yuv_error(frame: &Frame, format: FrameFormat) -> Result<(), TestCaseError> {
+        let restored = frame
+            .convert(format)
+            .and_then(|yuv| yuv.convert(FrameFormat::Rgba))
+            .unwrap();
+        prop_assert_eq!(restored.data.len(), frame.data.len());
+
+        for (original, restored) in frame.data.chunks_exact(4).zip(restored.data.chunks_exact(4)) {
+            prop_assert!(original[0].abs_diff(restored[0]) <= 2);
+            prop_assert!(original[1].abs_diff(restored[1]) <= 2);
+            prop_assert!(original[2].abs_diff(restored[2]) <= 3);
+            prop_assert_eq!(restored[3], 255);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_same_format_is_identity(frame in arb_frame(FrameFormat::Rgba, 32)) {
+            prop_assert_eq!(frame.convert(FrameFormat::Rgba).unwrap().data, frame.data);
+        }
+
+        /// Every RGB565 value survives expansion to RGBA and back exactly
+        #[test]
+        fn prop_rgb565_roundtrip_is_lossless(frame in arb_frame(FrameFormat::Rgb565, 32)) {
+            let restored = frame
+                .convert(FrameFormat::Rgba)
+                .and_then(|rgba| rgba.convert(FrameFormat::Rgb565))
+                .unwrap();
+            prop_assert_eq!(restored.data, frame.data);
+        }
+
+        /// RGBA through RGB565 loses at most the truncated low bits: 7 for
+        /// red and blue, 3 for green. Alpha always comes back opaque.
+        #[test]
+        fn prop_rgba_via_rgb565_error_is_bounded(frame in arb_frame(FrameFormat::Rgba, 32)) {
+            let restored = frame
+                .convert(FrameFormat::Rgb565)
+                .and_then(|rgb565| rgb565.convert(FrameFormat::Rgba))
+                .unwrap();
+            prop_assert_eq!(restored.data.len(), frame.data.len());
+
+            for (original, restored) in frame.data.chunks_exact(4).zip(restored.data.chunks_exact(4)) {
+                prop_assert!(original[0].abs_diff(restored[0]) <= 7);
+                prop_assert!(original[1].abs_diff(restored[1]) <= 3);
+                prop_assert!(original[2].abs_diff(restored[2]) <= 7);
+                prop_assert_eq!(restored[3], 255);
+            }
+        }
+
+        /// See `check_yuv_error` for the bound
+        #[test]
+        fn prop_rgba_via_yuv420_error_is_bounded(frame in arb_block_frame(16)) {
+            check_yuv_error(&frame, FrameFormat::Yuv420)?;
+        }
+
+        /// NV12 only reorders the chroma bytes, so its bound is YUV420's
+        #[test]
+        fn prop_rgba_via_nv12_error_is_bounded(frame in arb_block_frame(16)) {
+            check_yuv_error(&frame, FrameFormat::Nv12)?;
+        }
+
+        /// A frame with at most 256 colors, alpha included, survives
+        /// `Indexed8` and back exactly
+        #[test]
+        fn prop_indexed8_roundtrip_is_lossless(frame in arb_few_color_frame(32)) {
+            let restored = frame
+                .convert(FrameFormat::Indexed8)
+                .and_then(|indexed| indexed.convert(FrameFormat::Rgba))
+                .unwrap();
+            prop_assert_eq!(restored.data, frame.data);
+        }
+    }
+
+    #[cfg(feature = "simd")]
+    proptest! {
+        /// The vector path packs pixels exactly like the scalar one,
+        /// including the leftover pixels past the last full vector
+        #[test]
+        fn prop_simd_rgb565_matches_scalar(rgba in proptest::collection::vec(any::<[u8; 4]>(), 0..200)) {
+            let rgba = rgba.concat();
+            let mut scalar = Vec::new();
+            rgba_to_rgb565_scalar(&rgba, &mut scalar);
+
+            let mut simd = Vec::new();
+            let rest = rgba_to_rgb565_simd(&rgba, &mut simd);
+            rgba_to_rgb565_scalar(rest, &mut simd);
+            prop_assert_eq!(simd, scalar);
+        }
+    }
+
+    #[cfg(feature = "webp")]
+    proptest! {
+        // Encoding is slow, so keep frames small and the case count low
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn prop_lossless_compression_roundtrip(frame in arb_frame(FrameFormat::Rgba, 16)) {
+            let restored = frame
+                .convert(FrameFormat::Compressed)
+                .and_then(|compressed| compressed.convert(FrameFormat::Rgba))
+                .unwrap();
+            prop_assert_eq!(restored.data, frame.data);
+        }
+    }
+
+    /// The general-purpose codecs compiled into this build
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "deflate"))]
+    fn byte_codecs() -> impl Iterator<Item = CompressionCodec> {
+        [
+            (cfg!(feature = "zstd"), CompressionCodec::Zstd),
+            (cfg!(feature = "lz4"), CompressionCodec::Lz4),
+            (cfg!(feature = "deflate"), CompressionCodec::Deflate),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, codec)| enabled.then_some(codec))
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "deflate"))]
+    proptest! {
+        /// Zstd, LZ4 and DEFLATE give back exactly the RGBA bytes they were
+        /// given
+        #[test]
+        fn prop_byte_codec_roundtrip(frame in arb_frame(FrameFormat::Rgba, 32)) {
+            for codec in byte_codecs() {
+                let compressed = frame.convert_with_codec(FrameFormat::Compressed, codec).unwrap();
+                prop_assert_eq!(compressed.data[0], codec.id());
+                let restored = compressed.convert(FrameFormat::Rgba).unwrap();
+                prop_assert_eq!(&restored.data, &frame.data, "{:?}", codec);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_buffer_drop_stale_keeps_newest() {
+        let mut buffer = FrameBuffer::new(4);
+        for (sequence, timestamp) in [(1, 0.0), (2, 10.0), (3, 95.0)] {
+            let metadata = FrameMetadata {
+                sequence,
+                timestamp,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; 16]).unwrap());
+        }
+
+        assert_eq!(buffer.drop_stale(100.0, 50.0), 2);
+        assert_eq!(buffer.len(), 1);
+
+        // Everything is stale, but the newest frame survives
+        assert_eq!(buffer.drop_stale(1000.0, 50.0), 0);
+        assert_eq!(buffer.pop().unwrap().metadata.sequence, 3);
+    }
+
+    #[test]
+    fn test_frame_buffer_fullness() {
+        let mut buffer = FrameBuffer::new(4);
+        assert_eq!(buffer.capacity(), 4);
+        assert_eq!(buffer.fullness(), 0.0);
+
+        for sequence in 0..3 {
+            let metadata = FrameMetadata {
+                sequence,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; 16]).unwrap());
+        }
+        assert_eq!(buffer.fullness(), 0.75);
+        assert!(!buffer.is_full());
+
+        buffer.push(Frame::new(test_metadata(), vec![0u8; 16]).unwrap());
+        assert!(buffer.is_full());
+        assert_eq!(buffer.fullness(), 1.0);
+
+        // Overwriting keeps it full
+        buffer.push(Frame::new(test_metadata(), vec![0u8; 16]).unwrap());
+        assert!(buffer.is_full());
+        buffer.pop();
+        assert_eq!(buffer.fullness(), 0.75);
+    }
+
+    #[test]
+    fn test_frame_buffer_drop_policies() {
+        let frame = |sequence: u64, keyframe| {
+            let metadata = FrameMetadata {
+                sequence,
+                keyframe,
+                ..test_metadata()
+            };
+            Frame::new(metadata, vec![0u8; 16]).unwrap()
+        };
+        let sequences = |buffer: &FrameBuffer| buffer.iter().map(|f| f.metadata.sequence).collect::<Vec<_>>();
+
+        let mut newest = FrameBuffer::with_policy(3, DropPolicy::Newest);
+        for sequence in 0..3 {
+            assert!(newest.push(frame(sequence, false)));
+        }
+        assert!(!newest.push(frame(3, false)));
+        assert_eq!(sequences(&newest), vec![3]);
+        assert_eq!(newest.dropped_count(), 3);
+        assert_eq!(newest.byte_len(), 16);
+
+        // Deltas go first, oldest first, even when a keyframe is older
+        let mut keep = FrameBuffer::with_policy(3, DropPolicy::KeepKeyframe);
+        for (sequence, keyframe) in [(0, true), (1, false), (2, false)] {
+            keep.push(frame(sequence, keyframe));
+        }
+        assert!(!keep.push(frame(3, true)));
+        assert_eq!(sequences(&keep), vec![0, 2, 3]);
+        keep.push(frame(4, true));
+        assert_eq!(sequences(&keep), vec![0, 3, 4]);
+
+        // With only keyframes left it falls back to dropping the oldest
+        keep.push(frame(5, true));
+        assert_eq!(sequences(&keep), vec![3, 4, 5]);
+        assert_eq!(keep.dropped_count(), 3);
+        assert_eq!(keep.byte_len(), 48);
+        assert_eq!(keep.latest().unwrap().metadata.sequence, 5);
+        assert_eq!(keep.pop().unwrap().metadata.sequence, 3);
+    }
+
+    #[test]
+    fn test_frame_checksum() {
+        let frame = coordinate_frame(4, 2).with_checksum();
+        assert_eq!(frame.metadata.checksum, Some(crc32fast::hash(&frame.data)));
+        assert!(frame.verify_checksum().is_ok());
+
+        let mut corrupted = frame.clone();
+        let mut data = corrupted.data.to_vec();
+        data[5] ^= 0x10;
+        corrupted.data = data.into();
+        assert!(matches!(corrupted.verify_checksum(), Err(FrameError::ChecksumMismatch { .. })));
+
+        // Frames with new data don't inherit the old checksum
+        let converted = frame.convert(FrameFormat::Rgb565).unwrap();
+        assert_eq!(converted.metadata.checksum, None);
+        let mut patched = frame.clone();
+        patched.apply_region(&[0u8; 4], 0, 0, 1, 1).unwrap();
+        assert_eq!(patched.metadata.checksum, None);
+        assert!(frame.convert(FrameFormat::Rgba).unwrap().verify_checksum().is_ok());
+    }
+
+    #[test]
+    fn test_binary_frame_roundtrip() {
+        let mut frame = coordinate_frame(4, 2).with_checksum();
+        frame.metadata.generation = Some(9);
+        frame.metadata.stream_id = 3;
+        let encoded = encode_frame_binary(&frame);
+        let header_len = u32::from_le_bytes(encoded[..4].try_into().unwrap()) as usize;
+        assert_eq!(encoded.len(), 4 + header_len + frame.data.len());
+
+        let decoded = decode_frame_binary(&encoded).unwrap();
+        assert_eq!(decoded.data, frame.data);
+        let (expected, actual) = (&frame.metadata, &decoded.metadata);
+        assert_eq!((actual.sequence, actual.timestamp), (expected.sequence, expected.timestamp));
+        assert_eq!((actual.width, actual.height, actual.format), (4, 2, FrameFormat::Rgba));
+        assert_eq!((actual.generation, actual.stream_id), (Some(9), 3));
+        assert_eq!(actual.checksum, expected.checksum);
+        assert!(decoded.verify_checksum().is_ok());
+
+        let invalid = |bytes: &[u8]| matches!(decode_frame_binary(bytes), Err(FrameError::InvalidBinaryFrame(_)));
+        assert!(invalid(&encoded[..3]));
+        assert!(invalid(&encoded[..4 + header_len - 1]));
+        assert!(matches!(
+            decode_frame_binary(&encoded[..encoded.len() - 1]),
+            Err(FrameError::SizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_frame_buffer_set_capacity() {
+        let mut buffer = FrameBuffer::new(3);
+        for sequence in 0..5 {
+            let metadata = FrameMetadata {
+                sequence,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; 16]).unwrap());
+        }
+        let sequences = |buffer: &FrameBuffer| buffer.iter().map(|f| f.metadata.sequence).collect::<Vec<_>>();
+
+        // Growing keeps everything, wrapped or not
+        buffer.set_capacity(5);
+        assert_eq!(buffer.capacity(), 5);
+        assert_eq!(sequences(&buffer), vec![2, 3, 4]);
+        buffer.push(Frame::new(FrameMetadata { sequence: 5, ..test_metadata() }, vec![0u8; 16]).unwrap());
+        assert_eq!(sequences(&buffer), vec![2, 3, 4, 5]);
+
+        // Shrinking keeps the newest
+        let dropped = buffer.dropped_count();
+        buffer.set_capacity(2);
+        assert_eq!(sequences(&buffer), vec![4, 5]);
+        assert_eq!(buffer.dropped_count(), dropped + 2);
+        assert_eq!(buffer.byte_len(), 32);
+        assert!(buffer.is_full());
+        assert_eq!(buffer.latest().unwrap().metadata.sequence, 5);
+    }
+
+    #[test]
+    fn test_frame_buffer_latest() {
+        let mut buffer = FrameBuffer::new(2);
+        assert!(buffer.latest().is_none());
+
+        for sequence in 0..3 {
+            let metadata = FrameMetadata {
+                sequence,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; 16]).unwrap());
+            assert_eq!(buffer.latest().unwrap().metadata.sequence, sequence);
+        }
+
+        // Peeking leaves the frame in place
+        assert_eq!(buffer.len(), 2);
+        buffer.pop();
+        assert_eq!(buffer.latest().unwrap().metadata.sequence, 2);
+        buffer.pop();
+        assert!(buffer.latest().is_none());
+    }
+
+    #[test]
+    fn test_frame_buffer_iter_and_drain() {
+        let mut buffer = FrameBuffer::new(3);
+        // Wrap around so the live frames straddle the end of the ring
+        for sequence in 0..5 {
+            let metadata = FrameMetadata {
+                sequence,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; 16]).unwrap());
+        }
+
+        let iterated: Vec<u64> = buffer.iter().map(|f| f.metadata.sequence).collect();
+        assert_eq!(iterated, vec![2, 3, 4]);
+        assert_eq!(buffer.len(), 3);
+
+        let mut popped = Vec::new();
+        let mut copy = FrameBuffer::new(3);
+        for frame in buffer.iter() {
+            copy.push(frame.clone());
+        }
+        while let Some(frame) = copy.pop() {
+            popped.push(frame.metadata.sequence);
+        }
+        assert_eq!(iterated, popped);
+
+        // Slots already read are empty and never yielded
+        let mut partial = FrameBuffer::new(3);
+        for frame in buffer.iter() {
+            partial.push(frame.clone());
+        }
+        partial.pop();
+        let remaining: Vec<u64> = partial.iter().map(|f| f.metadata.sequence).collect();
+        assert_eq!(remaining, vec![3, 4]);
+        assert_eq!(partial.frames.iter().filter(|slot| slot.is_none()).count(), 1);
+
+        let drained: Vec<u64> = buffer.drain().map(|f| f.metadata.sequence).collect();
+        assert_eq!(drained, vec![2, 3, 4]);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.byte_len(), 0);
+        assert_eq!((buffer.read_index, buffer.write_index), (0, 0));
+        assert_eq!(buffer.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_frame_buffer_tracks_bytes() {
+        let mut buffer = FrameBuffer::new(2);
+        for (sequence, size) in [(1, 4), (2, 2), (3, 1)] {
+            let metadata = FrameMetadata {
+                sequence,
+                timestamp: sequence as f64,
+                width: size,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; size as usize * 8]).unwrap());
+        }
+
+        // Frame 1 was overwritten, leaving frames 2 and 3
+        assert_eq!(buffer.byte_len(), 16 + 8);
+        assert_eq!(buffer.oldest_timestamp(), Some(2.0));
+
+        buffer.pop();
+        assert_eq!(buffer.byte_len(), 8);
+        buffer.clear();
+        assert_eq!(buffer.byte_len(), 0);
+        assert_eq!(buffer.oldest_timestamp(), None);
+    }
+
+    #[test]
+    fn test_generation_tracker() {
+        let mut tracker = GenerationTracker::new();
+        // Unset generations are never torn
+        assert!(!tracker.observe(None, false));
+
+        assert!(!tracker.observe(Some(10), true));
+        assert!(!tracker.observe(Some(10), false));
+        assert!(!tracker.observe(Some(11), false));
+        // Skipped a generation
+        assert!(tracker.observe(Some(13), false));
+        // Unchecked until the next keyframe
+        assert!(!tracker.observe(Some(2), false));
+        assert!(!tracker.observe(Some(20), true));
+        // Went backwards
+        assert!(tracker.observe(Some(19), false));
+        assert_eq!(tracker.torn_count(), 2);
+
+        let mut tracker = GenerationTracker::new();
+        tracker.observe(Some(u32::MAX), true);
+        assert!(!tracker.observe(Some(0), false));
+    }
+
+    #[test]
+    fn test_frame_pool_reuses_buffers() {
+        let frame = Frame::new(test_metadata(), vec![255u8; 16]).unwrap();
+        let mut pool = FramePool::new(FrameFormat::Rgb565, 2, 2, 2).unwrap();
+        assert_eq!(pool.buffer_size(), 8);
+
+        let converted = pool.convert(&frame).unwrap();
+        assert_eq!(converted.metadata.format, FrameFormat::Rgb565);
+        let ptr = converted.data.as_ptr();
+        pool.release_frame(converted);
+        assert_eq!(pool.available(), 1);
+
+        let again = pool.convert(&frame).unwrap();
+        assert_eq!(again.data.as_ptr(), ptr);
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn test_frame_pool_discards_wrong_size_and_bounds() {
+        let mut pool = FramePool::new(FrameFormat::Rgba, 2, 2, 2).unwrap();
+        pool.release(vec![0u8; 8]);
+        assert_eq!(pool.available(), 0);
+
+        for _ in 0..3 {
+            pool.release(vec![0u8; 16]);
+        }
+        assert_eq!(pool.available(), 2);
+
+        assert!(matches!(
+            FramePool::new(FrameFormat::Compressed, 2, 2, 2),
+            Err(FrameError::UnsizedFormat(FrameFormat::Compressed))
+        ));
+    }
+
     #[test]
     fn test_frame_buffer() {
         let mut buffer = FrameBuffer::new(3);
@@ -247,4 +2315,43 @@ mod tests {
         assert_eq!(frame.metadata.sequence, 0);
         assert_eq!(buffer.len(), 2);
     }
+
+    #[test]
+    fn test_frame_buffer_overwrites_oldest() {
+        let mut buffer = FrameBuffer::new(2);
+        let metadata = test_metadata();
+        let mut results = Vec::new();
+        for i in 0..3 {
+            let mut m = metadata.clone();
+            m.sequence = i;
+            results.push(buffer.push(Frame::new(m, vec![0u8; 16]).unwrap()));
+        }
+
+        assert_eq!(results, vec![true, true, false]);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop().unwrap().metadata.sequence, 1);
+        assert_eq!(buffer.pop().unwrap().metadata.sequence, 2);
+        assert!(buffer.pop().is_none());
+    }
+
+    #[test]
+    fn test_frame_buffer_dropped_count() {
+        let mut buffer = FrameBuffer::new(3);
+        for sequence in 0..8 {
+            let metadata = FrameMetadata {
+                sequence,
+                ..test_metadata()
+            };
+            buffer.push(Frame::new(metadata, vec![0u8; 16]).unwrap());
+        }
+        assert_eq!(buffer.dropped_count(), 5);
+
+        // Reading and clearing are not drops
+        buffer.pop();
+        buffer.clear();
+        assert_eq!(buffer.dropped_count(), 5);
+
+        buffer.reset_stats();
+        assert_eq!(buffer.dropped_count(), 0);
+    }
 }
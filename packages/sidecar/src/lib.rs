@@ -13,17 +13,37 @@
 pub mod protocol;
 pub mod transport;
 pub mod frame;
+pub mod chunk;
+pub mod compression;
 
 #[cfg(feature = "native")]
 pub mod server;
 
+#[cfg(feature = "native")]
+pub mod config;
+
+#[cfg(feature = "native")]
+pub mod sink;
+
+#[cfg(feature = "native")]
+pub mod relay;
+
+#[cfg(feature = "native")]
+pub mod native;
+
+#[cfg(feature = "recording")]
+pub mod recording;
+
 #[cfg(feature = "wasm")]
 pub mod wasm;
 
 // Re-exports
 pub use protocol::*;
 pub use transport::Transport;
-pub use frame::{Frame, FrameBuffer};
+pub use frame::{
+    decode_frame_binary, encode_frame_binary, BlendMode, DropPolicy, Frame, FrameBuffer, FramePool, GenerationTracker,
+};
+pub use compression::CompressionCodec;
 
 /// Sidecar version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
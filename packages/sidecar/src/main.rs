@@ -3,42 +3,240 @@
 //! WebSocket server that accepts connections from browser clients
 //! for frame rendering and host integration.
 
-use qemuweb_sidecar::server::{ServerConfig, SidecarServer};
+use qemuweb_sidecar::config::FileConfig;
+use qemuweb_sidecar::server::{BindAddr, ServerConfig, SidecarServer};
 use qemuweb_sidecar::DEFAULT_PORT;
-use std::net::SocketAddr;
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Environment fallback for `--log-format`
+const LOG_FORMAT_ENV: &str = "SIDECAR_LOG_FORMAT";
+
+/// Environment fallback for `--log-level`
+const LOG_LEVEL_ENV: &str = "SIDECAR_LOG_LEVEL";
+
+/// Standard filter directives, used when no level is given otherwise
+const RUST_LOG_ENV: &str = "RUST_LOG";
+
+const USAGE: &str = "\
+Usage: qemuweb-sidecar [OPTIONS] [BIND_ADDR]
+
+Options:
+  --bind <ADDR>              Address or host:port to listen on [default: 127.0.0.1:9876]
+  --max-clients <N>          Maximum number of connected clients [default: 10]
+  --frame-buffer-size <N>    Frames buffered per client [default: 4]
+  --config <PATH>            TOML config file, re-read on SIGHUP
+  --log-level <LEVEL>        off, error, warn, info, debug or trace [default: info]
+                             [env: SIDECAR_LOG_LEVEL, then RUST_LOG directives]
+  --log-format <FORMAT>      compact, pretty or json [default: compact]
+                             [env: SIDECAR_LOG_FORMAT]
+  -h, --help                 Print this help
+
+Options given here take precedence over the config file. BIND_ADDR is the
+same as --bind.";
+
+/// Command line options
+#[derive(Debug, Default, PartialEq)]
+struct Cli {
+    bind_addr: Option<BindAddr>,
+    max_clients: Option<usize>,
+    frame_buffer_size: Option<usize>,
+    config_path: Option<PathBuf>,
+    log_level: Option<LevelFilter>,
+    log_format: Option<LogFormat>,
+    help: bool,
+}
+
+impl Cli {
+    /// Parse the arguments after the program name
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "-h" | "--help" => cli.help = true,
+                "--bind" => cli.bind_addr = Some(parse_bind_addr(&value()?)?),
+                "--max-clients" => cli.max_clients = Some(parse_count("--max-clients", &value()?)?),
+                "--frame-buffer-size" => {
+                    cli.frame_buffer_size = Some(parse_count("--frame-buffer-size", &value()?)?)
+                }
+                "--config" => cli.config_path = Some(PathBuf::from(value()?)),
+                "--log-level" => cli.log_level = Some(parse_log_level(&value()?)?),
+                "--log-format" => cli.log_format = Some(value()?.parse()?),
+                _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+                _ => cli.bind_addr = Some(parse_bind_addr(&arg)?),
+            }
+        }
+        Ok(cli)
+    }
+}
+
+/// A socket address, such as `0.0.0.0:9876` or `[::1]:9876`, or a
+/// `host:port` resolved when the server starts
+fn parse_bind_addr(value: &str) -> Result<BindAddr, String> {
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(value.into()),
+        _ => Err(format!("Invalid address: {} (expected host:port)", value)),
+    }
+}
+
+/// A positive count
+fn parse_count(flag: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("Invalid value for {}: {} (expected a positive number)", flag, value)),
+    }
+}
+
+fn parse_log_level(value: &str) -> Result<LevelFilter, String> {
+    value.parse().map_err(|_| format!("Unknown log level: {}", value))
+}
+
+/// Log output format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {} (expected json, compact or pretty)", s)),
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+    let cli = match Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if cli.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let log_format = match cli.log_format {
+        Some(format) => format,
+        None => match std::env::var(LOG_FORMAT_ENV) {
+            Ok(format) => format.parse()?,
+            Err(_) => LogFormat::default(),
+        },
+    };
+    let cli_log_level = match cli.log_level {
+        Some(level) => Some(level),
+        None => std::env::var(LOG_LEVEL_ENV).ok().map(|level| parse_log_level(&level)).transpose()?,
+    };
+
+    let config_path = cli.config_path.clone();
+    let file = match &config_path {
+        Some(path) => FileConfig::load(path)?,
+        None => FileConfig::default(),
+    };
+
+    // Command line and environment take precedence over the config file.
+    // RUST_LOG directives do their own filtering, so let everything through
+    // the level filter.
+    let env_filter = match cli_log_level {
+        Some(_) => None,
+        None => rust_log_filter()?,
+    };
+    let level = match env_filter {
+        Some(_) => LevelFilter::TRACE,
+        None => cli_log_level.or(file.log_level()?).unwrap_or(LevelFilter::INFO),
+    };
+    let level_fixed = cli_log_level.is_some() || env_filter.is_some();
+    let log_level = init_logging(log_format, level, env_filter);
+
+    let config = build_config(&cli, &file);
+
+    // The banner would corrupt a stream of JSON log lines
+    if log_format != LogFormat::Json {
+        print_banner();
+    }
+
+    let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+    let mut server = SidecarServer::new(config);
+    server.start().await?;
+
+    if let Some(addr) = server.local_addr().await {
+        info!("Server started on {}://{}", scheme, addr);
+    }
+    info!("Press Ctrl+C to stop");
+
+    // Wait for shutdown, reloading the config on SIGHUP
+    let mut reload_signal = ReloadSignal::new()?;
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = reload_signal.recv() => {
+                let log_level = (!level_fixed).then_some(&log_level);
+                reload_config(&server, config_path.as_deref(), &cli, log_level).await;
+            }
+        }
+    }
+
+    info!("Shutting down...");
+    server.stop().await;
+
+    Ok(())
+}
+
+/// `RUST_LOG` directives, if set
+fn rust_log_filter() -> Result<Option<EnvFilter>, String> {
+    match std::env::var(RUST_LOG_ENV) {
+        Ok(directives) if !directives.trim().is_empty() => EnvFilter::try_new(&directives)
+            .map(Some)
+            .map_err(|e| format!("Invalid {}: {}", RUST_LOG_ENV, e)),
+        _ => Ok(None),
+    }
+}
+
+/// Install the global subscriber, returning a handle to change its level
+fn init_logging(format: LogFormat, level: LevelFilter, env_filter: Option<EnvFilter>) -> LogLevelHandle {
+    let (level_filter, handle) = reload::Layer::new(level);
+    let layer = fmt::layer()
         .with_target(false)
         .with_thread_ids(false)
         .with_file(false)
-        .with_line_number(false)
-        .compact()
-        .init();
-
-    // Parse command line arguments
-    let args: Vec<String> = std::env::args().collect();
-    
-    let bind_addr: SocketAddr = if args.len() > 1 {
-        args[1].parse().unwrap_or_else(|_| {
-            eprintln!("Invalid address: {}, using default", args[1]);
-            format!("127.0.0.1:{}", DEFAULT_PORT).parse().unwrap()
-        })
-    } else {
-        format!("127.0.0.1:{}", DEFAULT_PORT).parse().unwrap()
+        .with_line_number(false);
+    let layer = match format {
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
     };
 
-    let config = ServerConfig {
-        bind_addr,
-        max_clients: 10,
-        frame_buffer_size: 4,
-    };
+    tracing_subscriber::registry()
+        .with(level_filter)
+        .with(env_filter)
+        .with(layer)
+        .init();
+    handle
+}
 
+fn print_banner() {
     println!();
     println!("╔══════════════════════════════════════════════════════════╗");
     println!("║           QemuWeb Sidecar v{}                       ║", qemuweb_sidecar::VERSION);
@@ -47,18 +245,142 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("║  Provides frame rendering and host integration           ║");
     println!("╚══════════════════════════════════════════════════════════╝");
     println!();
+}
 
-    let mut server = SidecarServer::new(config);
-    server.start().await?;
+/// Server config from defaults, the config file, then the command line
+fn build_config(cli: &Cli, file: &FileConfig) -> ServerConfig {
+    let mut config = ServerConfig::builder()
+        .bind_addr(format!("127.0.0.1:{}", DEFAULT_PORT))
+        .max_clients(10)
+        .frame_buffer_size(4)
+        .build();
+    file.apply(&mut config);
+    if let Some(addr) = &cli.bind_addr {
+        config.bind_addr = addr.clone();
+    }
+    if let Some(max_clients) = cli.max_clients {
+        config.max_clients = max_clients;
+    }
+    if let Some(frame_buffer_size) = cli.frame_buffer_size {
+        config.frame_buffer_size = frame_buffer_size;
+    }
+    config
+}
 
-    info!("Server started on ws://{}", bind_addr);
-    info!("Press Ctrl+C to stop");
+/// Re-read the config file and apply what can change at runtime
+///
+/// `log_level` is `None` when the level was fixed on the command line or by
+/// the environment, in which case the file's `log_level` is ignored.
+async fn reload_config(
+    server: &SidecarServer,
+    path: Option<&Path>,
+    cli: &Cli,
+    log_level: Option<&LogLevelHandle>,
+) {
+    let Some(path) = path else {
+        warn!("Reload requested but no --config file was given");
+        return;
+    };
 
-    // Wait for shutdown signal
-    tokio::signal::ctrl_c().await?;
+    info!("Reloading config from {}", path.display());
+    let file = match FileConfig::load(path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Config reload failed, keeping current config: {}", e);
+            return;
+        }
+    };
 
-    info!("Shutting down...");
-    server.stop().await;
+    let mut level_change = None;
+    if let (Some(log_level), Ok(Some(level))) = (log_level, file.log_level()) {
+        let before = LevelFilter::current();
+        if level != before {
+            match log_level.reload(level) {
+                Ok(()) => level_change = Some((before.to_string(), level.to_string())),
+                Err(e) => error!("Failed to change log level: {}", e),
+            }
+        }
+    }
 
-    Ok(())
+    // One summary for the log level and the server settings alike
+    let mut reload = server.reload_config(build_config(cli, &file)).await;
+    if let Some((before, after)) = level_change {
+        reload.applied.push(("log_level", before, after));
+    }
+    reload.log();
+}
+
+/// Fires on SIGHUP; never fires on platforms without it
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl ReloadSignal {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.hangup.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let cli = parse(&[
+            "--bind", "0.0.0.0:8080", "--max-clients", "3", "--frame-buffer-size", "8",
+            "--log-level", "debug", "--log-format", "json", "--config", "sidecar.toml",
+        ])
+        .unwrap();
+        assert_eq!(cli.bind_addr, Some("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!(cli.max_clients, Some(3));
+        assert_eq!(cli.frame_buffer_size, Some(8));
+        assert_eq!(cli.log_level, Some(LevelFilter::DEBUG));
+        assert_eq!(cli.log_format, Some(LogFormat::Json));
+        assert_eq!(cli.config_path, Some(PathBuf::from("sidecar.toml")));
+
+        // The command line wins over the config file
+        let file = FileConfig::parse("max_clients = 20\nframe_buffer_size = 2").unwrap();
+        let config = build_config(&cli, &file);
+        assert_eq!(config.max_clients, 3);
+        assert_eq!(config.frame_buffer_size, 8);
+
+        // A bare address still works, as do IPv6 addresses and host names
+        assert_eq!(parse(&["127.0.0.1:1234"]).unwrap().bind_addr, Some("127.0.0.1:1234".parse().unwrap()));
+        let ipv6 = parse(&["--bind", "[::1]:9876"]).unwrap().bind_addr;
+        assert_eq!(ipv6, Some(BindAddr::Addr("[::1]:9876".parse().unwrap())));
+        let host = parse(&["--bind", "localhost:9876"]).unwrap().bind_addr;
+        assert_eq!(host, Some(BindAddr::Host("localhost:9876".to_string())));
+        assert!(parse(&["--help"]).unwrap().help);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for args in [
+            &["--max-clients", "0"][..],
+            &["--max-clients", "many"],
+            &["--frame-buffer-size"],
+            &["--bind", "localhost"],
+            &["--bind", ":9876"],
+            &["--log-level", "loud"],
+            &["--verbose"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
 }
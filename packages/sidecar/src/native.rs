@@ -0,0 +1,496 @@
+//! Native Transport
+//!
+//! [`Transport`] over an outbound WebSocket connection, so a sidecar can
+//! talk to another sidecar (or anything speaking the same protocol) as a
+//! client and sidecars can be chained. Frames go out the way an emulator
+//! sends them: a `frame` message followed by the payload, with `setFormat`
+//! announcing each new format first.
+//!
+//! A background task reads the connection. Anything that parses as an
+//! [`EmulatorToSidecarMessage`] (including a sidecar server's `formatAck`
+//! and `requestKeyframe`, which share their wire form) is queued for
+//! [`Transport::poll`]; other replies such as pongs and throttles only
+//! update [`Transport::stats`]. Frames the peer broadcasts, a `frameHeader`
+//! followed by the payload in the format set with `setFormat`, are
+//! queued for [`NativeTransport::poll_frame`].
+
+use crate::frame::Frame;
+use crate::protocol::{
+    ConnectionState, EmulatorToSidecarMessage, FrameFormat, FrameMetadata, SidecarConfig, SidecarStats,
+    SidecarToEmulatorMessage, DEFAULT_STREAM,
+};
+use crate::relay::{self, UPSTREAM_CONNECT_TIMEOUT};
+use crate::transport::{FpsTracker, LatencyTracker, Transport, TransportError};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Weight of each new round trip in the rolling `avg_latency`
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Received frames kept for [`NativeTransport::poll_frame`]; older ones
+/// are dropped once this many are waiting
+const MAX_QUEUED_FRAMES: usize = 64;
+
+/// State shared with the read task
+struct Inbound {
+    state: ConnectionState,
+    stats: SidecarStats,
+    /// Ping round trips
+    latency: LatencyTracker,
+    /// Arrivals of received frames
+    fps: FpsTracker,
+    messages: VecDeque<EmulatorToSidecarMessage>,
+    frames: VecDeque<Frame>,
+    /// Header of the frame whose payload comes next
+    header: Option<FrameMetadata>,
+    /// Format last announced to the peer, which it sends frames back in
+    format: Option<(FrameFormat, u32, u32)>,
+}
+
+impl Default for Inbound {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::default(),
+            stats: SidecarStats::default(),
+            latency: LatencyTracker::default(),
+            fps: FpsTracker::new(60),
+            messages: VecDeque::new(),
+            frames: VecDeque::new(),
+            header: None,
+            format: None,
+        }
+    }
+}
+
+/// WebSocket client implementing [`Transport`]
+pub struct NativeTransport {
+    url: String,
+    config: SidecarConfig,
+    inbound: Arc<Mutex<Inbound>>,
+    sink: Option<SplitSink<WsStream, Message>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl NativeTransport {
+    /// Create a transport for `url`; nothing is dialed until `connect`
+    pub fn new(url: impl Into<String>, config: SidecarConfig) -> Self {
+        Self {
+            url: url.into(),
+            config,
+            inbound: Arc::default(),
+            sink: None,
+            reader: None,
+        }
+    }
+
+    /// Address the transport connects to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Next frame received from the peer, oldest first
+    pub fn poll_frame(&mut self) -> Option<Frame> {
+        self.inbound().frames.pop_front()
+    }
+
+    /// Ping the peer; the round trip shows up in `avg_latency` and the
+    /// latency percentiles of [`Transport::stats`]
+    pub async fn ping(&mut self) -> Result<(), TransportError> {
+        self.send_text(&EmulatorToSidecarMessage::Ping { timestamp: now_ms() }).await
+    }
+
+    fn inbound(&self) -> MutexGuard<'_, Inbound> {
+        lock(&self.inbound)
+    }
+
+    fn sink(&mut self) -> Result<&mut SplitSink<WsStream, Message>, TransportError> {
+        if self.inbound().state != ConnectionState::Connected {
+            return Err(TransportError::NotConnected);
+        }
+        self.sink.as_mut().ok_or(TransportError::NotConnected)
+    }
+
+    async fn send_text<T: serde::Serialize>(&mut self, msg: &T) -> Result<(), TransportError> {
+        let json = serde_json::to_string(msg).map_err(|e| TransportError::SendFailed(e.to_string()))?;
+        self.sink()?
+            .send(Message::Text(json))
+            .await
+            .map_err(|e| TransportError::SendFailed(e.to_string()))
+    }
+}
+
+impl Transport for NativeTransport {
+    fn state(&self) -> ConnectionState {
+        self.inbound().state
+    }
+
+    fn config(&self) -> &SidecarConfig {
+        &self.config
+    }
+
+    fn connect(&mut self) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+        Box::pin(async move {
+            if self.state() == ConnectionState::Connected {
+                return Ok(());
+            }
+            self.inbound().state = ConnectionState::Connecting;
+
+            let result = tokio::time::timeout(UPSTREAM_CONNECT_TIMEOUT, tokio_tungstenite::connect_async(&self.url))
+                .await
+                .map_err(|_| TransportError::ConnectionFailed(format!("timed out connecting to {}", self.url)))
+                .and_then(|result| {
+                    result.map_err(|e| TransportError::ConnectionFailed(format!("{}: {}", self.url, e)))
+                });
+            let ws = match result {
+                Ok((ws, _)) => ws,
+                Err(e) => {
+                    self.inbound().state = ConnectionState::Error;
+                    return Err(e);
+                }
+            };
+            info!("Connected to {}", self.url);
+
+            let (sink, stream) = ws.split();
+            self.sink = Some(sink);
+            {
+                let mut inbound = self.inbound();
+                inbound.format = None;
+                inbound.header = None;
+                inbound.state = ConnectionState::Connected;
+            }
+            self.reader = Some(tokio::spawn(read_messages(stream, self.inbound.clone(), self.url.clone())));
+            Ok(())
+        })
+    }
+
+    fn disconnect(&mut self) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+        Box::pin(async move {
+            let sink = self.sink.take();
+            if let Some(reader) = self.reader.take() {
+                reader.abort();
+            }
+            self.inbound().state = ConnectionState::Disconnected;
+            if let Some(mut sink) = sink {
+                // The peer may already be gone; either way the connection is over
+                let _ = sink.close().await;
+                info!("Disconnected from {}", self.url);
+            }
+            Ok(())
+        })
+    }
+
+    fn send_frame(&mut self, frame: Frame) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+        Box::pin(async move {
+            let len = frame.data.len() as u64;
+            let mut format = self.inbound().format;
+            let result = relay::send_frame(self.sink()?, &mut format, frame).await;
+            self.inbound().format = format;
+            result.map_err(|e| TransportError::SendFailed(e.to_string()))?;
+            self.inbound().stats.bytes_transferred += len;
+            Ok(())
+        })
+    }
+
+    fn send_message(
+        &mut self,
+        msg: SidecarToEmulatorMessage,
+    ) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+        Box::pin(async move { self.send_text(&msg).await })
+    }
+
+    fn set_format(
+        &mut self,
+        format: FrameFormat,
+        width: u32,
+        height: u32,
+    ) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+        Box::pin(async move {
+            self.send_text(&EmulatorToSidecarMessage::SetFormat { format, width, height }).await?;
+            self.inbound().format = Some((format, width, height));
+            Ok(())
+        })
+    }
+
+    fn stats(&self) -> SidecarStats {
+        self.inbound().stats.clone()
+    }
+
+    fn poll(&mut self) -> Option<EmulatorToSidecarMessage> {
+        self.inbound().messages.pop_front()
+    }
+}
+
+impl Drop for NativeTransport {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+    }
+}
+
+fn lock(inbound: &Mutex<Inbound>) -> MutexGuard<'_, Inbound> {
+    // Nothing panics while holding the lock, but don't wedge if it did
+    inbound.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Read the connection until it closes, queueing or recording each message
+async fn read_messages(mut stream: SplitStream<WsStream>, inbound: Arc<Mutex<Inbound>>, url: String) {
+    let state = loop {
+        match stream.next().await {
+            Some(Ok(Message::Text(text))) => handle_text(&inbound, &text, &url),
+            Some(Ok(Message::Binary(data))) => handle_binary(&inbound, data, &url),
+            Some(Ok(Message::Close(_))) | None => {
+                info!("{} closed the connection", url);
+                break ConnectionState::Disconnected;
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                warn!("Connection to {} failed: {}", url, e);
+                break ConnectionState::Error;
+            }
+        }
+    };
+    lock(&inbound).state = state;
+}
+
+/// Pair a binary payload with the `frameHeader` before it and queue the frame
+fn handle_binary(inbound: &Mutex<Inbound>, data: Vec<u8>, url: &str) {
+    let now = now_ms();
+    let inbound = &mut *lock(inbound);
+    inbound.stats.frames_received += 1;
+    inbound.stats.bytes_transferred += data.len() as u64;
+    inbound.fps.record(now);
+    inbound.stats.current_fps = inbound.fps.fps();
+
+    let Some(mut metadata) = inbound.header.take() else {
+        debug!("{} sent a payload without a frame header", url);
+        return;
+    };
+    let Some((format, width, height)) = inbound.format else {
+        debug!("{} sent frame {} before a format was set", url, metadata.sequence);
+        return;
+    };
+    metadata.timestamp = now;
+    metadata.format = format;
+    metadata.width = width;
+    metadata.height = height;
+
+    match Frame::new(metadata, data) {
+        Ok(frame) => {
+            if inbound.frames.len() >= MAX_QUEUED_FRAMES {
+                inbound.frames.pop_front();
+                inbound.stats.frames_dropped += 1;
+            }
+            inbound.frames.push_back(frame);
+        }
+        Err(e) => {
+            warn!("Unusable frame from {}: {}", url, e);
+            inbound.stats.frames_dropped += 1;
+        }
+    }
+}
+
+fn handle_text(inbound: &Mutex<Inbound>, text: &str, url: &str) {
+    // Server replies parse as `Unknown` here, so they fall through
+    match serde_json::from_str::<EmulatorToSidecarMessage>(text) {
+        Ok(EmulatorToSidecarMessage::Unknown) | Err(_) => {}
+        Ok(msg) => {
+            lock(inbound).messages.push_back(msg);
+            return;
+        }
+    }
+
+    match serde_json::from_str::<SidecarToEmulatorMessage>(text) {
+        Ok(SidecarToEmulatorMessage::Pong { timestamp, .. }) => {
+            let rtt = now_ms() - timestamp;
+            let inbound = &mut *lock(inbound);
+            let stats = &mut inbound.stats;
+            stats.avg_latency = if stats.avg_latency == 0.0 {
+                rtt
+            } else {
+                stats.avg_latency * (1.0 - LATENCY_SMOOTHING) + rtt * LATENCY_SMOOTHING
+            };
+            inbound.latency.record(rtt);
+            inbound.latency.update_stats(stats);
+        }
+        Ok(SidecarToEmulatorMessage::FrameThrottle { .. }) => lock(inbound).stats.frames_dropped += 1,
+        Ok(SidecarToEmulatorMessage::FrameHeader {
+            sequence,
+            generation,
+            keyframe,
+            stream_id,
+            ..
+        }) => {
+            // Dimensions and format are filled in when the payload arrives
+            lock(inbound).header = Some(FrameMetadata {
+                sequence,
+                timestamp: 0.0,
+                width: 0,
+                height: 0,
+                format: FrameFormat::Rgba,
+                keyframe: keyframe.unwrap_or(true),
+                generation,
+                checksum: None,
+                stream_id: stream_id.unwrap_or(DEFAULT_STREAM),
+            });
+        }
+        Ok(SidecarToEmulatorMessage::Error { code, message }) => {
+            warn!("{} reported {}: {}", url, code, message);
+        }
+        Ok(msg) => debug!("{} sent {:?}", url, msg),
+        Err(e) => warn!("Unreadable message from {}: {}", url, e),
+    }
+}
+
+fn now_ms() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FrameMetadata;
+    use crate::server::{ServerConfig, SidecarServer};
+    use crate::sink::RecordingSink;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_native_transport_chains_to_server() {
+        let sink = Arc::new(RecordingSink::new());
+        let mut server = SidecarServer::new(
+            ServerConfig::builder()
+                .bind_addr("127.0.0.1:0")
+                .frame_sink(sink.clone())
+                .build(),
+        );
+        server.start().await.unwrap();
+        let url = format!("ws://{}", server.local_addr().await.unwrap());
+
+        let mut transport = NativeTransport::new(url, SidecarConfig::default());
+        let frame = Frame::new(
+            FrameMetadata {
+                sequence: 1,
+                timestamp: 0.0,
+                width: 2,
+                height: 2,
+                format: FrameFormat::Rgba,
+                keyframe: true,
+                generation: None,
+                checksum: None,
+                stream_id: 0,
+            },
+            vec![7u8; 16],
+        )
+        .unwrap();
+        assert!(matches!(transport.send_frame(frame.clone()).await, Err(TransportError::NotConnected)));
+
+        transport.connect().await.unwrap();
+        assert_eq!(transport.state(), ConnectionState::Connected);
+        transport.set_format(FrameFormat::Rgba, 2, 2).await.unwrap();
+        transport.send_frame(frame).await.unwrap();
+
+        for _ in 0..100 {
+            if !sink.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let frames = sink.take();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1.data, vec![7u8; 16]);
+
+        // Requests from the server are queued for poll
+        let id = server.client_ids().await[0];
+        let client = server.client(id).await.unwrap();
+        client.send_message(&SidecarToEmulatorMessage::RequestKeyframe).unwrap();
+        let mut polled = Vec::new();
+        for _ in 0..100 {
+            polled.extend(transport.poll_all());
+            if polled.iter().any(|msg| matches!(msg, EmulatorToSidecarMessage::RequestKeyframe)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(
+            polled.as_slice(),
+            [EmulatorToSidecarMessage::FormatAck { success: true, .. }, EmulatorToSidecarMessage::RequestKeyframe]
+        ));
+
+        transport.disconnect().await.unwrap();
+        assert_eq!(transport.state(), ConnectionState::Disconnected);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_native_transport_receives_frames() {
+        let mut server = SidecarServer::new(ServerConfig::builder().bind_addr("127.0.0.1:0").build());
+        server.start().await.unwrap();
+        let url = format!("ws://{}", server.local_addr().await.unwrap());
+
+        let mut transport = NativeTransport::new(url, SidecarConfig::default());
+        transport.connect().await.unwrap();
+        transport.set_format(FrameFormat::Rgba, 2, 2).await.unwrap();
+        for _ in 0..100 {
+            if transport.poll().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        for sequence in 1..=2 {
+            let metadata = FrameMetadata {
+                sequence,
+                timestamp: 0.0,
+                width: 2,
+                height: 2,
+                format: FrameFormat::Rgba,
+                keyframe: true,
+                generation: None,
+                checksum: None,
+                stream_id: 0,
+            };
+            server.broadcast_frame(0, Frame::new(metadata, vec![sequence as u8; 16]).unwrap()).await.unwrap();
+        }
+
+        let mut frames = Vec::new();
+        for _ in 0..100 {
+            frames.extend(std::iter::from_fn(|| transport.poll_frame()));
+            if frames.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].metadata.sequence, 2);
+        assert_eq!((frames[1].metadata.width, frames[1].metadata.height), (2, 2));
+        assert_eq!(frames[1].data, vec![2u8; 16]);
+        assert_eq!(transport.stats().frames_received, 2);
+
+        transport.ping().await.unwrap();
+        for _ in 0..100 {
+            if transport.stats().avg_latency > 0.0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(transport.stats().avg_latency > 0.0);
+
+        transport.disconnect().await.unwrap();
+        server.stop().await;
+    }
+}
@@ -2,38 +2,38 @@
 //!
 //! Matches the TypeScript definitions in @qemuweb/sidecar-proto
 
-use serde::{Deserialize, Serialize};
+use crate::compression::CompressionCodec;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use thiserror::Error;
 
 /// Sidecar operating mode
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum SidecarMode {
+    #[default]
     Local,
     Remote,
     Disabled,
 }
 
-impl Default for SidecarMode {
-    fn default() -> Self {
-        Self::Local
-    }
-}
-
 /// Frame format for transmission
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FrameFormat {
+    #[default]
     Rgba,
     Rgb565,
     Yuv420,
     Compressed,
+    /// 256-entry RGBA palette followed by one palette index per pixel
+    Indexed8,
+    /// Semi-planar YUV 4:2:0: the Y plane, then interleaved U/V samples
+    Nv12,
 }
 
-impl Default for FrameFormat {
-    fn default() -> Self {
-        Self::Rgba
-    }
-}
+/// Size of the palette at the start of an `Indexed8` frame, in bytes
+pub const INDEXED8_PALETTE_SIZE: usize = 256 * 4;
 
 impl FrameFormat {
     /// Bytes per pixel (for uncompressed formats)
@@ -43,26 +43,56 @@ impl FrameFormat {
             FrameFormat::Rgb565 => Some(2),
             FrameFormat::Yuv420 => None, // Variable
             FrameFormat::Compressed => None,
+            FrameFormat::Indexed8 => Some(1),
+            FrameFormat::Nv12 => None,
+        }
+    }
+
+    /// Format id used in binary frame headers
+    pub fn to_byte(self) -> u8 {
+        match self {
+            FrameFormat::Rgba => 0,
+            FrameFormat::Rgb565 => 1,
+            FrameFormat::Yuv420 => 2,
+            FrameFormat::Compressed => 3,
+            FrameFormat::Indexed8 => 4,
+            FrameFormat::Nv12 => 5,
+        }
+    }
+
+    /// Format for a binary frame header id
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(FrameFormat::Rgba),
+            1 => Some(FrameFormat::Rgb565),
+            2 => Some(FrameFormat::Yuv420),
+            3 => Some(FrameFormat::Compressed),
+            4 => Some(FrameFormat::Indexed8),
+            5 => Some(FrameFormat::Nv12),
+            _ => None,
+        }
+    }
+
+    /// Bytes that precede the pixel data, such as a palette
+    pub fn header_size(&self) -> usize {
+        match self {
+            FrameFormat::Indexed8 => INDEXED8_PALETTE_SIZE,
+            _ => 0,
         }
     }
 }
 
 /// Sidecar connection state
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ConnectionState {
+    #[default]
     Disconnected,
     Connecting,
     Connected,
     Error,
 }
 
-impl Default for ConnectionState {
-    fn default() -> Self {
-        Self::Disconnected
-    }
-}
-
 /// Frame metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -84,8 +114,164 @@ pub struct FrameMetadata {
 
     /// Whether this is a keyframe (full frame vs delta)
     pub keyframe: bool,
+
+    /// Generation of the source framebuffer this frame was read from
+    ///
+    /// Optional; sources that set it let receivers detect torn frames with
+    /// a `GenerationTracker`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u32>,
+
+    /// CRC-32 of the frame data
+    ///
+    /// Optional; when present, receivers drop frames whose data doesn't
+    /// match it. Not carried by the binary header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u32>,
+
+    /// Stream the frame belongs to, e.g. one per monitor of the guest
+    ///
+    /// Clients only receive broadcasts on streams they are subscribed to.
+    /// Not carried by the binary header.
+    #[serde(default, skip_serializing_if = "is_default_stream")]
+    pub stream_id: u32,
+}
+
+/// Stream every client is subscribed to on connecting
+pub const DEFAULT_STREAM: u32 = 0;
+
+fn is_default_stream(stream_id: &u32) -> bool {
+    *stream_id == DEFAULT_STREAM
+}
+
+/// Size of a binary frame header, in bytes
+pub const FRAME_HEADER_SIZE: usize = 26;
+
+/// Binary header flag: the frame is a keyframe
+const HEADER_FLAG_KEYFRAME: u8 = 0x01;
+
+impl FrameMetadata {
+    /// Pack the metadata into a binary frame header
+    ///
+    /// Little-endian `sequence: u64, timestamp: f64, width: u32,
+    /// height: u32, format: u8, flags: u8`, where flag bit 0 marks a
+    /// keyframe. `generation`, `checksum` and `stream_id` are not carried.
+    pub fn to_header_bytes(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        header[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        header[16..20].copy_from_slice(&self.width.to_le_bytes());
+        header[20..24].copy_from_slice(&self.height.to_le_bytes());
+        header[24] = self.format.to_byte();
+        header[25] = if self.keyframe { HEADER_FLAG_KEYFRAME } else { 0 };
+        header
+    }
+
+    /// Unpack a binary frame header from the start of `bytes`
+    ///
+    /// Returns `None` if `bytes` is too short or names an unknown format.
+    pub fn from_header_bytes(bytes: &[u8]) -> Option<Self> {
+        let header = bytes.get(..FRAME_HEADER_SIZE)?;
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        Some(Self {
+            sequence: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+            timestamp: f64::from_le_bytes(header[8..16].try_into().unwrap()),
+            width: u32_at(16),
+            height: u32_at(20),
+            format: FrameFormat::from_byte(header[24])?,
+            keyframe: header[25] & HEADER_FLAG_KEYFRAME != 0,
+            generation: None,
+            checksum: None,
+            stream_id: DEFAULT_STREAM,
+        })
+    }
+}
+
+/// Header for one piece of a frame split across several binary messages
+///
+/// Sent as a `frameChunk` message immediately before the chunk's binary
+/// payload, after the frame's regular `frame` metadata message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameChunk {
+    /// Sequence number of the frame this chunk belongs to
+    pub sequence: u64,
+
+    /// Zero-based position of this chunk within the frame
+    pub chunk_index: u32,
+
+    /// Total number of chunks making up the frame
+    pub chunk_count: u32,
+
+    /// CRC-32 of the whole reassembled payload, checked before the frame
+    /// is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc: Option<u32>,
+}
+
+/// A changed rectangle of the client's latest frame
+///
+/// Sent as a `frameRegion` message followed by a binary payload holding the
+/// region's rows in the negotiated format. The server applies it to a copy
+/// of the client's latest frame, which becomes frame `sequence`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameRegion {
+    /// Sequence number of the frame the update produces
+    pub sequence: u64,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What a sidecar server is running, for diagnostics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    /// Crate version of the server
+    pub version: String,
+
+    /// Seconds since the server started
+    pub uptime_secs: u64,
+
+    /// Clients currently connected, including the one asking
+    pub client_count: usize,
+
+    /// Connection limit
+    pub max_clients: usize,
+
+    /// Frame formats the server can convert to and from
+    pub supported_formats: Vec<FrameFormat>,
+
+    /// How often rate and size limits have engaged since the server started
+    #[serde(default)]
+    pub rate_limits: RateLimitStats,
+}
+
+/// Server-wide counts of work refused by rate and size limits
+///
+/// Kept apart from `frames_dropped` so pacing and policy refusals can be
+/// told from frames lost in transit.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitStats {
+    /// Broadcast frames skipped to hold clients to their target fps
+    pub frames_rate_limited: u64,
+
+    /// Connections closed by the accept rate limiter
+    pub connections_rejected: u64,
+
+    /// Frames from clients refused for exceeding `max_frames_per_sec`
+    #[serde(default)]
+    pub inbound_frames_limited: u64,
+
+    /// Messages over the size limit, each closing its connection
+    pub oversized_messages: u64,
 }
 
+/// Valid range for a client's target frame rate
+pub const TARGET_FPS_RANGE: std::ops::RangeInclusive<u32> = 1..=240;
+
 /// Sidecar configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -109,9 +295,32 @@ pub struct SidecarConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub enable_compression: Option<bool>,
 
+    /// Codec for frames sent as `compressed`; [`CompressionCodec::default`]
+    /// when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_codec: Option<CompressionCodec>,
+
     /// Ring buffer size in frames (for local mode)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ring_buffer_size: Option<usize>,
+
+    /// Drop frames older than this many ms instead of showing them late
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_frame_age_ms: Option<f64>,
+
+    /// Send frames as a single binary message with a packed metadata
+    /// header instead of a JSON `frame` message followed by the payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary_header: Option<bool>,
+
+    /// Outgoing bytes per second above which frames step down from RGBA to
+    /// RGB565; unset or 0 leaves the format alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth: Option<f64>,
+
+    /// Let `max_bandwidth` step down to `compressed` as a last resort
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive_compression: Option<bool>,
 }
 
 impl Default for SidecarConfig {
@@ -122,13 +331,18 @@ impl Default for SidecarConfig {
             preferred_format: Some(FrameFormat::Rgba),
             remote_url: None,
             enable_compression: Some(false),
+            compression_codec: None,
             ring_buffer_size: Some(4),
+            max_frame_age_ms: None,
+            binary_header: None,
+            max_bandwidth: None,
+            adaptive_compression: None,
         }
     }
 }
 
 /// Sidecar statistics
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SidecarStats {
     /// Frames received
@@ -140,11 +354,179 @@ pub struct SidecarStats {
     /// Average frame latency in ms
     pub avg_latency: f64,
 
+    /// Lowest latency in the recent window, in ms
+    #[serde(default)]
+    pub min_latency: f64,
+
+    /// Highest latency in the recent window, in ms
+    #[serde(default)]
+    pub max_latency: f64,
+
+    /// 95th percentile latency in the recent window, in ms
+    #[serde(default)]
+    pub p95_latency: f64,
+
     /// Current FPS
     pub current_fps: f64,
 
     /// Total bytes transferred
     pub bytes_transferred: u64,
+
+    /// Recent throughput in bytes per second
+    #[serde(default)]
+    pub bytes_per_second: f64,
+
+    /// Bytes currently held in the receive frame buffer
+    #[serde(default)]
+    pub buffered_bytes: u64,
+
+    /// Frames currently held in the receive frame buffer
+    #[serde(default)]
+    pub buffer_depth: u64,
+
+    /// Uncompressed / compressed size of recent frames, 1.0 when frames
+    /// are sent uncompressed
+    #[serde(default = "default_compression_ratio")]
+    pub compression_ratio: f64,
+
+    /// Incomplete chunked frames evicted because too many were pending
+    ///
+    /// Also counted in `frames_dropped`.
+    #[serde(default)]
+    pub reassembly_overflows: u64,
+
+    /// Chunks still missing from chunked frames that were given up on
+    #[serde(default)]
+    pub chunks_lost: u64,
+
+    /// Chunked frames whose reassembled payload failed its CRC check
+    ///
+    /// Also counted in `frames_dropped`.
+    #[serde(default)]
+    pub frames_corrupted: u64,
+
+    /// Broadcast frames skipped to hold the client to its target fps
+    ///
+    /// Not counted in `frames_dropped`.
+    #[serde(default)]
+    pub frames_rate_limited: u64,
+
+    /// Frames from the client refused for exceeding the server's
+    /// `max_frames_per_sec`
+    ///
+    /// Not counted in `frames_dropped`.
+    #[serde(default)]
+    pub inbound_frames_limited: u64,
+
+    /// Messages rejected for exceeding the size limit
+    #[serde(default)]
+    pub oversized_messages: u64,
+
+    /// Estimated offset of the client's clock from the server's in ms,
+    /// positive when the client is ahead
+    ///
+    /// Estimated from the timestamps of the client's pings; 0 until it
+    /// sends one.
+    #[serde(default)]
+    pub clock_offset: f64,
+}
+
+fn default_compression_ratio() -> f64 {
+    1.0
+}
+
+impl Default for SidecarStats {
+    fn default() -> Self {
+        Self {
+            frames_received: 0,
+            frames_dropped: 0,
+            avg_latency: 0.0,
+            min_latency: 0.0,
+            max_latency: 0.0,
+            p95_latency: 0.0,
+            current_fps: 0.0,
+            bytes_transferred: 0,
+            bytes_per_second: 0.0,
+            buffered_bytes: 0,
+            buffer_depth: 0,
+            compression_ratio: default_compression_ratio(),
+            reassembly_overflows: 0,
+            chunks_lost: 0,
+            frames_corrupted: 0,
+            frames_rate_limited: 0,
+            inbound_frames_limited: 0,
+            oversized_messages: 0,
+            clock_offset: 0.0,
+        }
+    }
+}
+
+impl SidecarStats {
+    /// Frame rate at or above which the fps factor is perfect
+    const QUALITY_GOOD_FPS: f64 = 30.0;
+    /// Latency at or below which the latency factor is perfect, in ms
+    const QUALITY_GOOD_LATENCY_MS: f64 = 20.0;
+    /// Latency at or above which the latency factor is zero, in ms
+    const QUALITY_BAD_LATENCY_MS: f64 = 500.0;
+    /// Drop rate at or above which the drop factor is zero
+    const QUALITY_BAD_DROP_RATE: f64 = 0.2;
+    /// Throughput at or above which the bandwidth factor is perfect
+    const QUALITY_GOOD_BYTES_PER_SECOND: f64 = 1024.0 * 1024.0;
+
+    /// Single 0-100 connection quality indicator
+    ///
+    /// A weighted blend of four factors, each scored from 0.0 to 1.0:
+    ///
+    /// | Factor | Weight | 1.0 at | 0.0 at |
+    /// |--------|--------|--------|--------|
+    /// | Drop rate | 35 | no drops | 20% of frames dropped |
+    /// | Latency | 30 | ≤ 20 ms | ≥ 500 ms |
+    /// | Frame rate | 25 | ≥ 30 fps | 0 fps |
+    /// | Bandwidth | 10 | ≥ 1 MiB/s | 0 B/s |
+    ///
+    /// Factors scale linearly between their end points, so the score falls
+    /// steadily as any one of them worsens. Before any frame arrives there
+    /// is nothing to judge and the score is 0.
+    pub fn quality_score(&self) -> u8 {
+        let total = self.frames_received + self.frames_dropped;
+        if total == 0 {
+            return 0;
+        }
+
+        let drop_rate = self.frames_dropped as f64 / total as f64;
+        let drops = 1.0 - drop_rate / Self::QUALITY_BAD_DROP_RATE;
+        let latency = (Self::QUALITY_BAD_LATENCY_MS - self.avg_latency)
+            / (Self::QUALITY_BAD_LATENCY_MS - Self::QUALITY_GOOD_LATENCY_MS);
+        let fps = self.current_fps / Self::QUALITY_GOOD_FPS;
+        let bandwidth = self.bytes_per_second / Self::QUALITY_GOOD_BYTES_PER_SECOND;
+
+        let score = 35.0 * clamp_unit(drops)
+            + 30.0 * clamp_unit(latency)
+            + 25.0 * clamp_unit(fps)
+            + 10.0 * clamp_unit(bandwidth);
+        score.round().clamp(0.0, 100.0) as u8
+    }
+}
+
+/// Clamp a factor into 0.0..=1.0, treating NaN as 0.0
+fn clamp_unit(value: f64) -> f64 {
+    if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(0.0, 1.0)
+    }
+}
+
+/// Wire protocol version, exchanged in `hello` / `helloAck`
+///
+/// Independent of the crate version (`crate::VERSION`). It is bumped only
+/// for breaking changes; new optional fields and message types don't
+/// change it, so peers with the same version can always talk.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Whether a peer speaking `version` of the protocol can talk to this one
+pub fn protocol_compatible(version: u32) -> bool {
+    version == PROTOCOL_VERSION
 }
 
 // ============ Protocol Messages ============
@@ -153,6 +535,22 @@ pub struct SidecarStats {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum EmulatorToSidecarMessage {
+    /// First message on a connection to a server with an auth token
+    #[serde(rename = "auth")]
+    Auth { token: String },
+
+    /// Announce the client's protocol version, before anything else but
+    /// `auth`
+    #[serde(rename = "hello", rename_all = "camelCase")]
+    Hello {
+        protocol_version: u32,
+        client_version: String,
+        /// Ask for frames in both directions as single binary messages,
+        /// see `frame::encode_frame_binary`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binary_frames: Option<bool>,
+    },
+
     #[serde(rename = "setMode")]
     SetMode {
         mode: SidecarMode,
@@ -167,17 +565,169 @@ pub enum EmulatorToSidecarMessage {
         height: u32,
     },
 
+    /// Change the frame dimensions, keeping the format, e.g. when the
+    /// guest switches resolution
+    #[serde(rename = "resize")]
+    Resize { width: u32, height: u32 },
+
     #[serde(rename = "frame")]
     Frame { metadata: FrameMetadata },
 
+    #[serde(rename = "frameChunk")]
+    FrameChunk(FrameChunk),
+
+    /// Update part of the latest frame (binary region data follows)
+    #[serde(rename = "frameRegion")]
+    FrameRegion(FrameRegion),
+
     #[serde(rename = "ping")]
     Ping { timestamp: f64 },
+
+    /// Change the target frame rate without a full `setMode`
+    #[serde(rename = "setTargetFps")]
+    SetTargetFps { fps: u32 },
+
+    #[serde(rename = "getServerInfo")]
+    GetServerInfo,
+
+    /// Skip broadcast frames until the next keyframe, e.g. after a decode
+    /// error left the client without a reference frame
+    #[serde(rename = "requestKeyframe")]
+    RequestKeyframe,
+
+    /// Reply to a `requestFormat` the client chose not to apply
+    #[serde(rename = "formatAck")]
+    FormatAck { format: FrameFormat, success: bool },
+
+    /// Start receiving broadcasts on another stream
+    #[serde(rename = "subscribe", rename_all = "camelCase")]
+    Subscribe { stream_id: u32 },
+
+    /// Stop receiving broadcasts on a stream, including the default one
+    #[serde(rename = "unsubscribe", rename_all = "camelCase")]
+    Unsubscribe { stream_id: u32 },
+
+    /// Any `type` this build doesn't know, e.g. from a newer client
+    ///
+    /// Parsed so receivers can ignore it rather than fail; never sent.
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+/// Code of an `error` message
+///
+/// Sent as a lowercase snake_case string. Codes this build doesn't know
+/// deserialize to `Other` instead of failing, so newer peers can add codes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The server is at `max_clients`
+    MaxClients,
+    /// Missing or wrong `auth` token
+    Unauthorized,
+    /// The client's protocol version can't talk to the server's
+    IncompatibleVersion,
+    /// A frame doesn't match the negotiated format
+    FormatMismatch,
+    /// A frame couldn't be decoded or reassembled
+    BadFrame,
+    /// The requested frame format isn't supported
+    UnsupportedFormat,
+    /// The client is sending faster than the server allows
+    RateLimited,
+    ConnectionFailed,
+    NotConnected,
+    SendFailed,
+    ReceiveFailed,
+    /// A message broke the protocol, e.g. malformed JSON
+    ProtocolError,
+    Timeout,
+    /// Something went wrong on the sender's side
+    Internal,
+    Other(String),
+}
+
+impl ErrorCode {
+    /// The code as sent on the wire
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorCode::MaxClients => "max_clients",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::IncompatibleVersion => "incompatible_version",
+            ErrorCode::FormatMismatch => "format_mismatch",
+            ErrorCode::BadFrame => "bad_frame",
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::ConnectionFailed => "connection_failed",
+            ErrorCode::NotConnected => "not_connected",
+            ErrorCode::SendFailed => "send_failed",
+            ErrorCode::ReceiveFailed => "receive_failed",
+            ErrorCode::ProtocolError => "protocol_error",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<&str> for ErrorCode {
+    fn from(code: &str) -> Self {
+        match code {
+            "max_clients" => ErrorCode::MaxClients,
+            "unauthorized" => ErrorCode::Unauthorized,
+            "incompatible_version" => ErrorCode::IncompatibleVersion,
+            // Older servers sent this one in camelCase
+            "format_mismatch" | "formatMismatch" => ErrorCode::FormatMismatch,
+            "bad_frame" => ErrorCode::BadFrame,
+            "unsupported_format" => ErrorCode::UnsupportedFormat,
+            "rate_limited" => ErrorCode::RateLimited,
+            "connection_failed" => ErrorCode::ConnectionFailed,
+            "not_connected" => ErrorCode::NotConnected,
+            "send_failed" => ErrorCode::SendFailed,
+            "receive_failed" => ErrorCode::ReceiveFailed,
+            "protocol_error" => ErrorCode::ProtocolError,
+            "timeout" => ErrorCode::Timeout,
+            "internal" => ErrorCode::Internal,
+            other => ErrorCode::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        Ok(ErrorCode::from(code.as_str()))
+    }
 }
 
 /// Messages from Sidecar to Emulator
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum SidecarToEmulatorMessage {
+    /// Reply to `hello`; an incompatible client is then sent an `error`
+    /// and disconnected
+    #[serde(rename = "helloAck", rename_all = "camelCase")]
+    HelloAck {
+        protocol_version: u32,
+        server_version: String,
+        compatible: bool,
+        /// Whether frames now go as single binary messages, only present
+        /// when the `hello` asked for them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binary_frames: Option<bool>,
+    },
+
     #[serde(rename = "modeAck")]
     ModeAck {
         mode: SidecarMode,
@@ -189,17 +739,125 @@ pub enum SidecarToEmulatorMessage {
     #[serde(rename = "formatAck")]
     FormatAck { format: FrameFormat, success: bool },
 
+    #[serde(rename = "resizeAck")]
+    ResizeAck { width: u32, height: u32, success: bool },
+
+    /// One of the client's own frames was received
     #[serde(rename = "frameAck")]
-    FrameAck { sequence: u64, latency: f64 },
+    FrameAck {
+        sequence: u64,
+        /// How long the frame took to arrive, in ms
+        latency: f64,
+    },
+
+    /// Metadata of a broadcast frame; its payload is the next binary message
+    #[serde(rename = "frameHeader")]
+    FrameHeader {
+        sequence: u64,
+        /// How long the frame took to reach the send queue, in ms since its
+        /// `timestamp`
+        latency: f64,
+        /// Source generation, only present for frames that carry one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        generation: Option<u32>,
+        /// Whether the frame is a keyframe, sent alongside `generation`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyframe: Option<bool>,
+        /// Stream of a broadcast frame, only present off the default stream
+        #[serde(default, skip_serializing_if = "Option::is_none", rename = "streamId")]
+        stream_id: Option<u32>,
+    },
+
+    #[serde(rename = "targetFpsAck")]
+    TargetFpsAck {
+        fps: u32,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
 
     #[serde(rename = "pong")]
     Pong { timestamp: f64, server_time: f64 },
 
     #[serde(rename = "error")]
-    Error { code: String, message: String },
+    Error { code: ErrorCode, message: String },
+
+    #[serde(rename = "serverInfo")]
+    ServerInfo(ServerInfo),
+
+    /// Advisory request for the client to switch frame format
+    ///
+    /// The client either sends `setFormat` to accept or replies with a
+    /// `formatAck` with `success: false` to decline.
+    #[serde(rename = "requestFormat")]
+    RequestFormat { format: FrameFormat, reason: String },
+
+    /// The client has too many unacknowledged frames in flight
+    ///
+    /// The frame that triggered it was dropped. The client should wait for
+    /// `frameAck`s before sending more.
+    #[serde(rename = "frameThrottle")]
+    FrameThrottle { outstanding: u32 },
+
+    /// Ask the client to send a keyframe next, e.g. after one of its frames
+    /// arrived corrupted
+    #[serde(rename = "requestKeyframe")]
+    RequestKeyframe,
+}
+
+/// `type` tags of [`EmulatorToSidecarMessage`] variants
+const EMULATOR_MESSAGE_TYPES: &[&str] = &[
+    "auth",
+    "hello",
+    "setMode",
+    "setFormat",
+    "resize",
+    "frame",
+    "frameChunk",
+    "frameRegion",
+    "ping",
+    "setTargetFps",
+    "getServerInfo",
+    "requestKeyframe",
+    "formatAck",
+    "subscribe",
+    "unsubscribe",
+];
+
+/// `type` tags of [`SidecarToEmulatorMessage`] variants
+const SIDECAR_MESSAGE_TYPES: &[&str] = &[
+    "helloAck",
+    "modeAck",
+    "formatAck",
+    "resizeAck",
+    "frameAck",
+    "frameHeader",
+    "targetFpsAck",
+    "pong",
+    "error",
+    "serverInfo",
+    "requestFormat",
+    "frameThrottle",
+    "requestKeyframe",
+];
+
+/// Errors parsing a protocol message
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Invalid message: {0}")]
+    InvalidJson(String),
+
+    #[error("Message has no type")]
+    MissingType,
+
+    #[error("Unknown message type: {0}")]
+    UnknownType(String),
 }
 
 /// Combined message type for WebSocket handling
+///
+/// Deserializing tries each direction in turn; prefer [`Message::parse`],
+/// which picks the direction from the `type` tag.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Message {
@@ -207,10 +865,115 @@ pub enum Message {
     FromSidecar(SidecarToEmulatorMessage),
 }
 
+impl Message {
+    /// Parse a message of either direction, dispatching on its `type`
+    ///
+    /// `formatAck` and `requestKeyframe` are sent both ways with the same
+    /// shape; they parse as `FromEmulator`.
+    pub fn parse(text: &str) -> Result<Message, ProtocolError> {
+        let value: serde_json::Value =
+            serde_json::from_str(text).map_err(|e| ProtocolError::InvalidJson(e.to_string()))?;
+        let kind = value
+            .get("type")
+            .and_then(serde_json::Value::as_str)
+            .ok_or(ProtocolError::MissingType)?;
+
+        let invalid = |e: serde_json::Error| ProtocolError::InvalidJson(e.to_string());
+        if EMULATOR_MESSAGE_TYPES.contains(&kind) {
+            serde_json::from_value(value).map(Message::FromEmulator).map_err(invalid)
+        } else if SIDECAR_MESSAGE_TYPES.contains(&kind) {
+            serde_json::from_value(value).map(Message::FromSidecar).map_err(invalid)
+        } else {
+            Err(ProtocolError::UnknownType(kind.to_string()))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_message_parse_dispatches_on_type() {
+        let pong = serde_json::to_string(&SidecarToEmulatorMessage::Pong {
+            timestamp: 1.0,
+            server_time: 2.0,
+        })
+        .unwrap();
+        assert!(matches!(
+            Message::parse(&pong).unwrap(),
+            Message::FromSidecar(SidecarToEmulatorMessage::Pong { timestamp, .. }) if timestamp == 1.0
+        ));
+
+        let ping = serde_json::to_string(&EmulatorToSidecarMessage::Ping { timestamp: 3.0 }).unwrap();
+        assert!(matches!(
+            Message::parse(&ping).unwrap(),
+            Message::FromEmulator(EmulatorToSidecarMessage::Ping { timestamp }) if timestamp == 3.0
+        ));
+
+        assert!(matches!(
+            Message::parse(r#"{"type":"requestKeyframe"}"#).unwrap(),
+            Message::FromEmulator(EmulatorToSidecarMessage::RequestKeyframe)
+        ));
+
+        // A known type with the wrong fields isn't tried against the other direction
+        assert!(matches!(Message::parse(r#"{"type":"pong","timestamp":1}"#), Err(ProtocolError::InvalidJson(_))));
+        assert!(matches!(Message::parse(r#"{"timestamp":1}"#), Err(ProtocolError::MissingType)));
+        assert!(matches!(
+            Message::parse(r#"{"type":"warp"}"#),
+            Err(ProtocolError::UnknownType(kind)) if kind == "warp"
+        ));
+        assert!(matches!(Message::parse("not json"), Err(ProtocolError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn test_error_code_serialization() {
+        let msg = SidecarToEmulatorMessage::Error {
+            code: ErrorCode::MaxClients,
+            message: "full".to_string(),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""code":"max_clients""#));
+
+        let parse = |code: &str| match serde_json::from_str(&format!(
+            r#"{{"type":"error","code":"{}","message":""}}"#,
+            code
+        ))
+        .unwrap()
+        {
+            SidecarToEmulatorMessage::Error { code, .. } => code,
+            other => panic!("Unexpected message: {:?}", other),
+        };
+        assert_eq!(parse("unauthorized"), ErrorCode::Unauthorized);
+        assert_eq!(parse("formatMismatch"), ErrorCode::FormatMismatch);
+        assert_eq!(parse("disk_full"), ErrorCode::Other("disk_full".to_string()));
+        assert_eq!(ErrorCode::Other("disk_full".to_string()).to_string(), "disk_full");
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let json = r#"{"type":"hello","protocolVersion":1,"clientVersion":"0.1.0"}"#;
+        match serde_json::from_str(json).unwrap() {
+            EmulatorToSidecarMessage::Hello { protocol_version, client_version, binary_frames } => {
+                assert!(protocol_compatible(protocol_version));
+                assert_eq!(client_version, "0.1.0");
+                assert_eq!(binary_frames, None);
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        let ack = SidecarToEmulatorMessage::HelloAck {
+            protocol_version: PROTOCOL_VERSION,
+            server_version: "0.1.0".to_string(),
+            compatible: false,
+            binary_frames: None,
+        };
+        let json = serde_json::to_string(&ack).unwrap();
+        assert!(json.contains("\"type\":\"helloAck\""));
+        assert!(json.contains("\"serverVersion\":\"0.1.0\""));
+        assert!(!protocol_compatible(PROTOCOL_VERSION + 1));
+    }
+
     #[test]
     fn test_serialize_ping() {
         let msg = EmulatorToSidecarMessage::Ping { timestamp: 1234.5 };
@@ -219,6 +982,19 @@ mod tests {
         assert!(json.contains("\"timestamp\":1234.5"));
     }
 
+    #[test]
+    fn test_unknown_message_type() {
+        let msg: EmulatorToSidecarMessage =
+            serde_json::from_str(r#"{"type":"setColorSpace","space":"p3"}"#).unwrap();
+        assert!(matches!(msg, EmulatorToSidecarMessage::Unknown));
+        assert!(serde_json::to_string(&msg).is_err());
+
+        // Known types ignore fields they don't know
+        let msg: EmulatorToSidecarMessage =
+            serde_json::from_str(r#"{"type":"resize","width":800,"height":600,"dpr":2}"#).unwrap();
+        assert!(matches!(msg, EmulatorToSidecarMessage::Resize { width: 800, height: 600 }));
+    }
+
     #[test]
     fn test_deserialize_set_mode() {
         let json = r#"{"type":"setMode","mode":"local"}"#;
@@ -231,10 +1007,177 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_request_format_serialization() {
+        let msg = SidecarToEmulatorMessage::RequestFormat {
+            format: FrameFormat::Rgb565,
+            reason: "congestion".to_string(),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"requestFormat","format":"rgb565","reason":"congestion"}"#
+        );
+
+        let json = r#"{"type":"formatAck","format":"rgb565","success":false}"#;
+        let msg: EmulatorToSidecarMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            msg,
+            EmulatorToSidecarMessage::FormatAck {
+                format: FrameFormat::Rgb565,
+                success: false
+            }
+        ));
+    }
+
+    #[test]
+    fn test_frame_chunk_roundtrip() {
+        let msg = EmulatorToSidecarMessage::FrameChunk(FrameChunk {
+            sequence: 3,
+            chunk_index: 1,
+            chunk_count: 4,
+            crc: Some(0xDEAD_BEEF),
+        });
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"frameChunk\""));
+        assert!(json.contains("\"chunkIndex\":1"));
+
+        match serde_json::from_str(&json).unwrap() {
+            EmulatorToSidecarMessage::FrameChunk(chunk) => {
+                assert_eq!(chunk.chunk_count, 4);
+                assert_eq!(chunk.crc, Some(0xDEAD_BEEF));
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        // Chunks from senders without CRCs are still accepted
+        let json = r#"{"type":"frameChunk","sequence":3,"chunkIndex":0,"chunkCount":1}"#;
+        match serde_json::from_str(json).unwrap() {
+            EmulatorToSidecarMessage::FrameChunk(chunk) => assert_eq!(chunk.crc, None),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_frame_header_roundtrip() {
+        let metadata = FrameMetadata {
+            sequence: 0x0102_0304_0506_0708,
+            timestamp: 1234.5,
+            width: 640,
+            height: 480,
+            format: FrameFormat::Rgb565,
+            keyframe: true,
+            generation: None,
+            checksum: None,
+            stream_id: 0,
+        };
+        let header = metadata.to_header_bytes();
+        assert_eq!(header.len(), FRAME_HEADER_SIZE);
+        assert_eq!(&header[0..8], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(&header[24..], &[1, HEADER_FLAG_KEYFRAME]);
+
+        let mut message = header.to_vec();
+        message.extend_from_slice(&[0xAA; 4]);
+        let parsed = FrameMetadata::from_header_bytes(&message).unwrap();
+        assert_eq!(parsed.sequence, metadata.sequence);
+        assert_eq!(parsed.timestamp, metadata.timestamp);
+        assert_eq!((parsed.width, parsed.height), (640, 480));
+        assert_eq!(parsed.format, FrameFormat::Rgb565);
+        assert!(parsed.keyframe);
+
+        assert!(FrameMetadata::from_header_bytes(&header[..25]).is_none());
+        let mut bad_format = header;
+        bad_format[24] = 0xFF;
+        assert!(FrameMetadata::from_header_bytes(&bad_format).is_none());
+    }
+
+    #[test]
+    fn test_server_info_roundtrip() {
+        let msg: EmulatorToSidecarMessage = serde_json::from_str(r#"{"type":"getServerInfo"}"#).unwrap();
+        assert!(matches!(msg, EmulatorToSidecarMessage::GetServerInfo));
+
+        let msg = SidecarToEmulatorMessage::ServerInfo(ServerInfo {
+            version: "0.1.0".to_string(),
+            uptime_secs: 5,
+            client_count: 1,
+            max_clients: 10,
+            supported_formats: vec![FrameFormat::Rgba, FrameFormat::Rgb565],
+            rate_limits: RateLimitStats::default(),
+        });
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"serverInfo\""));
+        assert!(json.contains("\"uptimeSecs\":5"));
+        assert!(json.contains("\"supportedFormats\":[\"rgba\",\"rgb565\"]"));
+    }
+
+    fn stats(received: u64, dropped: u64, latency: f64, fps: f64, bps: f64) -> SidecarStats {
+        SidecarStats {
+            frames_received: received,
+            frames_dropped: dropped,
+            avg_latency: latency,
+            current_fps: fps,
+            bytes_per_second: bps,
+            ..SidecarStats::default()
+        }
+    }
+
+    #[test]
+    fn test_quality_score() {
+        const MIB: f64 = 1024.0 * 1024.0;
+
+        assert_eq!(SidecarStats::default().quality_score(), 0);
+        assert_eq!(stats(1000, 0, 10.0, 60.0, 4.0 * MIB).quality_score(), 100);
+        // 5% drops, 100ms, 24fps, 512KiB/s
+        assert_eq!(stats(950, 50, 100.0, 24.0, 0.5 * MIB).quality_score(), 76);
+        // 20% drops, 260ms, 15fps, 256KiB/s
+        assert_eq!(stats(800, 200, 260.0, 15.0, 0.25 * MIB).quality_score(), 30);
+        // Everything at or past its worst point
+        assert_eq!(stats(10, 90, 2000.0, 0.0, 0.0).quality_score(), 0);
+
+        // Worsening any single factor lowers the score
+        let good = stats(1000, 0, 10.0, 60.0, 4.0 * MIB).quality_score();
+        assert!(stats(900, 100, 10.0, 60.0, 4.0 * MIB).quality_score() < good);
+        assert!(stats(1000, 0, 300.0, 60.0, 4.0 * MIB).quality_score() < good);
+        assert!(stats(1000, 0, 10.0, 10.0, 4.0 * MIB).quality_score() < good);
+        assert!(stats(1000, 0, 10.0, 60.0, 0.1 * MIB).quality_score() < good);
+    }
+
+    #[test]
+    fn test_generation_is_opt_in() {
+        let header = SidecarToEmulatorMessage::FrameHeader {
+            sequence: 1,
+            latency: 0.0,
+            generation: None,
+            keyframe: None,
+            stream_id: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&header).unwrap(),
+            r#"{"type":"frameHeader","sequence":1,"latency":0.0}"#
+        );
+
+        let metadata: FrameMetadata = serde_json::from_str(
+            r#"{"sequence":1,"timestamp":0,"width":1,"height":1,"format":"rgba","keyframe":false,"generation":7}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.generation, Some(7));
+        assert_eq!(metadata.stream_id, DEFAULT_STREAM);
+        assert!(!serde_json::to_string(&metadata).unwrap().contains("streamId"));
+
+        let metadata: FrameMetadata = serde_json::from_str(
+            r#"{"sequence":1,"timestamp":0,"width":1,"height":1,"format":"rgba","keyframe":false,"streamId":2}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.stream_id, 2);
+    }
+
     #[test]
     fn test_frame_format_bytes() {
         assert_eq!(FrameFormat::Rgba.bytes_per_pixel(), Some(4));
         assert_eq!(FrameFormat::Rgb565.bytes_per_pixel(), Some(2));
         assert_eq!(FrameFormat::Compressed.bytes_per_pixel(), None);
+        assert_eq!(FrameFormat::Indexed8.bytes_per_pixel(), Some(1));
+        assert_eq!(FrameFormat::Indexed8.header_size(), 1024);
+        assert_eq!(serde_json::to_string(&FrameFormat::Indexed8).unwrap(), "\"indexed8\"");
     }
 }
@@ -0,0 +1,299 @@
+//! Frame Recording
+//!
+//! Records the frames clients send to a `.qwr` file and plays them back
+//! through [`SidecarServer::broadcast_frame`] at their original timing, for
+//! reproducing rendering glitches. Set `ServerConfig::record_path` to record
+//! every frame the server reconstructs.
+//!
+//! A recording is the magic `QWR` and a version byte, then one record per
+//! frame. Each record is a little-endian `u32` length of the rest of the
+//! record, followed by:
+//!
+//! | Field | Type |
+//! |-------|------|
+//! | client id | u64 |
+//! | ms since the recording started | f64 |
+//! | binary frame header | 26 bytes |
+//! | generation present, generation | u8, u32 |
+//! | stream id | u32 |
+//! | frame data | rest of the record |
+
+use crate::frame::{Frame, FrameError};
+use crate::protocol::{FrameMetadata, FRAME_HEADER_SIZE};
+use crate::server::{ClientId, SidecarServer};
+use crate::sink::FrameSink;
+use crate::transport::TransportError;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::warn;
+
+/// Bytes every recording starts with
+pub const RECORDING_MAGIC: &[u8; 3] = b"QWR";
+
+/// Version of the record layout
+pub const RECORDING_VERSION: u8 = 2;
+
+/// Bytes in a record before the frame data
+const RECORD_PREFIX_SIZE: usize = 8 + 8 + FRAME_HEADER_SIZE + 1 + 4 + 4;
+
+/// Recording errors
+#[derive(Debug, Error)]
+pub enum RecordingError {
+    #[error("Recording I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid recording: {0}")]
+    InvalidFile(String),
+
+    #[error("Invalid recorded frame: {0}")]
+    Frame(#[from] FrameError),
+
+    #[error("Replay failed: {0}")]
+    Broadcast(#[from] TransportError),
+}
+
+/// A frame read back from a recording
+#[derive(Debug, Clone)]
+pub struct RecordedFrame {
+    pub client: ClientId,
+    /// When the frame arrived, in ms after the recording started
+    pub elapsed_ms: f64,
+    pub frame: Frame,
+}
+
+/// Writes frames to a recording
+///
+/// Each frame is flushed as it is written, so a recording cut short by a
+/// crash still holds every frame before it. As a [`FrameSink`] it records
+/// the frames the server hands it, logging write failures.
+pub struct FrameRecorder<W: Write = BufWriter<File>> {
+    writer: Mutex<W>,
+    started: Instant,
+}
+
+impl FrameRecorder {
+    /// Create (or truncate) a recording file at `path`
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> FrameRecorder<W> {
+    /// Start a recording on `writer`, writing the file header
+    pub fn new(mut writer: W) -> Result<Self, RecordingError> {
+        writer.write_all(RECORDING_MAGIC)?;
+        writer.write_all(&[RECORDING_VERSION])?;
+        writer.flush()?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+            started: Instant::now(),
+        })
+    }
+
+    /// Record a frame from `client`, timed from when the recording started
+    pub fn record(&self, client: ClientId, frame: &Frame) -> Result<(), RecordingError> {
+        self.record_at(client, self.started.elapsed().as_secs_f64() * 1000.0, frame)
+    }
+
+    /// Record a frame from `client` that arrived `elapsed_ms` into the recording
+    pub fn record_at(&self, client: ClientId, elapsed_ms: f64, frame: &Frame) -> Result<(), RecordingError> {
+        let len = u32::try_from(RECORD_PREFIX_SIZE + frame.data.len())
+            .map_err(|_| RecordingError::InvalidFile("frame too large to record".to_string()))?;
+        let metadata = &frame.metadata;
+
+        let mut prefix = Vec::with_capacity(4 + RECORD_PREFIX_SIZE);
+        prefix.extend_from_slice(&len.to_le_bytes());
+        prefix.extend_from_slice(&client.0.to_le_bytes());
+        prefix.extend_from_slice(&elapsed_ms.to_le_bytes());
+        prefix.extend_from_slice(&metadata.to_header_bytes());
+        prefix.push(metadata.generation.is_some() as u8);
+        prefix.extend_from_slice(&metadata.generation.unwrap_or(0).to_le_bytes());
+        prefix.extend_from_slice(&metadata.stream_id.to_le_bytes());
+
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(&prefix)?;
+        writer.write_all(&frame.data)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Finish the recording and return the writer
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Send> FrameSink for FrameRecorder<W> {
+    fn on_frame(&self, client: ClientId, frame: Frame) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        if let Err(e) = self.record(client, &frame) {
+            warn!("Failed to record frame {} from client {}: {}", frame.metadata.sequence, client.0, e);
+        }
+        Box::pin(async {})
+    }
+}
+
+/// Reads frames back from a recording
+///
+/// Iterating yields each [`RecordedFrame`] in the order it was recorded;
+/// [`FrameReplayer::replay`] broadcasts them at their original timing.
+pub struct FrameReplayer<R: Read = BufReader<File>> {
+    reader: R,
+}
+
+impl FrameReplayer {
+    /// Open the recording file at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> FrameReplayer<R> {
+    /// Read a recording from `reader`, checking its file header
+    pub fn new(mut reader: R) -> Result<Self, RecordingError> {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => RecordingError::InvalidFile("missing file header".to_string()),
+            _ => RecordingError::Io(e),
+        })?;
+        if &header[..3] != RECORDING_MAGIC {
+            return Err(RecordingError::InvalidFile("not a frame recording".to_string()));
+        }
+        if header[3] != RECORDING_VERSION {
+            return Err(RecordingError::InvalidFile(format!("unsupported version {}", header[3])));
+        }
+        Ok(Self { reader })
+    }
+
+    /// Read the next frame, or `None` at the end of the recording
+    pub fn next_frame(&mut self) -> Result<Option<RecordedFrame>, RecordingError> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len < RECORD_PREFIX_SIZE {
+            return Err(RecordingError::InvalidFile(format!("record of {} bytes is too short", len)));
+        }
+
+        // Read what's there rather than trusting the length up front
+        let mut record = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut record)?;
+        if record.len() < len {
+            return Err(RecordingError::InvalidFile("truncated record".to_string()));
+        }
+
+        let client = ClientId(u64::from_le_bytes(record[0..8].try_into().unwrap()));
+        let elapsed_ms = f64::from_le_bytes(record[8..16].try_into().unwrap());
+        let header = &record[16..16 + FRAME_HEADER_SIZE];
+        let mut metadata = FrameMetadata::from_header_bytes(header)
+            .ok_or_else(|| RecordingError::InvalidFile("unknown frame format".to_string()))?;
+        let generation = &record[16 + FRAME_HEADER_SIZE..RECORD_PREFIX_SIZE - 4];
+        if generation[0] != 0 {
+            metadata.generation = Some(u32::from_le_bytes(generation[1..5].try_into().unwrap()));
+        }
+        metadata.stream_id = u32::from_le_bytes(record[RECORD_PREFIX_SIZE - 4..RECORD_PREFIX_SIZE].try_into().unwrap());
+
+        record.drain(..RECORD_PREFIX_SIZE);
+        let frame = Frame::new(metadata, record)?;
+        Ok(Some(RecordedFrame {
+            client,
+            elapsed_ms,
+            frame,
+        }))
+    }
+
+    /// Broadcast every remaining frame through `server`, spaced as recorded
+    ///
+    /// Returns how many frames were broadcast.
+    pub async fn replay(mut self, server: &SidecarServer) -> Result<u64, RecordingError> {
+        let start = tokio::time::Instant::now();
+        let mut first_ms = None;
+        let mut replayed = 0;
+        while let Some(recorded) = self.next_frame()? {
+            let offset_ms = recorded.elapsed_ms - *first_ms.get_or_insert(recorded.elapsed_ms);
+            let offset = Duration::from_secs_f64(offset_ms.max(0.0) / 1000.0);
+            tokio::time::sleep_until(start + offset).await;
+            server.broadcast_frame(recorded.frame.metadata.stream_id, recorded.frame).await?;
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+}
+
+impl<R: Read> Iterator for FrameReplayer<R> {
+    type Item = Result<RecordedFrame, RecordingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FrameFormat;
+
+    fn test_frame(sequence: u64, generation: Option<u32>) -> Frame {
+        let metadata = FrameMetadata {
+            sequence,
+            timestamp: sequence as f64 * 16.0,
+            width: 2,
+            height: 1,
+            format: FrameFormat::Rgba,
+            keyframe: sequence == 0,
+            generation,
+            checksum: None,
+            stream_id: sequence as u32,
+        };
+        Frame::new(metadata, vec![sequence as u8; 8]).unwrap()
+    }
+
+    #[test]
+    fn test_record_and_read_back() {
+        let recorder = FrameRecorder::new(Vec::new()).unwrap();
+        recorder.record_at(ClientId(3), 0.0, &test_frame(0, None)).unwrap();
+        recorder.record_at(ClientId(4), 20.5, &test_frame(1, Some(7))).unwrap();
+        let bytes = recorder.into_inner();
+        assert_eq!(&bytes[..4], b"QWR\x02");
+
+        let frames: Vec<RecordedFrame> = FrameReplayer::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].client.0, frames[0].elapsed_ms), (3, 0.0));
+        assert_eq!(frames[0].frame.metadata.timestamp, 0.0);
+        assert!(frames[0].frame.metadata.keyframe);
+        assert_eq!(frames[0].frame.metadata.generation, None);
+        assert_eq!((frames[1].client.0, frames[1].elapsed_ms), (4, 20.5));
+        assert_eq!(frames[1].frame.metadata.generation, Some(7));
+        assert_eq!(frames[1].frame.metadata.stream_id, 1);
+        assert_eq!(frames[1].frame.data, vec![1u8; 8]);
+    }
+
+    #[test]
+    fn test_invalid_recordings() {
+        let invalid = |bytes: &[u8]| matches!(FrameReplayer::new(bytes), Err(RecordingError::InvalidFile(_)));
+        assert!(invalid(b""));
+        assert!(invalid(b"RIFF"));
+        assert!(invalid(b"QWR\x09"));
+
+        let recorder = FrameRecorder::new(Vec::new()).unwrap();
+        recorder.record_at(ClientId(1), 0.0, &test_frame(0, None)).unwrap();
+        let bytes = recorder.into_inner();
+        let mut replayer = FrameReplayer::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(replayer.next_frame(), Err(RecordingError::InvalidFile(_))));
+
+        // A clean end between records is just the end
+        let mut replayer = FrameReplayer::new(&bytes[..4]).unwrap();
+        assert!(replayer.next_frame().unwrap().is_none());
+    }
+}
@@ -0,0 +1,141 @@
+//! Upstream Relay
+//!
+//! In remote mode the server forwards a client's frames to another sidecar.
+//! It dials the client's `remote_url` and, acting as an emulator itself,
+//! sends every frame it reconstructs as a `frame` message followed by the
+//! payload, announcing each new format with `setFormat` first.
+
+use crate::frame::Frame;
+use crate::protocol::{EmulatorToSidecarMessage, FrameFormat, FrameMetadata};
+use crate::transport::TransportError;
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+
+/// How long dialing the upstream may take
+pub const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frames waiting to be relayed; more are dropped until there is room
+const UPSTREAM_QUEUE: usize = 16;
+
+/// Outbound connection relaying one client's frames
+///
+/// Dropping it closes the connection once queued frames have been sent.
+#[derive(Debug)]
+pub struct Upstream {
+    url: String,
+    tx: mpsc::Sender<Frame>,
+}
+
+impl Upstream {
+    /// Dial `url`
+    pub async fn connect(url: &str) -> Result<Self, TransportError> {
+        let (ws, _) = tokio::time::timeout(UPSTREAM_CONNECT_TIMEOUT, tokio_tungstenite::connect_async(url))
+            .await
+            .map_err(|_| TransportError::ConnectionFailed(format!("timed out connecting to {}", url)))?
+            .map_err(|e| TransportError::ConnectionFailed(format!("{}: {}", url, e)))?;
+        info!("Connected upstream to {}", url);
+
+        let (tx, rx) = mpsc::channel(UPSTREAM_QUEUE);
+        tokio::spawn(relay_frames(ws, rx, url.to_string()));
+        Ok(Self {
+            url: url.to_string(),
+            tx,
+        })
+    }
+
+    /// Address the connection was dialed to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Queue a frame to send upstream
+    ///
+    /// Fails with `SendFailed` if the queue is full and `NotConnected` once
+    /// the upstream has closed the connection.
+    pub fn forward(&self, frame: Frame) -> Result<(), TransportError> {
+        self.tx.try_send(frame).map_err(|e| match e {
+            TrySendError::Full(_) => TransportError::SendFailed("upstream queue full".to_string()),
+            TrySendError::Closed(_) => TransportError::NotConnected,
+        })
+    }
+}
+
+/// Send queued frames until the `Upstream` is dropped or the connection fails
+async fn relay_frames(
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    mut rx: mpsc::Receiver<Frame>,
+    url: String,
+) {
+    let (mut sink, mut stream) = ws.split();
+    let mut format: Option<(FrameFormat, u32, u32)> = None;
+
+    loop {
+        tokio::select! {
+            frame = rx.recv() => {
+                let Some(frame) = frame else {
+                    let _ = sink.close().await;
+                    info!("Closed upstream connection to {}", url);
+                    break;
+                };
+                if let Err(e) = send_frame(&mut sink, &mut format, frame).await {
+                    warn!("Relaying to {} failed: {}", url, e);
+                    break;
+                }
+            }
+            msg = stream.next() => match msg {
+                // Acks and pongs from the upstream aren't passed back
+                Some(Ok(msg)) => debug!("Upstream {} sent {:?}", url, msg),
+                Some(Err(e)) => {
+                    warn!("Upstream connection to {} failed: {}", url, e);
+                    break;
+                }
+                None => {
+                    info!("Upstream {} closed the connection", url);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) async fn send_frame<W>(
+    sink: &mut W,
+    format: &mut Option<(FrameFormat, u32, u32)>,
+    frame: Frame,
+) -> Result<(), W::Error>
+where
+    W: futures_util::Sink<Message> + Unpin,
+{
+    let metadata = &frame.metadata;
+    let frame_format = (metadata.format, metadata.width, metadata.height);
+    if *format != Some(frame_format) {
+        let msg = EmulatorToSidecarMessage::SetFormat {
+            format: metadata.format,
+            width: metadata.width,
+            height: metadata.height,
+        };
+        sink.send(to_text(&msg)).await?;
+        *format = Some(frame_format);
+    }
+
+    // Checksummed here so the receiver can catch corruption in transit
+    let msg = EmulatorToSidecarMessage::Frame {
+        metadata: FrameMetadata {
+            checksum: Some(frame.compute_checksum()),
+            ..frame.metadata.clone()
+        },
+    };
+    sink.send(to_text(&msg)).await?;
+    sink.send(Message::Binary(frame.data.into())).await
+}
+
+fn to_text(msg: &EmulatorToSidecarMessage) -> Message {
+    // Serializing the protocol's own types can't fail
+    Message::Text(serde_json::to_string(msg).unwrap_or_default())
+}
@@ -0,0 +1,120 @@
+//! Frame Sinks
+//!
+//! Destinations for the frames the server reconstructs from clients. Set one
+//! with `ServerConfig::frame_sink` to route frames to a renderer, encoder or
+//! recorder without the server knowing which.
+
+use crate::frame::Frame;
+use crate::server::ClientId;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// Receives every frame the server reconstructs from a client
+///
+/// Called after the frame has been buffered, outside the server's state
+/// lock, so a slow sink delays only the client that sent the frame.
+pub trait FrameSink: Send + Sync {
+    fn on_frame(&self, client: ClientId, frame: Frame) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+impl std::fmt::Debug for dyn FrameSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FrameSink")
+    }
+}
+
+/// Forwards frames into a channel
+///
+/// Waits for room when the channel is full, applying backpressure to the
+/// sending client. Frames are discarded once the receiver is dropped.
+pub struct ChannelSink {
+    tx: mpsc::Sender<(ClientId, Frame)>,
+}
+
+impl ChannelSink {
+    /// Create a sink and the receiver its frames arrive on
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<(ClientId, Frame)>) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        (Self { tx }, rx)
+    }
+}
+
+impl FrameSink for ChannelSink {
+    fn on_frame(&self, client: ClientId, frame: Frame) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let _ = self.tx.send((client, frame)).await;
+        })
+    }
+}
+
+/// Keeps every frame in memory, for tests and capture tools
+#[derive(Default)]
+pub struct RecordingSink {
+    frames: Mutex<Vec<(ClientId, Frame)>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of frames recorded so far
+    pub fn len(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove and return the recorded frames, oldest first
+    pub fn take(&self) -> Vec<(ClientId, Frame)> {
+        std::mem::take(&mut *self.frames.lock().unwrap())
+    }
+}
+
+impl FrameSink for RecordingSink {
+    fn on_frame(&self, client: ClientId, frame: Frame) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        self.frames.lock().unwrap().push((client, frame));
+        Box::pin(async {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{FrameFormat, FrameMetadata};
+
+    fn test_frame(sequence: u64) -> Frame {
+        let metadata = FrameMetadata {
+            sequence,
+            timestamp: 0.0,
+            width: 1,
+            height: 1,
+            format: FrameFormat::Rgba,
+            keyframe: true,
+            generation: None,
+            checksum: None,
+            stream_id: 0,
+        };
+        Frame::new(metadata, vec![0u8; 4]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_builtin_sinks() {
+        let (channel, mut rx) = ChannelSink::new(4);
+        channel.on_frame(ClientId(1), test_frame(7)).await;
+        let (client, frame) = rx.recv().await.unwrap();
+        assert_eq!((client.0, frame.metadata.sequence), (1, 7));
+
+        let recording = RecordingSink::new();
+        for sequence in 0..3 {
+            recording.on_frame(ClientId(2), test_frame(sequence)).await;
+        }
+        let sequences: Vec<u64> = recording.take().iter().map(|(_, f)| f.metadata.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2]);
+        assert!(recording.is_empty());
+    }
+}
@@ -4,11 +4,15 @@
 
 use crate::frame::Frame;
 use crate::protocol::{
-    ConnectionState, EmulatorToSidecarMessage, FrameFormat, SidecarConfig,
+    ConnectionState, EmulatorToSidecarMessage, ErrorCode, FrameFormat, SidecarConfig,
     SidecarStats, SidecarToEmulatorMessage,
 };
+use futures_core::Stream;
+use std::collections::VecDeque;
 use std::future::Future;
 use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use thiserror::Error;
 
 /// Transport errors
@@ -33,6 +37,40 @@ pub enum TransportError {
     Timeout,
 }
 
+impl TransportError {
+    /// Error code reported to the peer in a protocol `Error` message
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TransportError::ConnectionFailed(_) => ErrorCode::ConnectionFailed,
+            TransportError::NotConnected => ErrorCode::NotConnected,
+            TransportError::SendFailed(_) => ErrorCode::SendFailed,
+            TransportError::ReceiveFailed(_) => ErrorCode::ReceiveFailed,
+            TransportError::ProtocolError(_) => ErrorCode::ProtocolError,
+            TransportError::Timeout => ErrorCode::Timeout,
+        }
+    }
+
+    /// Whether the connection cannot continue after this error
+    ///
+    /// Protocol errors (e.g. a malformed message) and timeouts only affect
+    /// the message that caused them; everything else means the underlying
+    /// connection is gone.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            TransportError::ProtocolError(_) | TransportError::Timeout
+        )
+    }
+
+    /// Convert into a protocol `Error` message for the peer
+    pub fn to_message(&self) -> SidecarToEmulatorMessage {
+        SidecarToEmulatorMessage::Error {
+            code: self.code(),
+            message: self.to_string(),
+        }
+    }
+}
+
 /// Callback type for frame events
 pub type FrameCallback = Box<dyn Fn(Frame) + Send + Sync>;
 
@@ -81,45 +119,150 @@ pub trait Transport: Send + Sync {
 
     /// Process incoming messages (call periodically)
     fn poll(&mut self) -> Option<EmulatorToSidecarMessage>;
+
+    /// Drain all pending incoming messages in one pass
+    ///
+    /// Messages are returned in the order they were received (FIFO), the
+    /// same order repeated calls to [`Transport::poll`] would yield them.
+    fn poll_all(&mut self) -> Vec<EmulatorToSidecarMessage> {
+        let mut messages = Vec::new();
+        while let Some(msg) = self.poll() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    /// Incoming messages as an async stream, for use in a select loop
+    ///
+    /// Built on [`Transport::poll`], checking again every
+    /// [`MESSAGE_POLL_INTERVAL`] while nothing is waiting. Ends once the
+    /// transport is disconnected (or failed) and every queued message has
+    /// been yielded. Needs a tokio runtime with the timer enabled.
+    fn message_stream(&mut self) -> Pin<Box<dyn Stream<Item = EmulatorToSidecarMessage> + Send + '_>> {
+        Box::pin(MessageStream::new(self))
+    }
+}
+
+/// How often [`MessageStream`] polls an idle transport
+pub const MESSAGE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Stream of a transport's incoming messages, see [`Transport::message_stream`]
+pub struct MessageStream<'a, T: Transport + ?Sized> {
+    transport: &'a mut T,
+    /// Wait before polling again, set while the transport is idle
+    idle: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<'a, T: Transport + ?Sized> MessageStream<'a, T> {
+    pub fn new(transport: &'a mut T) -> Self {
+        Self { transport, idle: None }
+    }
 }
 
+impl<T: Transport + ?Sized> Stream for MessageStream<'_, T> {
+    type Item = EmulatorToSidecarMessage;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(idle) = this.idle.as_mut() {
+                if idle.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.idle = None;
+            }
+            if let Some(msg) = this.transport.poll() {
+                return Poll::Ready(Some(msg));
+            }
+            if matches!(this.transport.state(), ConnectionState::Disconnected | ConnectionState::Error) {
+                return Poll::Ready(None);
+            }
+            this.idle = Some(Box::pin(tokio::time::sleep(MESSAGE_POLL_INTERVAL)));
+        }
+    }
+}
+
+/// Default outlier threshold for FPS, as a multiple of the median interval
+pub const DEFAULT_FPS_OUTLIER_FACTOR: f64 = 3.0;
+
 /// Calculate FPS from timestamps
+///
+/// Gaps longer than [`DEFAULT_FPS_OUTLIER_FACTOR`] times the median
+/// interval are ignored, see [`calculate_fps_with_outliers`].
 pub fn calculate_fps(timestamps: &[f64]) -> f64 {
-    if timestamps.len() < 2 {
+    calculate_fps_with_outliers(timestamps, DEFAULT_FPS_OUTLIER_FACTOR)
+}
+
+/// Calculate FPS from the intervals between timestamps, skipping stalls
+///
+/// Intervals longer than `outlier_factor` times the median are left out of
+/// the average, so one long pause (e.g. a backgrounded tab) doesn't drag
+/// the rate down. Pass `f64::INFINITY` to average every interval.
+pub fn calculate_fps_with_outliers(timestamps: &[f64], outlier_factor: f64) -> f64 {
+    fps_from_intervals(timestamps.windows(2).map(|pair| pair[1] - pair[0]).collect(), outlier_factor)
+}
+
+fn fps_from_intervals(mut intervals: Vec<f64>, outlier_factor: f64) -> f64 {
+    if intervals.is_empty() {
         return 0.0;
     }
 
-    let duration = timestamps[timestamps.len() - 1] - timestamps[0];
+    intervals.sort_by(f64::total_cmp);
+    let mid = intervals.len() / 2;
+    let median = if intervals.len().is_multiple_of(2) {
+        (intervals[mid - 1] + intervals[mid]) / 2.0
+    } else {
+        intervals[mid]
+    };
+    let limit = if median > 0.0 { median * outlier_factor } else { f64::INFINITY };
+
+    let kept: Vec<f64> = intervals.into_iter().filter(|&interval| interval <= limit).collect();
+    let duration: f64 = kept.iter().sum();
     if duration <= 0.0 {
         return 0.0;
     }
 
-    ((timestamps.len() - 1) as f64 * 1000.0) / duration
+    (kept.len() as f64 * 1000.0) / duration
 }
 
 /// FPS tracker
 pub struct FpsTracker {
-    timestamps: Vec<f64>,
+    timestamps: VecDeque<f64>,
     max_samples: usize,
+    outlier_factor: f64,
 }
 
 impl FpsTracker {
     pub fn new(max_samples: usize) -> Self {
         Self {
-            timestamps: Vec::with_capacity(max_samples),
+            timestamps: VecDeque::with_capacity(max_samples),
             max_samples,
+            outlier_factor: DEFAULT_FPS_OUTLIER_FACTOR,
         }
     }
 
+    /// Ignore intervals longer than `factor` times the median when
+    /// computing FPS; `f64::INFINITY` keeps them all
+    pub fn with_outlier_factor(mut self, factor: f64) -> Self {
+        self.outlier_factor = factor;
+        self
+    }
+
     pub fn record(&mut self, timestamp: f64) {
         if self.timestamps.len() >= self.max_samples {
-            self.timestamps.remove(0);
+            self.timestamps.pop_front();
         }
-        self.timestamps.push(timestamp);
+        self.timestamps.push_back(timestamp);
     }
 
     pub fn fps(&self) -> f64 {
-        calculate_fps(&self.timestamps)
+        let intervals = self
+            .timestamps
+            .iter()
+            .zip(self.timestamps.iter().skip(1))
+            .map(|(earlier, later)| later - earlier)
+            .collect();
+        fps_from_intervals(intervals, self.outlier_factor)
     }
 
     pub fn clear(&mut self) {
@@ -127,10 +270,596 @@ impl FpsTracker {
     }
 }
 
+/// Default number of latency samples a [`LatencyTracker`] keeps
+pub const DEFAULT_LATENCY_SAMPLES: usize = 100;
+
+/// Rolling window of latency measurements
+///
+/// Complements the smoothed `avg_latency` with the spread of recent
+/// samples, so tail spikes show up in the stats.
+pub struct LatencyTracker {
+    samples: VecDeque<f64>,
+    max_samples: usize,
+}
+
+impl LatencyTracker {
+    pub fn new(max_samples: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(max_samples),
+            max_samples: max_samples.max(1),
+        }
+    }
+
+    /// Record one latency measurement in ms
+    pub fn record(&mut self, latency: f64) {
+        if self.samples.len() >= self.max_samples {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// Lowest latency in the window, 0 when empty
+    pub fn min(&self) -> f64 {
+        self.samples.iter().copied().reduce(f64::min).unwrap_or(0.0)
+    }
+
+    /// Highest latency in the window, 0 when empty
+    pub fn max(&self) -> f64 {
+        self.samples.iter().copied().reduce(f64::max).unwrap_or(0.0)
+    }
+
+    /// Latency that `percentile` percent of the window is at or below
+    ///
+    /// Uses the nearest-rank method, so the result is always one of the
+    /// recorded samples. 0 when empty.
+    pub fn percentile(&self, percentile: f64) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let mut sorted: Vec<f64> = self.samples.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted[rank.saturating_sub(1)]
+    }
+
+    /// 95th percentile latency
+    pub fn p95(&self) -> f64 {
+        self.percentile(95.0)
+    }
+
+    /// Copy the window's min, max and p95 into `stats`
+    pub fn update_stats(&self, stats: &mut SidecarStats) {
+        stats.min_latency = self.min();
+        stats.max_latency = self.max();
+        stats.p95_latency = self.p95();
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl Default for LatencyTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_LATENCY_SAMPLES)
+    }
+}
+
+/// Default number of ping samples a [`ClockOffsetTracker`] keeps
+pub const DEFAULT_CLOCK_SAMPLES: usize = 8;
+
+/// Estimate of how far a peer's clock runs ahead of ours
+///
+/// Each ping gives a sample of the peer's send time minus our receive time:
+/// the true offset less the ping's one-way delay. The largest recent sample
+/// was delayed least, so it is taken as the offset.
+pub struct ClockOffsetTracker {
+    samples: VecDeque<f64>,
+    max_samples: usize,
+}
+
+impl ClockOffsetTracker {
+    pub fn new(max_samples: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(max_samples),
+            max_samples: max_samples.max(1),
+        }
+    }
+
+    /// Record a message the peer stamped `peer_ms` arriving at `local_ms`
+    pub fn record(&mut self, peer_ms: f64, local_ms: f64) {
+        if self.samples.len() >= self.max_samples {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(peer_ms - local_ms);
+    }
+
+    /// Estimated peer clock minus ours in ms, `None` before any sample
+    pub fn offset(&self) -> Option<f64> {
+        self.samples.iter().copied().reduce(f64::max)
+    }
+
+    /// Translate a time on the peer's clock to ours
+    ///
+    /// Returned unchanged until there is an estimate.
+    pub fn to_local(&self, peer_ms: f64) -> f64 {
+        peer_ms - self.offset().unwrap_or(0.0)
+    }
+}
+
+impl Default for ClockOffsetTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_CLOCK_SAMPLES)
+    }
+}
+
+/// Default bandwidth measurement window in ms
+pub const DEFAULT_BANDWIDTH_WINDOW_MS: f64 = 1000.0;
+
+/// Bandwidth tracker over a sliding time window
+///
+/// Only samples from the last `window_ms` count, so the rate reflects recent
+/// throughput and decays to zero once the link goes idle.
+pub struct BandwidthTracker {
+    samples: VecDeque<(f64, u64)>,
+    window_ms: f64,
+    window_bytes: u64,
+}
+
+impl BandwidthTracker {
+    pub fn new(window_ms: f64) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window_ms,
+            window_bytes: 0,
+        }
+    }
+
+    /// Record `bytes` transferred at `timestamp` (ms)
+    pub fn record(&mut self, timestamp: f64, bytes: u64) {
+        self.samples.push_back((timestamp, bytes));
+        self.window_bytes += bytes;
+        self.prune(timestamp);
+    }
+
+    /// Drop samples that fall outside the window ending at `now` (ms)
+    pub fn prune(&mut self, now: f64) {
+        let cutoff = now - self.window_ms;
+        while let Some(&(timestamp, bytes)) = self.samples.front() {
+            if timestamp > cutoff {
+                break;
+            }
+            self.samples.pop_front();
+            self.window_bytes -= bytes;
+        }
+    }
+
+    /// Bytes per second over the window, or 0 when the window is empty
+    pub fn bps(&self) -> f64 {
+        if self.samples.is_empty() || self.window_ms <= 0.0 {
+            return 0.0;
+        }
+        self.window_bytes as f64 * 1000.0 / self.window_ms
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.window_bytes = 0;
+    }
+}
+
+impl Default for BandwidthTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_BANDWIDTH_WINDOW_MS)
+    }
+}
+
+/// Token bucket rate limiter
+///
+/// Holds up to `burst` tokens and refills at `rate_per_sec`. Each allowed
+/// event takes one token, so a steady rate at or below `rate_per_sec` is
+/// never limited while bursts beyond `burst` are.
+pub struct TokenBucket {
+    tokens: f64,
+    burst: f64,
+    refill_per_ms: f64,
+    last_ms: Option<f64>,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(rate_per_sec: f64, burst: f64) -> Self {
+        let burst = burst.max(1.0);
+        Self {
+            tokens: burst,
+            burst,
+            refill_per_ms: rate_per_sec.max(0.0) / 1000.0,
+            last_ms: None,
+        }
+    }
+
+    /// Take a token at `now` (ms), returning `false` if none is available
+    pub fn try_take(&mut self, now: f64) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Tokens available at `now` (ms)
+    pub fn available(&mut self, now: f64) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    fn refill(&mut self, now: f64) {
+        if let Some(last) = self.last_ms {
+            let elapsed = (now - last).max(0.0);
+            self.tokens = (self.tokens + elapsed * self.refill_per_ms).min(self.burst);
+        }
+        self.last_ms = Some(now);
+    }
+}
+
+/// Default compression ratio measurement window in ms
+pub const DEFAULT_COMPRESSION_WINDOW_MS: f64 = 5000.0;
+
+/// Compression ratio tracker over a sliding time window
+///
+/// Sums the uncompressed and compressed sizes of recent frames, so large
+/// frames weigh more than small ones.
+pub struct CompressionTracker {
+    samples: VecDeque<(f64, u64, u64)>,
+    window_ms: f64,
+    raw_bytes: u64,
+    compressed_bytes: u64,
+}
+
+impl CompressionTracker {
+    pub fn new(window_ms: f64) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window_ms,
+            raw_bytes: 0,
+            compressed_bytes: 0,
+        }
+    }
+
+    /// Record a frame of `raw` bytes compressed to `compressed` bytes at
+    /// `timestamp` (ms)
+    pub fn record(&mut self, timestamp: f64, raw: u64, compressed: u64) {
+        self.samples.push_back((timestamp, raw, compressed));
+        self.raw_bytes += raw;
+        self.compressed_bytes += compressed;
+
+        let cutoff = timestamp - self.window_ms;
+        while let Some(&(timestamp, raw, compressed)) = self.samples.front() {
+            if timestamp > cutoff {
+                break;
+            }
+            self.samples.pop_front();
+            self.raw_bytes -= raw;
+            self.compressed_bytes -= compressed;
+        }
+    }
+
+    /// Uncompressed / compressed size over the window, or 1.0 when empty
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.compressed_bytes as f64
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.raw_bytes = 0;
+        self.compressed_bytes = 0;
+    }
+}
+
+impl Default for CompressionTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_COMPRESSION_WINDOW_MS)
+    }
+}
+
+/// Default minimum time between adaptive format changes, in ms
+pub const DEFAULT_ADAPTIVE_HOLD_MS: f64 = 2000.0;
+
+/// Fraction of the ceiling a richer format's projected rate must fit under
+/// before stepping back up to it
+const ADAPTIVE_RECOVERY_HEADROOM: f64 = 0.8;
+
+/// Bandwidth-driven format downgrade for one client's outgoing frames
+///
+/// While the measured send rate is over `ceiling_bps`, steps down from
+/// RGBA to RGB565 and, if allowed, on to `Compressed`. It steps back up once
+/// the rate the richer format would need, scaled from the current rate by
+/// bytes per pixel (and the measured compression ratio), fits under the
+/// ceiling with some headroom. Changes are at least `hold_ms` apart so the
+/// format doesn't flap while the window refills.
+pub struct AdaptiveQuality {
+    ceiling_bps: f64,
+    allow_compressed: bool,
+    hold_ms: f64,
+    bandwidth: BandwidthTracker,
+    compression_ratio: f64,
+    /// Steps down from the preferred format
+    steps: usize,
+    last_change_ms: Option<f64>,
+}
+
+impl AdaptiveQuality {
+    pub fn new(ceiling_bps: f64, allow_compressed: bool) -> Self {
+        Self {
+            ceiling_bps,
+            allow_compressed,
+            hold_ms: DEFAULT_ADAPTIVE_HOLD_MS,
+            bandwidth: BandwidthTracker::default(),
+            compression_ratio: 1.0,
+            steps: 0,
+            last_change_ms: None,
+        }
+    }
+
+    /// Set the minimum time between format changes, in ms
+    pub fn with_hold_ms(mut self, hold_ms: f64) -> Self {
+        self.hold_ms = hold_ms.max(0.0);
+        self
+    }
+
+    /// Rate above which frames step down, in bytes per second
+    pub fn ceiling_bps(&self) -> f64 {
+        self.ceiling_bps
+    }
+
+    /// Record `bytes` sent at `timestamp` (ms)
+    pub fn record(&mut self, timestamp: f64, bytes: u64) {
+        self.bandwidth.record(timestamp, bytes);
+    }
+
+    /// Record the RGBA / compressed size ratio of recent `Compressed` frames
+    pub fn set_compression_ratio(&mut self, ratio: f64) {
+        if ratio > 0.0 {
+            self.compression_ratio = ratio;
+        }
+    }
+
+    /// Send rate over the measurement window, in bytes per second
+    pub fn bps(&self) -> f64 {
+        self.bandwidth.bps()
+    }
+
+    /// Format to send when the client asked for `preferred`
+    ///
+    /// Only RGBA and RGB565 step down; other formats are left alone.
+    pub fn format(&self, preferred: FrameFormat) -> FrameFormat {
+        let ladder = self.ladder(preferred);
+        ladder[self.steps.min(ladder.len() - 1)]
+    }
+
+    /// Step up or down if the rate at `now` (ms) calls for it, returning
+    /// the new format when it changes
+    pub fn update(&mut self, now: f64, preferred: FrameFormat) -> Option<FrameFormat> {
+        self.bandwidth.prune(now);
+        if self.last_change_ms.is_some_and(|last| now - last < self.hold_ms) {
+            return None;
+        }
+
+        let ladder = self.ladder(preferred);
+        let steps = self.steps.min(ladder.len() - 1);
+        let bps = self.bandwidth.bps();
+        let steps = if bps > self.ceiling_bps && steps + 1 < ladder.len() {
+            steps + 1
+        } else if steps > 0
+            && bps * self.cost(ladder[steps - 1]) / self.cost(ladder[steps])
+                < self.ceiling_bps * ADAPTIVE_RECOVERY_HEADROOM
+        {
+            steps - 1
+        } else {
+            return None;
+        };
+
+        self.steps = steps;
+        self.last_change_ms = Some(now);
+        // The window measured the old format
+        self.bandwidth.clear();
+        Some(ladder[steps])
+    }
+
+    fn ladder(&self, preferred: FrameFormat) -> Vec<FrameFormat> {
+        let mut ladder = match preferred {
+            FrameFormat::Rgba => vec![FrameFormat::Rgba, FrameFormat::Rgb565],
+            FrameFormat::Rgb565 => vec![FrameFormat::Rgb565],
+            other => return vec![other],
+        };
+        if self.allow_compressed {
+            ladder.push(FrameFormat::Compressed);
+        }
+        ladder
+    }
+
+    /// Relative bytes per pixel of a format on the ladder
+    fn cost(&self, format: FrameFormat) -> f64 {
+        match format {
+            FrameFormat::Compressed => 4.0 / self.compression_ratio,
+            format => format.bytes_per_pixel().unwrap_or(4) as f64,
+        }
+    }
+}
+
+/// Simulated network conditions for outgoing messages
+///
+/// Models a link with fixed one-way latency, limited throughput and random
+/// loss. Messages are serialised onto the link in order, so a burst larger
+/// than the bandwidth queues up behind itself. A zero parameter disables
+/// that effect.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkSimulator {
+    latency_ms: f64,
+    bytes_per_ms: f64,
+    loss: f64,
+    /// Time the link finishes sending everything queued so far, in ms
+    busy_until: f64,
+}
+
+impl NetworkSimulator {
+    /// Create a simulator adding `latency_ms`, capping throughput at
+    /// `bandwidth_kbps` and dropping `loss_pct` percent of sends
+    pub fn new(latency_ms: f64, bandwidth_kbps: f64, loss_pct: f64) -> Self {
+        Self {
+            latency_ms: latency_ms.max(0.0),
+            bytes_per_ms: bandwidth_kbps.max(0.0) / 8.0,
+            loss: loss_pct.clamp(0.0, 100.0) / 100.0,
+            busy_until: 0.0,
+        }
+    }
+
+    /// Whether the simulator changes anything at all
+    pub fn is_active(&self) -> bool {
+        self.latency_ms > 0.0 || self.bytes_per_ms > 0.0 || self.loss > 0.0
+    }
+
+    /// Schedule a send of `bytes` at `now` (ms)
+    ///
+    /// `random` is a uniform sample in `[0, 1)` deciding loss. Returns how
+    /// long to wait before sending, or `None` if the send is lost.
+    pub fn schedule(&mut self, now: f64, bytes: usize, random: f64) -> Option<f64> {
+        if random < self.loss {
+            return None;
+        }
+
+        let start = self.busy_until.max(now);
+        let transmit = if self.bytes_per_ms > 0.0 {
+            bytes as f64 / self.bytes_per_ms
+        } else {
+            0.0
+        };
+        self.busy_until = start + transmit;
+        Some(self.busy_until + self.latency_ms - now)
+    }
+}
+
+/// Default delay before the first reconnection attempt in ms
+pub const DEFAULT_RECONNECT_BASE_MS: f64 = 500.0;
+
+/// Default longest delay between reconnection attempts in ms
+pub const DEFAULT_RECONNECT_MAX_MS: f64 = 30_000.0;
+
+/// Exponential backoff between reconnection attempts
+///
+/// The first retry waits `base_ms` and each following one twice as long,
+/// up to `max_ms`. After `max_retries` attempts it gives up until `reset`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base_ms: f64,
+    max_ms: f64,
+    max_retries: u32,
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Create a backoff allowing `max_retries` attempts
+    pub fn new(base_ms: f64, max_ms: f64, max_retries: u32) -> Self {
+        Self {
+            base_ms: base_ms.max(0.0),
+            max_ms: max_ms.max(base_ms),
+            max_retries,
+            attempts: 0,
+        }
+    }
+
+    /// Delay in ms before the next attempt, or `None` once retries run out
+    pub fn next_delay(&mut self) -> Option<f64> {
+        if self.attempts >= self.max_retries {
+            return None;
+        }
+        let delay = self.base_ms * 2f64.powi(self.attempts.min(30) as i32);
+        self.attempts += 1;
+        Some(delay.min(self.max_ms))
+    }
+
+    /// Attempts made since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Start over, e.g. after a successful connection
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Minimal in-memory transport for exercising provided trait methods
+    struct MockTransport {
+        config: SidecarConfig,
+        state: ConnectionState,
+        inbox: VecDeque<EmulatorToSidecarMessage>,
+    }
+
+    impl MockTransport {
+        fn new(inbox: Vec<EmulatorToSidecarMessage>) -> Self {
+            Self {
+                config: SidecarConfig::default(),
+                state: ConnectionState::Connected,
+                inbox: inbox.into(),
+            }
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn state(&self) -> ConnectionState {
+            self.state
+        }
+
+        fn config(&self) -> &SidecarConfig {
+            &self.config
+        }
+
+        fn connect(&mut self) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn disconnect(&mut self) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn send_frame(&mut self, _frame: Frame) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn send_message(
+            &mut self,
+            _msg: SidecarToEmulatorMessage,
+        ) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn set_format(
+            &mut self,
+            _format: FrameFormat,
+            _width: u32,
+            _height: u32,
+        ) -> Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn stats(&self) -> SidecarStats {
+            SidecarStats::default()
+        }
+
+        fn poll(&mut self) -> Option<EmulatorToSidecarMessage> {
+            self.inbox.pop_front()
+        }
+    }
+
     #[test]
     fn test_calculate_fps() {
         let timestamps: Vec<f64> = vec![0.0, 16.67, 33.33, 50.0];
@@ -139,6 +868,26 @@ mod tests {
         assert!(fps > 55.0 && fps < 65.0);
     }
 
+    #[test]
+    fn test_calculate_fps_ignores_stalls() {
+        let mut timestamps: Vec<f64> = (0..30).map(|i| i as f64 * 16.67).collect();
+        // The tab was backgrounded for five seconds
+        let resumed = timestamps[29] + 5000.0;
+        timestamps.extend((0..30).map(|i| resumed + i as f64 * 16.67));
+
+        let fps = calculate_fps(&timestamps);
+        assert!(fps > 59.0 && fps < 61.0, "fps = {}", fps);
+        assert!(calculate_fps_with_outliers(&timestamps, f64::INFINITY) < 15.0);
+
+        let mut tracker = FpsTracker::new(100).with_outlier_factor(f64::INFINITY);
+        for timestamp in &timestamps {
+            tracker.record(*timestamp);
+        }
+        assert!(tracker.fps() < 15.0);
+
+        assert_eq!(calculate_fps(&[5.0, 5.0, 5.0]), 0.0);
+    }
+
     #[test]
     fn test_fps_tracker() {
         let mut tracker = FpsTracker::new(10);
@@ -148,4 +897,253 @@ mod tests {
         let fps = tracker.fps();
         assert!(fps > 55.0 && fps < 65.0);
     }
+
+    #[test]
+    fn test_fps_tracker_window_stays_bounded() {
+        let mut tracker = FpsTracker::new(10);
+        // 30 fps for a while, then 60 fps for long enough to fill the window
+        for i in 0..1000 {
+            tracker.record(i as f64 * 33.33);
+        }
+        let start = 1000.0 * 33.33;
+        for i in 0..1000 {
+            tracker.record(start + i as f64 * 16.67);
+        }
+
+        assert_eq!(tracker.timestamps.len(), 10);
+        let fps = tracker.fps();
+        assert!(fps > 59.0 && fps < 61.0, "fps = {}", fps);
+    }
+
+    #[test]
+    fn test_latency_tracker() {
+        let mut tracker = LatencyTracker::new(20);
+        let mut stats = SidecarStats::default();
+        tracker.update_stats(&mut stats);
+        assert_eq!((stats.min_latency, stats.max_latency, stats.p95_latency), (0.0, 0.0, 0.0));
+
+        // Mostly 10 ms with one 500 ms spike, then enough to push it out
+        for i in 0..20 {
+            tracker.record(if i == 5 { 500.0 } else { 10.0 + i as f64 });
+        }
+        tracker.update_stats(&mut stats);
+        assert_eq!(stats.min_latency, 10.0);
+        assert_eq!(stats.max_latency, 500.0);
+        assert_eq!(stats.p95_latency, 29.0);
+        assert_eq!(tracker.percentile(100.0), 500.0);
+
+        for _ in 0..20 {
+            tracker.record(40.0);
+        }
+        assert_eq!(tracker.max(), 40.0);
+        assert_eq!(tracker.p95(), 40.0);
+    }
+
+    #[test]
+    fn test_clock_offset_tracker() {
+        let mut tracker = ClockOffsetTracker::new(3);
+        assert_eq!(tracker.offset(), None);
+        assert_eq!(tracker.to_local(1000.0), 1000.0);
+
+        // Peer 500 ms ahead, pings taking 30, 10 and 20 ms
+        tracker.record(1000.0, 530.0);
+        tracker.record(2000.0, 1510.0);
+        tracker.record(3000.0, 2520.0);
+        assert_eq!(tracker.offset(), Some(490.0));
+        assert_eq!(tracker.to_local(4000.0), 3510.0);
+
+        // The least delayed sample ages out of the window
+        tracker.record(4000.0, 3540.0);
+        tracker.record(5000.0, 4540.0);
+        assert_eq!(tracker.offset(), Some(480.0));
+    }
+
+    #[test]
+    fn test_bandwidth_burst_then_idle() {
+        let mut tracker = BandwidthTracker::new(1000.0);
+        assert_eq!(tracker.bps(), 0.0);
+
+        // 10 KB burst over 100ms
+        for i in 0..10 {
+            tracker.record(i as f64 * 10.0, 1000);
+        }
+        assert_eq!(tracker.bps(), 10_000.0);
+
+        // Half the burst has left the window
+        tracker.prune(1045.0);
+        assert_eq!(tracker.bps(), 5_000.0);
+
+        // Idle link decays to zero
+        tracker.prune(2000.0);
+        assert_eq!(tracker.bps(), 0.0);
+    }
+
+    #[test]
+    fn test_bandwidth_record_prunes() {
+        let mut tracker = BandwidthTracker::new(500.0);
+        tracker.record(0.0, 4000);
+        tracker.record(1000.0, 500);
+        assert_eq!(tracker.bps(), 1000.0);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10.0, 3.0);
+
+        // Burst up to capacity, then limited
+        assert!((0..3).all(|_| bucket.try_take(0.0)));
+        assert!(!bucket.try_take(0.0));
+
+        // One token every 100ms
+        assert!(!bucket.try_take(50.0));
+        assert!(bucket.try_take(100.0));
+        assert!(!bucket.try_take(100.0));
+
+        // A steady rate at the limit is never refused
+        assert!((1..=20).all(|i| bucket.try_take(100.0 + i as f64 * 100.0)));
+
+        // Refill stops at the burst size
+        assert_eq!(bucket.available(60_000.0), 3.0);
+    }
+
+    #[test]
+    fn test_compression_ratio_window() {
+        let mut tracker = CompressionTracker::new(1000.0);
+        assert_eq!(tracker.ratio(), 1.0);
+
+        tracker.record(0.0, 4000, 1000);
+        tracker.record(100.0, 4000, 3000);
+        assert_eq!(tracker.ratio(), 2.0);
+
+        // The first frame leaves the window
+        tracker.record(1050.0, 2000, 1000);
+        assert_eq!(tracker.ratio(), 1.5);
+
+        tracker.clear();
+        assert_eq!(tracker.ratio(), 1.0);
+    }
+
+    #[test]
+    fn test_adaptive_quality_steps_down_and_recovers() {
+        let mut adaptive = AdaptiveQuality::new(1000.0, true).with_hold_ms(500.0);
+        assert_eq!(adaptive.format(FrameFormat::Rgba), FrameFormat::Rgba);
+
+        // 1200 B/s of RGBA is over the ceiling
+        adaptive.record(0.0, 1200);
+        assert_eq!(adaptive.update(10.0, FrameFormat::Rgba), Some(FrameFormat::Rgb565));
+        assert_eq!(adaptive.format(FrameFormat::Rgba), FrameFormat::Rgb565);
+
+        // Still over, but held until hold_ms has passed
+        adaptive.record(100.0, 1100);
+        assert_eq!(adaptive.update(200.0, FrameFormat::Rgba), None);
+        assert_eq!(adaptive.update(600.0, FrameFormat::Rgba), Some(FrameFormat::Compressed));
+
+        // At a 4:1 ratio RGB565 would need twice the compressed rate
+        adaptive.set_compression_ratio(4.0);
+        adaptive.record(700.0, 450);
+        assert_eq!(adaptive.update(1200.0, FrameFormat::Rgba), None);
+        adaptive.record(1300.0, 350);
+        assert_eq!(adaptive.update(1800.0, FrameFormat::Rgba), Some(FrameFormat::Rgb565));
+
+        // An idle link has room for RGBA again
+        assert_eq!(adaptive.update(2400.0, FrameFormat::Rgba), Some(FrameFormat::Rgba));
+        assert_eq!(adaptive.update(3000.0, FrameFormat::Rgba), None);
+
+        // Formats off the ladder are left alone
+        adaptive.record(3100.0, 5000);
+        assert_eq!(adaptive.update(3200.0, FrameFormat::Yuv420), None);
+        assert_eq!(adaptive.format(FrameFormat::Yuv420), FrameFormat::Yuv420);
+    }
+
+    #[test]
+    fn test_network_simulator() {
+        assert!(!NetworkSimulator::new(0.0, 0.0, 0.0).is_active());
+
+        // 8 kbps moves one byte per ms
+        let mut link = NetworkSimulator::new(50.0, 8.0, 0.0);
+        assert_eq!(link.schedule(0.0, 100, 0.5), Some(150.0));
+        // Queued behind the first send
+        assert_eq!(link.schedule(10.0, 100, 0.5), Some(240.0));
+        // Link idle again
+        assert_eq!(link.schedule(1000.0, 10, 0.5), Some(60.0));
+
+        let mut lossy = NetworkSimulator::new(0.0, 0.0, 25.0);
+        assert_eq!(lossy.schedule(0.0, 100, 0.1), None);
+        assert_eq!(lossy.schedule(0.0, 100, 0.3), Some(0.0));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(500.0, 3000.0, 5);
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_delay()).collect();
+        assert_eq!(delays, vec![500.0, 1000.0, 2000.0, 3000.0, 3000.0]);
+        assert_eq!(backoff.attempts(), 5);
+        assert_eq!(backoff.next_delay(), None);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(500.0));
+        assert_eq!(Backoff::new(500.0, 3000.0, 0).next_delay(), None);
+    }
+
+    #[test]
+    fn test_error_to_message() {
+        let err = TransportError::ProtocolError("bad json".to_string());
+        assert!(!err.is_fatal());
+        match err.to_message() {
+            SidecarToEmulatorMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::ProtocolError);
+                assert!(message.contains("bad json"));
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        assert!(TransportError::SendFailed("closed".to_string()).is_fatal());
+    }
+
+    #[test]
+    fn test_poll_all_drains_in_order() {
+        let mut transport = MockTransport::new(
+            (0..5)
+                .map(|i| EmulatorToSidecarMessage::Ping { timestamp: i as f64 })
+                .collect(),
+        );
+
+        let messages = transport.poll_all();
+        let timestamps: Vec<f64> = messages
+            .iter()
+            .map(|msg| match msg {
+                EmulatorToSidecarMessage::Ping { timestamp } => *timestamp,
+                _ => panic!("Wrong message type"),
+            })
+            .collect();
+
+        assert_eq!(timestamps, vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+        assert!(transport.poll().is_none());
+        assert!(transport.poll_all().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_message_stream() {
+        let mut transport = MockTransport::new(vec![
+            EmulatorToSidecarMessage::RequestKeyframe,
+            EmulatorToSidecarMessage::GetServerInfo,
+        ]);
+        async fn next<S: Stream + Unpin + ?Sized>(stream: &mut S) -> Option<S::Item> {
+            std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+        }
+        let mut stream = transport.message_stream();
+
+        assert!(matches!(next(&mut stream).await, Some(EmulatorToSidecarMessage::RequestKeyframe)));
+        assert!(matches!(next(&mut stream).await, Some(EmulatorToSidecarMessage::GetServerInfo)));
+        // Waits while connected and idle
+        assert!(tokio::time::timeout(MESSAGE_POLL_INTERVAL * 5, next(&mut stream)).await.is_err());
+        drop(stream);
+
+        // Ends once disconnected, after what was already queued
+        transport.inbox.push_back(EmulatorToSidecarMessage::RequestKeyframe);
+        transport.state = ConnectionState::Disconnected;
+        let mut stream = transport.message_stream();
+        assert!(matches!(next(&mut stream).await, Some(EmulatorToSidecarMessage::RequestKeyframe)));
+        assert!(next(&mut stream).await.is_none());
+    }
 }
@@ -2,16 +2,23 @@
 //!
 //! WebAssembly bindings for running the sidecar in the browser with WebGPU.
 
-use crate::frame::{Frame, FrameBuffer};
+use crate::chunk::{split_payload, DEFAULT_MAX_CHUNK_SIZE};
+use crate::frame::{Frame, FrameBuffer, GenerationTracker};
 use crate::protocol::{
     ConnectionState, EmulatorToSidecarMessage, FrameFormat, FrameMetadata,
-    SidecarConfig, SidecarStats, SidecarToEmulatorMessage,
+    SidecarConfig, SidecarMode, SidecarStats, SidecarToEmulatorMessage, DEFAULT_STREAM, FRAME_HEADER_SIZE,
+    PROTOCOL_VERSION,
+};
+use crate::transport::{
+    Backoff, BandwidthTracker, FpsTracker, LatencyTracker, NetworkSimulator, DEFAULT_RECONNECT_BASE_MS,
+    DEFAULT_RECONNECT_MAX_MS,
 };
-use crate::transport::FpsTracker;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use web_sys::{console, MessageEvent, WebSocket};
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::rc::Rc;
 
 /// Initialize panic hook for better error messages
@@ -21,18 +28,56 @@ pub fn init() {
     console::log_1(&"QemuWeb Sidecar WASM initialized".into());
 }
 
+/// Sequence and keyframe flag from a `frameHeader`
+type PendingHeader = (u64, Option<bool>);
+
 /// WASM Sidecar client
 #[wasm_bindgen]
 pub struct WasmSidecar {
-    ws: Option<WebSocket>,
+    /// Open socket, replaced when reconnecting automatically
+    ws: Rc<RefCell<Option<WebSocket>>>,
     config: SidecarConfig,
-    state: ConnectionState,
-    stats: SidecarStats,
+    state: Rc<Cell<ConnectionState>>,
+    /// Shared with the socket handlers, which record latency and acks
+    stats: Rc<RefCell<SidecarStats>>,
     fps_tracker: FpsTracker,
+    /// Ping round trips, shared with the socket handlers
+    latency_tracker: Rc<RefCell<LatencyTracker>>,
+    bandwidth_tracker: BandwidthTracker,
     frame_buffer: FrameBuffer,
+    max_chunk_size: usize,
+    /// Last format sent with `set_format`, with its dimensions
+    current_format: Rc<Cell<(FrameFormat, u32, u32)>>,
+    auto_apply_format: Rc<Cell<bool>>,
     frame_callback: Option<js_sys::Function>,
+    message_callback: Option<js_sys::Function>,
     state_callback: Option<js_sys::Function>,
     error_callback: Option<js_sys::Function>,
+    format_request_callback: Option<js_sys::Function>,
+    server_info_callback: Option<js_sys::Function>,
+    tear_callback: Option<js_sys::Function>,
+    keyframe_request_callback: Option<js_sys::Function>,
+    generation_tracker: Rc<RefCell<GenerationTracker>>,
+    /// `frameAck`s received from the server for this client's frames
+    frames_acked: Rc<Cell<u64>>,
+    network_sim: NetworkSimulator,
+    /// Flow control window and ack timeout in ms, see `set_frame_window`
+    frame_window: Option<(usize, f64)>,
+    /// Frames sent but not yet acked, with the time they were sent
+    unacked: Rc<RefCell<VecDeque<(u64, f64)>>>,
+    /// Token sent in an `auth` message as soon as the socket opens
+    auth_token: Option<String>,
+    /// Whether binary messages from the server start with a frame header
+    binary_header: Rc<Cell<bool>>,
+    /// The last `frameHeader`, waiting for the binary payload it describes
+    pending_frame: Rc<Cell<Option<PendingHeader>>>,
+    /// Renders every frame sent or received, see `set_renderer`
+    renderer: Rc<RefCell<Option<WasmRenderer>>>,
+    /// Reconnection backoff, `None` unless `set_auto_reconnect` enabled it
+    reconnect: Rc<RefCell<Option<Backoff>>>,
+    /// Bumped by `connect` and `disconnect` so handlers and retry timers of
+    /// an earlier connection stand down
+    session: Rc<Cell<u32>>,
 }
 
 #[wasm_bindgen]
@@ -41,214 +86,511 @@ impl WasmSidecar {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
         Self {
-            ws: None,
+            ws: Rc::new(RefCell::new(None)),
             config: SidecarConfig::default(),
-            state: ConnectionState::Disconnected,
-            stats: SidecarStats::default(),
+            state: Rc::new(Cell::new(ConnectionState::Disconnected)),
+            stats: Rc::new(RefCell::new(SidecarStats::default())),
             fps_tracker: FpsTracker::new(60),
+            latency_tracker: Rc::new(RefCell::new(LatencyTracker::default())),
+            bandwidth_tracker: BandwidthTracker::default(),
             frame_buffer: FrameBuffer::new(4),
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            current_format: Rc::new(Cell::new((FrameFormat::Rgba, 0, 0))),
+            auto_apply_format: Rc::new(Cell::new(false)),
             frame_callback: None,
+            message_callback: None,
             state_callback: None,
             error_callback: None,
+            format_request_callback: None,
+            server_info_callback: None,
+            tear_callback: None,
+            keyframe_request_callback: None,
+            generation_tracker: Rc::new(RefCell::new(GenerationTracker::new())),
+            frames_acked: Rc::new(Cell::new(0)),
+            network_sim: NetworkSimulator::default(),
+            frame_window: None,
+            unacked: Rc::new(RefCell::new(VecDeque::new())),
+            auth_token: None,
+            binary_header: Rc::new(Cell::new(false)),
+            pending_frame: Rc::new(Cell::new(None)),
+            renderer: Rc::new(RefCell::new(None)),
+            reconnect: Rc::new(RefCell::new(None)),
+            session: Rc::new(Cell::new(0)),
         }
     }
 
     /// Connect to a remote sidecar server
     #[wasm_bindgen]
     pub fn connect(&mut self, url: &str) -> Result<(), JsValue> {
-        if self.ws.is_some() {
+        if self.ws.borrow().is_some() {
             return Err(JsValue::from_str("Already connected"));
         }
 
-        self.set_state(ConnectionState::Connecting);
-
-        let ws = WebSocket::new(url)?;
-        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-
-        // Set up event handlers
-        let state_clone = Rc::new(RefCell::new(ConnectionState::Connecting));
-        let callback_clone = self.state_callback.clone();
-
-        // onopen
-        {
-            let state = state_clone.clone();
-            let callback = callback_clone.clone();
-            let onopen = Closure::wrap(Box::new(move |_: JsValue| {
-                *state.borrow_mut() = ConnectionState::Connected;
-                if let Some(ref cb) = callback {
-                    let _ = cb.call1(&JsValue::NULL, &JsValue::from_str("connected"));
-                }
-                console::log_1(&"WebSocket connected".into());
-            }) as Box<dyn FnMut(JsValue)>);
-            ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
-            onopen.forget();
-        }
-
-        // onclose
-        {
-            let state = state_clone.clone();
-            let callback = callback_clone.clone();
-            let onclose = Closure::wrap(Box::new(move |_: JsValue| {
-                *state.borrow_mut() = ConnectionState::Disconnected;
-                if let Some(ref cb) = callback {
-                    let _ = cb.call1(&JsValue::NULL, &JsValue::from_str("disconnected"));
-                }
-                console::log_1(&"WebSocket closed".into());
-            }) as Box<dyn FnMut(JsValue)>);
-            ws.set_onclose(Some(onclose.as_ref().unchecked_ref()));
-            onclose.forget();
-        }
-
-        // onerror
-        {
-            let state = state_clone.clone();
-            let error_callback = self.error_callback.clone();
-            let onerror = Closure::wrap(Box::new(move |e: JsValue| {
-                *state.borrow_mut() = ConnectionState::Error;
-                if let Some(ref cb) = error_callback {
-                    let _ = cb.call1(&JsValue::NULL, &e);
-                }
-                console::error_1(&"WebSocket error".into());
-            }) as Box<dyn FnMut(JsValue)>);
-            ws.set_onerror(Some(onerror.as_ref().unchecked_ref()));
-            onerror.forget();
-        }
-
-        // onmessage
-        {
-            let frame_callback = self.frame_callback.clone();
-            let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| {
-                if let Ok(text) = e.data().dyn_into::<js_sys::JsString>() {
-                    // JSON message
-                    let text: String = text.into();
-                    console::log_1(&format!("Received: {}", text).into());
-                } else if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
-                    // Binary frame data
-                    let array = js_sys::Uint8Array::new(&buffer);
-                    let len = array.length();
-                    console::log_1(&format!("Received {} bytes of frame data", len).into());
-
-                    if let Some(ref cb) = frame_callback {
-                        let _ = cb.call1(&JsValue::NULL, &buffer);
-                    }
-                }
-            }) as Box<dyn FnMut(MessageEvent)>);
-            ws.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
-            onmessage.forget();
+        // Cancels any reconnection still pending from an earlier connection
+        self.session.set(self.session.get().wrapping_add(1));
+        if let Some(backoff) = self.reconnect.borrow_mut().as_mut() {
+            backoff.reset();
         }
+        self.set_state(ConnectionState::Connecting);
 
-        self.ws = Some(ws);
-        Ok(())
+        let connection = Connection {
+            url: url.into(),
+            session: self.session.get(),
+            current_session: self.session.clone(),
+            socket: self.ws.clone(),
+            state: self.state.clone(),
+            reconnect: self.reconnect.clone(),
+            auth: self.auth_token.clone().and_then(|token| {
+                serde_json::to_string(&EmulatorToSidecarMessage::Auth { token }).ok()
+            }),
+            stats: self.stats.clone(),
+            latency_tracker: self.latency_tracker.clone(),
+            frames_acked: self.frames_acked.clone(),
+            binary_header: self.binary_header.clone(),
+            pending_frame: self.pending_frame.clone(),
+            renderer: self.renderer.clone(),
+            frame_callback: self.frame_callback.clone(),
+            message_callback: self.message_callback.clone(),
+            state_callback: self.state_callback.clone(),
+            error_callback: self.error_callback.clone(),
+            format_request_callback: self.format_request_callback.clone(),
+            server_info_callback: self.server_info_callback.clone(),
+            tear_callback: self.tear_callback.clone(),
+            keyframe_request_callback: self.keyframe_request_callback.clone(),
+            generation_tracker: self.generation_tracker.clone(),
+            unacked: self.unacked.clone(),
+            current_format: self.current_format.clone(),
+            auto_apply_format: self.auto_apply_format.clone(),
+        };
+        connection.open()
     }
 
     /// Disconnect from the server
+    ///
+    /// Never followed by an automatic reconnection.
     #[wasm_bindgen]
     pub fn disconnect(&mut self) -> Result<(), JsValue> {
-        if let Some(ws) = self.ws.take() {
+        self.session.set(self.session.get().wrapping_add(1));
+        let ws = self.ws.borrow_mut().take();
+        if let Some(ws) = ws {
             ws.close()?;
         }
         self.set_state(ConnectionState::Disconnected);
         Ok(())
     }
 
+    /// Reconnect automatically when the connection drops unexpectedly
+    ///
+    /// Up to `max_retries` attempts are made, waiting 0.5s before the first
+    /// and doubling each time up to 30s. The state callback fires with
+    /// `"connecting"` for every attempt. The count starts over once a
+    /// connection opens, and `disconnect` never triggers a reconnection.
+    #[wasm_bindgen]
+    pub fn set_auto_reconnect(&mut self, enabled: bool, max_retries: u32) {
+        *self.reconnect.borrow_mut() = enabled
+            .then(|| Backoff::new(DEFAULT_RECONNECT_BASE_MS, DEFAULT_RECONNECT_MAX_MS, max_retries));
+    }
+
     /// Send a ping message
     #[wasm_bindgen]
-    pub fn ping(&self) -> Result<(), JsValue> {
-        let ws = self.ws.as_ref().ok_or_else(|| JsValue::from_str("Not connected"))?;
+    pub fn ping(&mut self) -> Result<(), JsValue> {
+        let ws = self.socket()?;
 
         let now = js_sys::Date::now();
         let msg = EmulatorToSidecarMessage::Ping { timestamp: now };
-        let json = serde_json::to_string(&msg)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-
-        ws.send_with_str(&json)
+        self.dispatch(&ws, vec![Outgoing::json(&msg)?])?;
+        Ok(())
     }
 
     /// Set the frame format
     #[wasm_bindgen]
     pub fn set_format(&self, format: &str, width: u32, height: u32) -> Result<(), JsValue> {
-        let ws = self.ws.as_ref().ok_or_else(|| JsValue::from_str("Not connected"))?;
-
-        let format = match format {
-            "rgba" => FrameFormat::Rgba,
-            "rgb565" => FrameFormat::Rgb565,
-            "yuv420" => FrameFormat::Yuv420,
-            "compressed" => FrameFormat::Compressed,
-            _ => return Err(JsValue::from_str("Invalid format")),
-        };
+        let ws = self.socket()?;
+        let format = parse_format(format)?;
+        send_set_format(&ws, &self.current_format, format, width, height)
+    }
 
-        let msg = EmulatorToSidecarMessage::SetFormat { format, width, height };
-        let json = serde_json::to_string(&msg)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    /// Change the frame dimensions, keeping the format
+    ///
+    /// The server answers with a `resizeAck`.
+    #[wasm_bindgen]
+    pub fn resize(&self, width: u32, height: u32) -> Result<(), JsValue> {
+        let ws = self.socket()?;
+        send_message(&ws, &EmulatorToSidecarMessage::Resize { width, height })?;
+        let (format, _, _) = self.current_format.get();
+        self.current_format.set((format, width, height));
+        Ok(())
+    }
+
+    /// Ask the server for its version, uptime and capabilities
+    ///
+    /// The reply is delivered to the `on_server_info` callback.
+    #[wasm_bindgen]
+    pub fn request_server_info(&self) -> Result<(), JsValue> {
+        let ws = self.socket()?;
+        send_message(&ws, &EmulatorToSidecarMessage::GetServerInfo)
+    }
+
+    /// Change the target frame rate (1-240) without switching mode
+    #[wasm_bindgen]
+    pub fn set_target_fps(&self, fps: u32) -> Result<(), JsValue> {
+        let ws = self.socket()?;
+        send_message(&ws, &EmulatorToSidecarMessage::SetTargetFps { fps })
+    }
 
-        ws.send_with_str(&json)
+    /// Ask the server to hold back frames until the next keyframe
+    ///
+    /// Use after a decode error to resume from a clean reference frame.
+    #[wasm_bindgen]
+    pub fn request_keyframe(&self) -> Result<(), JsValue> {
+        let ws = self.socket()?;
+        send_message(&ws, &EmulatorToSidecarMessage::RequestKeyframe)
+    }
+
+    /// Send any emulator-to-sidecar message given as JSON
+    ///
+    /// For messages without a dedicated method. JSON that isn't a valid
+    /// message is rejected without sending anything.
+    #[wasm_bindgen]
+    pub fn send_message(&self, msg_json: &str) -> Result<(), JsValue> {
+        let msg: EmulatorToSidecarMessage = serde_json::from_str(msg_json)
+            .map_err(|e| JsValue::from_str(&format!("Invalid message: {}", e)))?;
+        let ws = self.socket()?;
+        match msg {
+            // Keep the remembered format in step with the server
+            EmulatorToSidecarMessage::SetFormat { format, width, height } => {
+                send_set_format(&ws, &self.current_format, format, width, height)
+            }
+            EmulatorToSidecarMessage::Unknown => Err(JsValue::from_str("Invalid message: unknown type")),
+            msg => send_message(&ws, &msg),
+        }
+    }
+
+    /// Switch mode (`local`, `remote` or `disabled`), optionally with a new
+    /// target frame rate
+    #[wasm_bindgen]
+    pub fn send_set_mode(&self, mode: &str, target_fps: Option<u32>) -> Result<(), JsValue> {
+        let mode = parse_mode(mode)?;
+        let ws = self.socket()?;
+        let config = target_fps.map(|fps| SidecarConfig {
+            mode,
+            target_fps: Some(fps),
+            ..self.config.clone()
+        });
+        send_message(&ws, &EmulatorToSidecarMessage::SetMode { mode, config })
+    }
+
+    /// Discard buffered frames and reset sequence numbers and fps tracking
+    ///
+    /// Fires the state callback with `"flushed"`.
+    #[wasm_bindgen]
+    pub fn flush_buffer(&mut self) {
+        self.frame_buffer.clear();
+        self.fps_tracker.clear();
+        {
+            let mut stats = self.stats.borrow_mut();
+            stats.buffer_depth = 0;
+            stats.frames_received = 0;
+            stats.current_fps = 0.0;
+        }
+        if let Some(ref cb) = self.state_callback {
+            let _ = cb.call1(&JsValue::NULL, &JsValue::from_str("flushed"));
+        }
+    }
+
+    /// Decline a format the server asked for with `requestFormat`
+    #[wasm_bindgen]
+    pub fn decline_format(&self, format: &str) -> Result<(), JsValue> {
+        let ws = self.socket()?;
+        send_message(&ws, &EmulatorToSidecarMessage::FormatAck {
+            format: parse_format(format)?,
+            success: false,
+        })
+    }
+
+    /// Apply server format requests automatically
+    ///
+    /// When enabled, a `requestFormat` from the server is answered with
+    /// `set_format` at the current dimensions unless the `on_format_request`
+    /// callback returns `false`, in which case it is declined. When disabled
+    /// (the default) requests are only reported to the callback.
+    #[wasm_bindgen]
+    pub fn set_auto_apply_format(&mut self, enabled: bool) {
+        self.auto_apply_format.set(enabled);
     }
 
     /// Send frame data
     #[wasm_bindgen]
     pub fn send_frame(&mut self, data: &[u8], width: u32, height: u32, keyframe: bool) -> Result<(), JsValue> {
-        let ws = self.ws.as_ref().ok_or_else(|| JsValue::from_str("Not connected"))?;
+        let ws = self.socket()?;
 
         let now = js_sys::Date::now();
+        if !self.has_credit(now) {
+            // Wait for acks rather than piling more onto a slow consumer
+            self.stats.borrow_mut().frames_dropped += 1;
+            return Ok(());
+        }
         self.fps_tracker.record(now);
-        self.stats.frames_received += 1;
-        self.stats.current_fps = self.fps_tracker.fps();
-        self.stats.bytes_transferred += data.len() as u64;
-
+        self.bandwidth_tracker.record(now, data.len() as u64);
+        let sequence = {
+            let mut stats = self.stats.borrow_mut();
+            stats.frames_received += 1;
+            stats.current_fps = self.fps_tracker.fps();
+            stats.bytes_transferred += data.len() as u64;
+            stats.bytes_per_second = self.bandwidth_tracker.bps();
+            stats.frames_received
+        };
         let metadata = FrameMetadata {
-            sequence: self.stats.frames_received,
+            sequence,
             timestamp: now,
             width,
             height,
             format: self.config.preferred_format.unwrap_or(FrameFormat::Rgba),
             keyframe,
+            generation: None,
+            checksum: None,
+            stream_id: DEFAULT_STREAM,
         };
+        render(&self.renderer, &metadata, data);
 
-        // Send metadata
-        let msg = EmulatorToSidecarMessage::Frame { metadata };
-        let json = serde_json::to_string(&msg)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-        ws.send_with_str(&json)?;
+        let mut messages = Vec::new();
+        if self.config.binary_header == Some(true) && data.len() <= self.max_chunk_size {
+            // Header and data in one message, when negotiated and it fits
+            let mut message = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+            message.extend_from_slice(&metadata.to_header_bytes());
+            message.extend_from_slice(data);
+            messages.push(Outgoing::Binary(Cow::Owned(message)));
+        } else {
+            // Metadata, then binary data split into chunks if it is too large
+            // for one message
+            let metadata = FrameMetadata {
+                checksum: Some(crc32fast::hash(data)),
+                ..metadata
+            };
+            messages.push(Outgoing::json(&EmulatorToSidecarMessage::Frame { metadata })?);
+            if data.len() <= self.max_chunk_size {
+                messages.push(Outgoing::Binary(Cow::Borrowed(data)));
+            } else {
+                for (chunk, payload) in split_payload(sequence, data, self.max_chunk_size) {
+                    messages.push(Outgoing::json(&EmulatorToSidecarMessage::FrameChunk(chunk))?);
+                    messages.push(Outgoing::Binary(Cow::Borrowed(payload)));
+                }
+            }
+        }
 
-        // Send binary data
-        ws.send_with_u8_array(data)
+        if self.frame_window.is_some() {
+            self.unacked.borrow_mut().push_back((sequence, now));
+        }
+        if !self.dispatch(&ws, messages)? {
+            self.stats.borrow_mut().frames_dropped += 1;
+        }
+        Ok(())
+    }
+
+    /// Render every frame sent with `send_frame` or received with metadata
+    ///
+    /// The renderer stays usable from JS as well.
+    #[wasm_bindgen]
+    pub fn set_renderer(&mut self, renderer: &WasmRenderer) {
+        *self.renderer.borrow_mut() = Some(renderer.clone());
+    }
+
+    /// Stop rendering frames
+    #[wasm_bindgen]
+    pub fn clear_renderer(&mut self) {
+        self.renderer.borrow_mut().take();
+    }
+
+    /// Authenticate with `token` on the next `connect`
+    ///
+    /// Needed for servers with an `auth_token`; `undefined` connects
+    /// without authenticating.
+    #[wasm_bindgen]
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.auth_token = token;
+    }
+
+    /// Limit frames in flight to `window` until the server acks them
+    ///
+    /// Matches the server's `frame_window`: `send_frame` drops frames
+    /// (counted in `framesDropped`) while `window` frames are unacked. A
+    /// frame whose ack hasn't come back after `ack_timeout_ms` is given up
+    /// on, so a lost ack can't stall sending for good. A window of 0
+    /// disables flow control.
+    #[wasm_bindgen]
+    pub fn set_frame_window(&mut self, window: usize, ack_timeout_ms: f64) {
+        self.frame_window = (window > 0).then_some((window, ack_timeout_ms.max(0.0)));
+        self.unacked.borrow_mut().clear();
+    }
+
+    /// Whether `send_frame` would send a frame now rather than drop it
+    #[wasm_bindgen]
+    pub fn can_send_frame(&mut self) -> bool {
+        self.has_credit(js_sys::Date::now())
+    }
+
+    /// Simulate a slow or lossy network for everything sent from now on
+    ///
+    /// Frames and pings are delayed by `latency_ms`, queued behind each
+    /// other at `bandwidth_kbps`, and `loss_pct` percent of them are
+    /// silently dropped (lost frames count in `framesDropped`). Pass zeros
+    /// to turn the simulation off. Everything happens client-side with
+    /// timers, so it only shapes outgoing traffic.
+    #[wasm_bindgen]
+    pub fn set_network_simulation(&mut self, latency_ms: f64, bandwidth_kbps: f64, loss_pct: f64) {
+        self.network_sim = NetworkSimulator::new(latency_ms, bandwidth_kbps, loss_pct);
+    }
+
+    /// Exchange frames as one binary message with a packed metadata header
+    ///
+    /// Tells the server with `setMode` if connected. Frames passed to the
+    /// frame callback then start with a `FRAME_HEADER_SIZE` byte header.
+    /// Frames too large for one message are still sent chunked, with JSON
+    /// metadata.
+    #[wasm_bindgen]
+    pub fn set_binary_header(&mut self, enabled: bool) -> Result<(), JsValue> {
+        self.config.binary_header = Some(enabled);
+        self.binary_header.set(enabled);
+        match self.ws.borrow().as_ref() {
+            Some(ws) => send_message(
+                ws,
+                &EmulatorToSidecarMessage::SetMode {
+                    mode: self.config.mode,
+                    config: Some(self.config.clone()),
+                },
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Set the largest binary payload sent in a single message
+    ///
+    /// Frames larger than this are split into `frameChunk` pieces.
+    #[wasm_bindgen]
+    pub fn set_max_chunk_size(&mut self, max_chunk_size: usize) {
+        self.max_chunk_size = max_chunk_size.max(1);
     }
 
     /// Get connection state
     #[wasm_bindgen]
     pub fn get_state(&self) -> String {
-        match self.state {
-            ConnectionState::Disconnected => "disconnected".to_string(),
-            ConnectionState::Connecting => "connecting".to_string(),
-            ConnectionState::Connected => "connected".to_string(),
-            ConnectionState::Error => "error".to_string(),
-        }
+        state_name(self.state.get()).to_string()
     }
 
     /// Get current FPS
     #[wasm_bindgen]
     pub fn get_fps(&self) -> f64 {
-        self.stats.current_fps
+        self.stats.borrow().current_fps
     }
 
     /// Get frames received count
     #[wasm_bindgen]
     pub fn get_frames_received(&self) -> u64 {
-        self.stats.frames_received
+        self.stats.borrow().frames_received
+    }
+
+    /// Get frames dropped, locally or throttled by the server
+    #[wasm_bindgen]
+    pub fn get_frames_dropped(&self) -> u64 {
+        self.stats.borrow().frames_dropped
+    }
+
+    /// Get the number of `frameAck`s received from the server
+    #[wasm_bindgen]
+    pub fn get_frames_acked(&self) -> u64 {
+        self.frames_acked.get()
+    }
+
+    /// Get the smoothed ping round-trip time in ms, 0 before the first pong
+    #[wasm_bindgen]
+    pub fn get_latency(&self) -> f64 {
+        self.stats.borrow().avg_latency
+    }
+
+    /// Get the number of frames held in the frame buffer
+    #[wasm_bindgen]
+    pub fn get_buffer_depth(&mut self) -> u32 {
+        self.stats.borrow_mut().buffer_depth = self.frame_buffer.len() as u64;
+        self.frame_buffer.len() as u32
+    }
+
+    /// Get the number of frames waiting in the frame buffer
+    #[wasm_bindgen]
+    pub fn get_buffer_len(&self) -> u32 {
+        self.frame_buffer.len() as u32
+    }
+
+    /// Get how many frames the frame buffer can hold
+    #[wasm_bindgen]
+    pub fn get_buffer_capacity(&self) -> u32 {
+        self.frame_buffer.capacity() as u32
+    }
+
+    /// Get how many frames the frame buffer has dropped for lack of room
+    #[wasm_bindgen]
+    pub fn get_buffer_dropped(&self) -> u32 {
+        self.frame_buffer.dropped_count() as u32
+    }
+
+    /// Resize the frame buffer, keeping the newest queued frames
+    #[wasm_bindgen]
+    pub fn set_buffer_capacity(&mut self, n: u32) {
+        self.frame_buffer.set_capacity(n as usize);
+        self.stats.borrow_mut().buffer_depth = self.frame_buffer.len() as u64;
+    }
+
+    /// Get how full the frame buffer is, from 0.0 to 1.0
+    #[wasm_bindgen]
+    pub fn get_buffer_fullness(&self) -> f32 {
+        self.frame_buffer.fullness()
     }
 
     /// Get bytes transferred
     #[wasm_bindgen]
     pub fn get_bytes_transferred(&self) -> u64 {
-        self.stats.bytes_transferred
+        self.stats.borrow().bytes_transferred
+    }
+
+    /// Get recent outgoing throughput in bytes per second
+    #[wasm_bindgen]
+    pub fn get_bytes_per_second(&mut self) -> f64 {
+        self.bandwidth_tracker.prune(js_sys::Date::now());
+        let mut stats = self.stats.borrow_mut();
+        stats.bytes_per_second = self.bandwidth_tracker.bps();
+        stats.bytes_per_second
+    }
+
+    /// Get a 0-100 connection quality score, see `SidecarStats::quality_score`
+    #[wasm_bindgen]
+    pub fn get_quality_score(&mut self) -> u8 {
+        self.bandwidth_tracker.prune(js_sys::Date::now());
+        let mut stats = self.stats.borrow_mut();
+        stats.bytes_per_second = self.bandwidth_tracker.bps();
+        stats.quality_score()
     }
 
     /// Set callback for frame events
+    ///
+    /// Called with `{sequence, width, height, format, keyframe, data}`,
+    /// where `data` is a `Uint8Array` of the payload. Width, height and
+    /// format come from the binary header when enabled, otherwise from the
+    /// last `set_format`. A payload whose metadata is unknown is passed as
+    /// a bare `ArrayBuffer`.
     #[wasm_bindgen]
     pub fn on_frame(&mut self, callback: js_sys::Function) {
         self.frame_callback = Some(callback);
     }
 
+    /// Set callback for every message from the server
+    ///
+    /// Called with `(type, message)`, where `type` is the message's `type`
+    /// field, e.g. `"pong"`, and `message` the decoded JSON object. Malformed
+    /// messages go to the error callback instead.
+    #[wasm_bindgen]
+    pub fn on_message(&mut self, callback: js_sys::Function) {
+        self.message_callback = Some(callback);
+    }
+
     /// Set callback for state changes
     #[wasm_bindgen]
     pub fn on_state_change(&mut self, callback: js_sys::Function) {
@@ -261,20 +603,793 @@ impl WasmSidecar {
         self.error_callback = Some(callback);
     }
 
+    /// Set callback for torn frames
+    ///
+    /// Called with the frame's sequence number when its generation doesn't
+    /// follow on from the previous frame. A keyframe is requested either way.
+    /// Only frames whose source sets a generation are checked.
+    #[wasm_bindgen]
+    pub fn on_tear(&mut self, callback: js_sys::Function) {
+        self.tear_callback = Some(callback);
+    }
+
+    /// Set callback for keyframe requests from the server
+    ///
+    /// Called when a frame sent with `send_frame` arrived corrupted; the next
+    /// frame sent should be a keyframe.
+    #[wasm_bindgen]
+    pub fn on_keyframe_request(&mut self, callback: js_sys::Function) {
+        self.keyframe_request_callback = Some(callback);
+    }
+
+    /// Get the number of torn frames detected
+    #[wasm_bindgen]
+    pub fn get_torn_frames(&self) -> u64 {
+        self.generation_tracker.borrow().torn_count()
+    }
+
+    /// Set callback for `serverInfo` replies
+    #[wasm_bindgen]
+    pub fn on_server_info(&mut self, callback: js_sys::Function) {
+        self.server_info_callback = Some(callback);
+    }
+
+    /// Set callback for server format requests
+    ///
+    /// Called with `(format, reason)`. Returning `false` vetoes the change.
+    #[wasm_bindgen]
+    pub fn on_format_request(&mut self, callback: js_sys::Function) {
+        self.format_request_callback = Some(callback);
+    }
+
     fn set_state(&mut self, state: ConnectionState) {
-        self.state = state;
+        self.state.set(state);
         if let Some(ref cb) = self.state_callback {
-            let state_str = match state {
-                ConnectionState::Disconnected => "disconnected",
-                ConnectionState::Connecting => "connecting",
-                ConnectionState::Connected => "connected",
-                ConnectionState::Error => "error",
-            };
-            let _ = cb.call1(&JsValue::NULL, &JsValue::from_str(state_str));
+            let _ = cb.call1(&JsValue::NULL, &JsValue::from_str(state_name(state)));
+        }
+    }
+}
+
+fn state_name(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Disconnected => "disconnected",
+        ConnectionState::Connecting => "connecting",
+        ConnectionState::Connected => "connected",
+        ConnectionState::Error => "error",
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<SidecarMode, JsValue> {
+    match mode {
+        "local" => Ok(SidecarMode::Local),
+        "remote" => Ok(SidecarMode::Remote),
+        "disabled" => Ok(SidecarMode::Disabled),
+        _ => Err(JsValue::from_str("Invalid mode")),
+    }
+}
+
+fn parse_format(format: &str) -> Result<FrameFormat, JsValue> {
+    match format {
+        "rgba" => Ok(FrameFormat::Rgba),
+        "rgb565" => Ok(FrameFormat::Rgb565),
+        "yuv420" => Ok(FrameFormat::Yuv420),
+        "compressed" => Ok(FrameFormat::Compressed),
+        "indexed8" => Ok(FrameFormat::Indexed8),
+        "nv12" => Ok(FrameFormat::Nv12),
+        _ => Err(JsValue::from_str("Invalid format")),
+    }
+}
+
+fn format_name(format: FrameFormat) -> &'static str {
+    match format {
+        FrameFormat::Rgba => "rgba",
+        FrameFormat::Rgb565 => "rgb565",
+        FrameFormat::Yuv420 => "yuv420",
+        FrameFormat::Compressed => "compressed",
+        FrameFormat::Indexed8 => "indexed8",
+        FrameFormat::Nv12 => "nv12",
+    }
+}
+
+/// `{sequence, width, height, format, keyframe, data}` for the frame callback
+fn frame_object(metadata: &FrameMetadata, data: &js_sys::Uint8Array) -> Result<JsValue, JsValue> {
+    object(&[
+        ("sequence", JsValue::from_f64(metadata.sequence as f64)),
+        ("width", metadata.width.into()),
+        ("height", metadata.height.into()),
+        ("format", format_name(metadata.format).into()),
+        ("keyframe", metadata.keyframe.into()),
+        ("data", data.into()),
+    ])
+}
+
+/// Draw a frame with the renderer, if one is set
+fn render(renderer: &RefCell<Option<WasmRenderer>>, metadata: &FrameMetadata, data: &[u8]) {
+    if let Some(renderer) = renderer.borrow().as_ref() {
+        if let Err(e) = renderer.render_frame(metadata, data) {
+            console::error_1(&e);
         }
     }
 }
 
+/// A plain JS object with the given properties
+fn object(entries: &[(&str, JsValue)]) -> Result<JsValue, JsValue> {
+    let object = js_sys::Object::new();
+    for (key, value) in entries {
+        js_sys::Reflect::set(&object, &JsValue::from_str(key), value)?;
+    }
+    Ok(object.into())
+}
+
+/// Call `target.method(...args)`
+fn call_method(target: &JsValue, method: &str, args: &[JsValue]) -> Result<JsValue, JsValue> {
+    let function: js_sys::Function = js_sys::Reflect::get(target, &JsValue::from_str(method))?
+        .dyn_into()
+        .map_err(|_| JsValue::from_str(&format!("{} is not a function", method)))?;
+    function.apply(target, &args.iter().collect::<js_sys::Array>())
+}
+
+/// A message waiting to go out on the socket
+enum Outgoing<'a> {
+    Text(String),
+    Binary(Cow<'a, [u8]>),
+}
+
+impl Outgoing<'_> {
+    fn json(msg: &EmulatorToSidecarMessage) -> Result<Self, JsValue> {
+        serde_json::to_string(msg)
+            .map(Outgoing::Text)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Outgoing::Text(text) => text.len(),
+            Outgoing::Binary(data) => data.len(),
+        }
+    }
+
+    fn send(&self, ws: &WebSocket) -> Result<(), JsValue> {
+        match self {
+            Outgoing::Text(text) => ws.send_with_str(text),
+            Outgoing::Binary(data) => ws.send_with_u8_array(data),
+        }
+    }
+}
+
+impl WasmSidecar {
+    /// The open socket
+    fn socket(&self) -> Result<WebSocket, JsValue> {
+        self.ws.borrow().clone().ok_or_else(|| JsValue::from_str("Not connected"))
+    }
+
+    /// Whether the flow control window has room for another frame at `now`
+    fn has_credit(&mut self, now: f64) -> bool {
+        let Some((window, timeout_ms)) = self.frame_window else {
+            return true;
+        };
+        let mut unacked = self.unacked.borrow_mut();
+        unacked.retain(|&(_, sent)| now - sent <= timeout_ms);
+        unacked.len() < window
+    }
+
+    /// Send a group of messages, through the network simulation if enabled
+    ///
+    /// Returns `false` if the simulation lost them.
+    fn dispatch(&mut self, ws: &WebSocket, messages: Vec<Outgoing>) -> Result<bool, JsValue> {
+        if !self.network_sim.is_active() {
+            for message in &messages {
+                message.send(ws)?;
+            }
+            return Ok(true);
+        }
+
+        let bytes = messages.iter().map(Outgoing::len).sum();
+        let Some(delay) = self.network_sim.schedule(js_sys::Date::now(), bytes, js_sys::Math::random()) else {
+            return Ok(false);
+        };
+
+        let messages: Vec<Outgoing<'static>> = messages
+            .into_iter()
+            .map(|message| match message {
+                Outgoing::Text(text) => Outgoing::Text(text),
+                Outgoing::Binary(data) => Outgoing::Binary(Cow::Owned(data.into_owned())),
+            })
+            .collect();
+        let ws = ws.clone();
+        let send_later = Closure::once_into_js(move || {
+            for message in &messages {
+                if let Err(e) = message.send(&ws) {
+                    console::error_1(&e);
+                }
+            }
+        });
+        let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window"))?;
+        window.set_timeout_with_callback_and_timeout_and_arguments_0(
+            send_later.unchecked_ref(),
+            delay.ceil() as i32,
+        )?;
+        Ok(true)
+    }
+}
+
+fn send_message(ws: &WebSocket, msg: &EmulatorToSidecarMessage) -> Result<(), JsValue> {
+    let json = serde_json::to_string(msg)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    ws.send_with_str(&json)
+}
+
+fn send_set_format(
+    ws: &WebSocket,
+    current_format: &Cell<(FrameFormat, u32, u32)>,
+    format: FrameFormat,
+    width: u32,
+    height: u32,
+) -> Result<(), JsValue> {
+    send_message(ws, &EmulatorToSidecarMessage::SetFormat { format, width, height })?;
+    current_format.set((format, width, height));
+    Ok(())
+}
+
+/// Answer a `requestFormat` from the server
+fn handle_format_request(
+    ws: &WebSocket,
+    format: FrameFormat,
+    reason: &str,
+    callback: Option<&js_sys::Function>,
+    current_format: &Cell<(FrameFormat, u32, u32)>,
+    auto_apply: bool,
+) -> Result<(), JsValue> {
+    let accepted = match callback {
+        Some(cb) => {
+            let name = JsValue::from_str(format_name(format));
+            cb.call2(&JsValue::NULL, &name, &JsValue::from_str(reason))?
+                .as_bool()
+                != Some(false)
+        }
+        None => true,
+    };
+
+    if !accepted {
+        return send_message(ws, &EmulatorToSidecarMessage::FormatAck {
+            format,
+            success: false,
+        });
+    }
+
+    if auto_apply {
+        let (_, width, height) = current_format.get();
+        send_set_format(ws, current_format, format, width, height)?;
+    }
+    Ok(())
+}
+
+/// What the socket event handlers share with `WasmSidecar`
+///
+/// Kept apart so that automatic reconnection can open a new socket with the
+/// same handlers after `connect` has returned.
+#[derive(Clone)]
+struct Connection {
+    url: Rc<str>,
+    /// Session this connection belongs to, see `WasmSidecar::session`
+    session: u32,
+    current_session: Rc<Cell<u32>>,
+    socket: Rc<RefCell<Option<WebSocket>>>,
+    state: Rc<Cell<ConnectionState>>,
+    reconnect: Rc<RefCell<Option<Backoff>>>,
+    /// Serialized `auth` message, if a token was set
+    auth: Option<String>,
+    stats: Rc<RefCell<SidecarStats>>,
+    latency_tracker: Rc<RefCell<LatencyTracker>>,
+    frames_acked: Rc<Cell<u64>>,
+    binary_header: Rc<Cell<bool>>,
+    pending_frame: Rc<Cell<Option<PendingHeader>>>,
+    renderer: Rc<RefCell<Option<WasmRenderer>>>,
+    frame_callback: Option<js_sys::Function>,
+    message_callback: Option<js_sys::Function>,
+    state_callback: Option<js_sys::Function>,
+    error_callback: Option<js_sys::Function>,
+    format_request_callback: Option<js_sys::Function>,
+    server_info_callback: Option<js_sys::Function>,
+    tear_callback: Option<js_sys::Function>,
+    keyframe_request_callback: Option<js_sys::Function>,
+    generation_tracker: Rc<RefCell<GenerationTracker>>,
+    unacked: Rc<RefCell<VecDeque<(u64, f64)>>>,
+    current_format: Rc<Cell<(FrameFormat, u32, u32)>>,
+    auto_apply_format: Rc<Cell<bool>>,
+}
+
+impl Connection {
+    /// Whether `connect` or `disconnect` has been called since
+    fn is_stale(&self) -> bool {
+        self.current_session.get() != self.session
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.state.set(state);
+        if let Some(ref cb) = self.state_callback {
+            let _ = cb.call1(&JsValue::NULL, &JsValue::from_str(state_name(state)));
+        }
+    }
+
+    /// Open a socket to `url` with the event handlers installed
+    fn open(&self) -> Result<(), JsValue> {
+        let ws = WebSocket::new(&self.url)?;
+        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+
+        // onopen
+        {
+            let connection = self.clone();
+            let ws_open = ws.clone();
+            let onopen = Closure::wrap(Box::new(move |_: JsValue| {
+                if connection.is_stale() {
+                    return;
+                }
+                // Must be the first message on the connection
+                if let Some(auth) = &connection.auth {
+                    let _ = ws_open.send_with_str(auth);
+                }
+                let hello = EmulatorToSidecarMessage::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    client_version: crate::VERSION.to_string(),
+                    binary_frames: None,
+                };
+                if let Err(e) = send_message(&ws_open, &hello) {
+                    console::error_1(&e);
+                }
+                if let Some(backoff) = connection.reconnect.borrow_mut().as_mut() {
+                    backoff.reset();
+                }
+                connection.set_state(ConnectionState::Connected);
+                console::log_1(&"WebSocket connected".into());
+            }) as Box<dyn FnMut(JsValue)>);
+            ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+            onopen.forget();
+        }
+
+        // onclose
+        {
+            let connection = self.clone();
+            let onclose = Closure::wrap(Box::new(move |_: JsValue| {
+                // An explicit disconnect has already updated the state
+                if connection.is_stale() {
+                    return;
+                }
+                connection.socket.borrow_mut().take();
+                connection.set_state(ConnectionState::Disconnected);
+                console::log_1(&"WebSocket closed".into());
+                connection.schedule_reconnect();
+            }) as Box<dyn FnMut(JsValue)>);
+            ws.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+            onclose.forget();
+        }
+
+        // onerror
+        {
+            let connection = self.clone();
+            let onerror = Closure::wrap(Box::new(move |e: JsValue| {
+                if connection.is_stale() {
+                    return;
+                }
+                connection.state.set(ConnectionState::Error);
+                if let Some(ref cb) = connection.error_callback {
+                    let _ = cb.call1(&JsValue::NULL, &e);
+                }
+                console::error_1(&"WebSocket error".into());
+            }) as Box<dyn FnMut(JsValue)>);
+            ws.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+            onerror.forget();
+        }
+
+        // onmessage
+        {
+            let connection = self.clone();
+            let socket = ws.clone();
+            let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| {
+                if let Ok(text) = e.data().dyn_into::<js_sys::JsString>() {
+                    connection.handle_text(&socket, text.into());
+                } else if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                    connection.handle_binary(buffer);
+                }
+            }) as Box<dyn FnMut(MessageEvent)>);
+            ws.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+            onmessage.forget();
+        }
+
+        *self.socket.borrow_mut() = Some(ws);
+        Ok(())
+    }
+
+    /// Handle a JSON message from the server
+    fn handle_text(&self, socket: &WebSocket, text: String) {
+        console::log_1(&format!("Received: {}", text).into());
+
+        let msg = match serde_json::from_str::<SidecarToEmulatorMessage>(&text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                let error = JsValue::from_str(&format!("Malformed message: {}", e));
+                console::error_1(&error);
+                if let Some(ref cb) = self.error_callback {
+                    let _ = cb.call1(&JsValue::NULL, &error);
+                }
+                return;
+            }
+        };
+
+        // The server sends a frame's payload right after its frameHeader
+        if let SidecarToEmulatorMessage::FrameHeader { sequence, keyframe, .. } = &msg {
+            self.pending_frame.set(Some((*sequence, *keyframe)));
+        }
+
+        match &msg {
+            SidecarToEmulatorMessage::Pong { timestamp, .. } => {
+                // Smooth the round-trip time so one slow ping doesn't dominate
+                let rtt = (js_sys::Date::now() - timestamp).max(0.0);
+                let mut stats = self.stats.borrow_mut();
+                stats.avg_latency = if stats.avg_latency == 0.0 {
+                    rtt
+                } else {
+                    stats.avg_latency * 0.8 + rtt * 0.2
+                };
+                let mut latency_tracker = self.latency_tracker.borrow_mut();
+                latency_tracker.record(rtt);
+                latency_tracker.update_stats(&mut stats);
+            }
+            SidecarToEmulatorMessage::RequestFormat { format, reason } => {
+                let result = handle_format_request(
+                    socket,
+                    *format,
+                    reason,
+                    self.format_request_callback.as_ref(),
+                    &self.current_format,
+                    self.auto_apply_format.get(),
+                );
+                if let Err(e) = result {
+                    console::error_1(&e);
+                }
+            }
+            SidecarToEmulatorMessage::FrameHeader {
+                sequence,
+                generation: generation @ Some(_),
+                keyframe,
+                ..
+            } => {
+                let torn = self
+                    .generation_tracker
+                    .borrow_mut()
+                    .observe(*generation, keyframe.unwrap_or(false));
+                if torn {
+                    console::warn_1(&format!("Frame {} is torn, requesting a keyframe", sequence).into());
+                    if let Some(ref cb) = self.tear_callback {
+                        let _ = cb.call1(&JsValue::NULL, &JsValue::from_f64(*sequence as f64));
+                    }
+                    if let Err(e) = send_message(socket, &EmulatorToSidecarMessage::RequestKeyframe) {
+                        console::error_1(&e);
+                    }
+                }
+            }
+            SidecarToEmulatorMessage::FrameAck { sequence, .. } => {
+                self.frames_acked.set(self.frames_acked.get() + 1);
+                self.unacked.borrow_mut().retain(|&(unacked, _)| unacked != *sequence);
+            }
+            SidecarToEmulatorMessage::RequestKeyframe => {
+                console::warn_1(&"Server requested a keyframe".into());
+                if let Some(ref cb) = self.keyframe_request_callback {
+                    let _ = cb.call0(&JsValue::NULL);
+                }
+            }
+            SidecarToEmulatorMessage::FrameThrottle { outstanding } => {
+                // The server dropped the frame that hit its window
+                self.stats.borrow_mut().frames_dropped += 1;
+                console::warn_1(&format!("Server throttled a frame, {} in flight", outstanding).into());
+            }
+            SidecarToEmulatorMessage::HelloAck {
+                protocol_version,
+                server_version,
+                compatible: false,
+                ..
+            } => {
+                // The server follows up with an error and closes the connection
+                let error = JsValue::from_str(&format!(
+                    "Server {} speaks protocol version {}, this client {}",
+                    server_version, protocol_version, PROTOCOL_VERSION
+                ));
+                console::error_1(&error);
+                if let Some(ref cb) = self.error_callback {
+                    let _ = cb.call1(&JsValue::NULL, &error);
+                }
+            }
+            SidecarToEmulatorMessage::ServerInfo(_) => {
+                if let Some(ref cb) = self.server_info_callback {
+                    if let Ok(info) = js_sys::JSON::parse(&text) {
+                        let _ = cb.call1(&JsValue::NULL, &info);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(ref cb) = self.message_callback {
+            if let Ok(payload) = js_sys::JSON::parse(&text) {
+                let kind = js_sys::Reflect::get(&payload, &JsValue::from_str("type")).unwrap_or(JsValue::NULL);
+                let _ = cb.call2(&JsValue::NULL, &kind, &payload);
+            }
+        }
+    }
+
+    /// Handle binary frame data from the server
+    ///
+    /// The frame callback gets the payload with its metadata, taken from
+    /// the binary header or the preceding `frameHeader` and the negotiated
+    /// format. Without either it gets the raw buffer.
+    fn handle_binary(&self, buffer: js_sys::ArrayBuffer) {
+        let array = js_sys::Uint8Array::new(&buffer);
+        console::log_1(&format!("Received {} bytes of frame data", array.length()).into());
+
+        let pending = self.pending_frame.take();
+        let frame = if self.binary_header.get() {
+            let header = array.subarray(0, FRAME_HEADER_SIZE as u32).to_vec();
+            FrameMetadata::from_header_bytes(&header)
+                .map(|metadata| (metadata, array.subarray(FRAME_HEADER_SIZE as u32, array.length())))
+        } else {
+            let (format, width, height) = self.current_format.get();
+            pending.filter(|_| width > 0 && height > 0).map(|(sequence, keyframe)| {
+                let metadata = FrameMetadata {
+                    sequence,
+                    timestamp: js_sys::Date::now(),
+                    width,
+                    height,
+                    format,
+                    keyframe: keyframe.unwrap_or(true),
+                    generation: None,
+                    checksum: None,
+                    stream_id: 0,
+                };
+                (metadata, array)
+            })
+        };
+
+        if let Some((metadata, data)) = &frame {
+            render(&self.renderer, metadata, &data.to_vec());
+        }
+        let Some(ref cb) = self.frame_callback else {
+            return;
+        };
+
+        let arg = match frame {
+            Some((metadata, data)) => match frame_object(&metadata, &data) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    console::error_1(&e);
+                    return;
+                }
+            },
+            None => {
+                console::warn_1(&"Binary message without frame metadata, passing it on raw".into());
+                buffer.into()
+            }
+        };
+        let _ = cb.call1(&JsValue::NULL, &arg);
+    }
+
+    /// Try again after the next backoff delay, unless retries are used up
+    /// or reconnection is off
+    fn schedule_reconnect(&self) {
+        let delay = match self.reconnect.borrow_mut().as_mut() {
+            Some(backoff) => backoff.next_delay(),
+            None => return,
+        };
+        let Some(delay) = delay else {
+            console::warn_1(&"Giving up reconnecting".into());
+            return;
+        };
+
+        let connection = self.clone();
+        let retry = Closure::once_into_js(move || {
+            if connection.is_stale() {
+                return;
+            }
+            connection.set_state(ConnectionState::Connecting);
+            if let Err(e) = connection.open() {
+                console::error_1(&e);
+                connection.schedule_reconnect();
+            }
+        });
+        let scheduled = web_sys::window()
+            .ok_or_else(|| JsValue::from_str("No window"))
+            .and_then(|window| {
+                window.set_timeout_with_callback_and_timeout_and_arguments_0(
+                    retry.unchecked_ref(),
+                    delay.ceil() as i32,
+                )
+            });
+        if let Err(e) = scheduled {
+            console::error_1(&e);
+        }
+    }
+}
+
+/// `GPUTextureUsage` flags
+const GPU_COPY_SRC: u32 = 0x01;
+const GPU_COPY_DST: u32 = 0x02;
+const GPU_TEXTURE_BINDING: u32 = 0x04;
+const GPU_RENDER_ATTACHMENT: u32 = 0x10;
+
+/// Uploads frames to a WebGPU texture and shows them on a canvas
+///
+/// The WebGPU bindings in `web-sys` are unstable, so the device and canvas
+/// context are driven through `Reflect`. Clones share the same texture.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct WasmRenderer {
+    state: Rc<RefCell<RendererState>>,
+}
+
+struct RendererState {
+    device: JsValue,
+    queue: JsValue,
+    /// Canvas `webgpu` context to present on, if any
+    context: Option<JsValue>,
+    /// `rgba8unorm` texture sized to the last frame
+    texture: Option<JsValue>,
+    size: (u32, u32),
+}
+
+#[wasm_bindgen]
+impl WasmRenderer {
+    /// Create a renderer for a `GPUDevice`
+    ///
+    /// `context` is a canvas' `webgpu` context, configured for `rgba8unorm`
+    /// and resized to each frame. Pass `null` to only upload to `texture()`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(device: JsValue, context: JsValue) -> Result<WasmRenderer, JsValue> {
+        let queue = js_sys::Reflect::get(&device, &JsValue::from_str("queue"))?;
+        if queue.is_undefined() || queue.is_null() {
+            return Err(JsValue::from_str("Not a GPUDevice"));
+        }
+        let context = (!context.is_undefined() && !context.is_null()).then_some(context);
+        Ok(Self {
+            state: Rc::new(RefCell::new(RendererState {
+                device,
+                queue,
+                context,
+                texture: None,
+                size: (0, 0),
+            })),
+        })
+    }
+
+    /// Upload an RGBA frame, or an RGB565 one converted to RGBA
+    ///
+    /// The format is told apart by the size of `data`.
+    #[wasm_bindgen]
+    pub fn render(&mut self, data: &[u8], width: u32, height: u32) -> Result<(), JsValue> {
+        let size = |format| {
+            Frame::buffer_size(format, width, height)
+                .map(Option::unwrap_or_default)
+                .map_err(|e| JsValue::from_str(&e.to_string()))
+        };
+        let (rgba_len, rgb565_len) = (size(FrameFormat::Rgba)?, size(FrameFormat::Rgb565)?);
+        let format = if data.len() == rgba_len {
+            FrameFormat::Rgba
+        } else if data.len() == rgb565_len {
+            FrameFormat::Rgb565
+        } else {
+            return Err(JsValue::from_str(&format!(
+                "Expected {} bytes of RGBA or {} bytes of RGB565 for {}x{}, got {}",
+                rgba_len,
+                rgb565_len,
+                width,
+                height,
+                data.len()
+            )));
+        };
+        let metadata = FrameMetadata {
+            sequence: 0,
+            timestamp: 0.0,
+            width,
+            height,
+            format,
+            keyframe: true,
+            generation: None,
+            checksum: None,
+            stream_id: 0,
+        };
+        self.render_frame(&metadata, data)
+    }
+
+    /// The texture holding the last frame, `undefined` before the first
+    #[wasm_bindgen]
+    pub fn texture(&self) -> JsValue {
+        self.state.borrow().texture.clone().unwrap_or(JsValue::UNDEFINED)
+    }
+}
+
+impl WasmRenderer {
+    /// Upload a frame in any format, converting it to RGBA first
+    fn render_frame(&self, metadata: &FrameMetadata, data: &[u8]) -> Result<(), JsValue> {
+        let (width, height) = (metadata.width, metadata.height);
+        if metadata.format == FrameFormat::Rgba {
+            return self.state.borrow_mut().upload(data, width, height);
+        }
+        let frame = Frame::new(metadata.clone(), data.to_vec())
+            .and_then(|frame| frame.convert(FrameFormat::Rgba))
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.state.borrow_mut().upload(&frame.data, width, height)
+    }
+}
+
+impl RendererState {
+    /// Write RGBA pixels to the texture with `queue.writeTexture`,
+    /// reallocating it if the size changed, then present it
+    fn upload(&mut self, rgba: &[u8], width: u32, height: u32) -> Result<(), JsValue> {
+        if width == 0 || height == 0 {
+            return Err(JsValue::from_str("Empty frame"));
+        }
+        let size: JsValue = js_sys::Array::of2(&width.into(), &height.into()).into();
+
+        let texture = match &self.texture {
+            Some(texture) if self.size == (width, height) => texture.clone(),
+            _ => self.allocate(width, height, &size)?,
+        };
+
+        call_method(
+            &self.queue,
+            "writeTexture",
+            &[
+                object(&[("texture", texture.clone())])?,
+                js_sys::Uint8Array::from(rgba).into(),
+                object(&[("bytesPerRow", (width * 4).into()), ("rowsPerImage", height.into())])?,
+                size.clone(),
+            ],
+        )?;
+
+        if let Some(context) = &self.context {
+            let encoder = call_method(&self.device, "createCommandEncoder", &[])?;
+            let target = call_method(context, "getCurrentTexture", &[])?;
+            call_method(
+                &encoder,
+                "copyTextureToTexture",
+                &[object(&[("texture", texture)])?, object(&[("texture", target)])?, size],
+            )?;
+            let commands = call_method(&encoder, "finish", &[])?;
+            call_method(&self.queue, "submit", &[js_sys::Array::of1(&commands).into()])?;
+        }
+        Ok(())
+    }
+
+    /// Replace the texture with one of the new size, resizing the canvas
+    fn allocate(&mut self, width: u32, height: u32, size: &JsValue) -> Result<JsValue, JsValue> {
+        if let Some(old) = self.texture.take() {
+            call_method(&old, "destroy", &[])?;
+        }
+
+        let descriptor = object(&[
+            ("size", size.clone()),
+            ("format", "rgba8unorm".into()),
+            ("usage", (GPU_COPY_SRC | GPU_COPY_DST | GPU_TEXTURE_BINDING).into()),
+        ])?;
+        let texture = call_method(&self.device, "createTexture", &[descriptor])?;
+
+        if let Some(context) = &self.context {
+            let canvas = js_sys::Reflect::get(context, &JsValue::from_str("canvas"))?;
+            js_sys::Reflect::set(&canvas, &JsValue::from_str("width"), &width.into())?;
+            js_sys::Reflect::set(&canvas, &JsValue::from_str("height"), &height.into())?;
+            let configuration = object(&[
+                ("device", self.device.clone()),
+                ("format", "rgba8unorm".into()),
+                ("usage", (GPU_COPY_DST | GPU_RENDER_ATTACHMENT).into()),
+                ("alphaMode", "opaque".into()),
+            ])?;
+            call_method(context, "configure", &[configuration])?;
+        }
+
+        self.texture = Some(texture.clone());
+        self.size = (width, height);
+        Ok(texture)
+    }
+}
+
 impl Default for WasmSidecar {
     fn default() -> Self {
         Self::new()
@@ -287,6 +1402,35 @@ pub fn version() -> String {
     crate::VERSION.to_string()
 }
 
+/// Extract the WebP image from a compressed frame payload
+///
+/// Returns `undefined` if the payload is not WebP. The result can be passed
+/// to `createImageBitmap` so the browser decodes it natively.
+#[wasm_bindgen]
+pub fn webp_payload(data: &[u8]) -> Option<Vec<u8>> {
+    crate::compression::webp_payload(data).map(|payload| payload.to_vec())
+}
+
+/// Decode a compressed frame payload to RGBA
+#[wasm_bindgen]
+pub fn decompress_frame(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, JsValue> {
+    let metadata = FrameMetadata {
+        sequence: 0,
+        timestamp: 0.0,
+        width,
+        height,
+        format: FrameFormat::Compressed,
+        keyframe: true,
+        generation: None,
+        checksum: None,
+        stream_id: 0,
+    };
+    let frame = Frame::new(metadata, data.to_vec())
+        .and_then(|frame| frame.decompress())
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(frame.data.into())
+}
+
 /// Check if WebGPU is available
 #[wasm_bindgen]
 pub async fn check_webgpu() -> bool {